@@ -0,0 +1,21 @@
+#![no_main]
+
+use std::convert::TryFrom;
+use libfuzzer_sys::fuzz_target;
+use transaction_processor::{csv_handlers::TransactionReader, ledger::Ledger, transactions::Transaction};
+
+// Feeds arbitrary bytes through the csv reader and parser, and whatever parses into a ledger.
+// Errors are expected, only a panic is a failure.
+fuzz_target!(|data: &[u8]| {
+  let mut reader = TransactionReader::from_bytes(data.to_vec()).with_scientific_amounts(true);
+  let mut l = Ledger::new();
+  while !reader.is_done() {
+    if let Ok(transaction) = reader.transaction() {
+      l.add_transaction(transaction);
+    }
+  }
+  let _ = l.calculate_all_account_summaries();
+  if let Ok(line) = std::str::from_utf8(data) {
+    let _ = Transaction::try_from(line);
+  }
+});
@@ -0,0 +1,299 @@
+
+use std::{io::Write, path::PathBuf};
+use crate::{
+  enabled_features,
+  Currency,
+  csv_handlers::{append_as_csv, is_url, read_account_summaries, write_as_csv, Column, InputFormat, MergePolicy},
+  ledger::{AccountSummary, Ledger, SortKey},
+  timing::{timed, Timings}
+};
+
+/// Options the binary is run with, parsed from the command line.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CliArgs {
+  pub input: PathBuf,
+  pub input_format: InputFormat,
+  pub sort: SortKey,
+  /// Print how long each phase of the run took to stderr.
+  pub timing: bool,
+  /// Only output accounts with a nonzero total.
+  pub nonzero_only: bool,
+  /// Only output locked accounts.
+  pub locked_only: bool,
+  /// Which columns to output, in order.
+  pub columns: Vec<Column>,
+  /// Succeed even if the input holds no valid transactions.
+  pub allow_empty: bool,
+  /// Gzip compress the output.
+  pub gzip: bool,
+  /// A file of expected account summaries to check the output against.
+  pub reconcile: Option<PathBuf>,
+  /// Print a warning to stderr for each transaction dropped because its client's account is locked.
+  pub warn_locked: bool,
+  /// Print the cargo features the program was built with instead of processing any input.
+  pub features: bool,
+  /// A file to merge the summaries into rather than writing them to the output.
+  pub append: Option<PathBuf>,
+  /// How summaries are merged into the `append` file.
+  pub merge: MergePolicy,
+}
+impl CliArgs {
+  /// Parses the arguments following the program name.
+  pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, Box<dyn std::error::Error>> {
+    let mut input = None;
+    let mut input_format = InputFormat::default();
+    let mut sort = SortKey::default();
+    let mut timing = false;
+    let mut nonzero_only = false;
+    let mut locked_only = false;
+    let mut columns = Column::ALL.to_vec();
+    let mut allow_empty = false;
+    let mut gzip = false;
+    let mut reconcile = None;
+    let mut warn_locked = false;
+    let mut features = false;
+    let mut append = None;
+    let mut merge = MergePolicy::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+      match arg.as_str() {
+        "--input-format" => input_format = args.next().ok_or("Missing value for --input-format.")?.parse()?,
+        "--sort" => sort = args.next().ok_or("Missing value for --sort.")?.parse()?,
+        "--timing" => timing = true,
+        "--nonzero-only" => nonzero_only = true,
+        "--locked-only" => locked_only = true,
+        "--columns" => columns = Column::parse_list(&args.next().ok_or("Missing value for --columns.")?)?,
+        "--allow-empty" => allow_empty = true,
+        "--gzip" => gzip = true,
+        "--reconcile" => reconcile = Some(args.next().ok_or("Missing value for --reconcile.")?.into()),
+        "--warn-locked" => warn_locked = true,
+        "--features" => features = true,
+        "--append" => append = Some(args.next().ok_or("Missing value for --append.")?.into()),
+        "--merge" => merge = args.next().ok_or("Missing value for --merge.")?.parse()?,
+        _ if input.is_none() => input = Some(arg.into()),
+        _ => return Err(From::from(format!("Unexpected argument: {}", arg))),
+      }
+    }
+    if append.is_some() && (gzip || columns != Column::ALL) {
+      return Err(From::from("--append always writes the standard uncompressed columns, so can't be combined with --gzip or --columns."))
+    }
+    Ok(CliArgs {
+      // No input is needed when only listing features
+      input: input.or_else(|| features.then(PathBuf::new)).ok_or("Arg empty.")?,
+      input_format,
+      sort,
+      timing,
+      nonzero_only,
+      locked_only,
+      columns,
+      allow_empty,
+      gzip,
+      reconcile,
+      warn_locked,
+      features,
+      append,
+      merge,
+    })
+  }
+  /// Whether a summary passes the output filters and should be written.
+  pub fn keeps(&self, summary: &AccountSummary) -> bool {
+    (!self.nonzero_only || summary.total() != &Currency::from(0))
+    && (!self.locked_only || summary.locked())
+  }
+}
+
+/// Processes the input and writes account summaries to `out`, or merges them into the `append`
+/// file if set, returning the exit code for the process. Input without a single valid
+/// transaction usually means something is wrong with it, so exits with 1 after writing the
+/// (empty) output, unless `allow_empty` is set. Also exits with 1, after printing the
+/// differences to stderr, if the accounts don't match the `reconcile` file.
+/// With `features` set, lists the features the program was built with instead.
+pub fn run<W: Write>(args: &CliArgs, mut out: W) -> Result<i32, Box<dyn std::error::Error>> {
+  if args.features {
+    for feature in enabled_features() {
+      writeln!(out, "{}", feature)?;
+    }
+    return Ok(0)
+  }
+  let mut l = Ledger::new().with_input_format(args.input_format).with_lock_warnings(args.warn_locked);
+  let (records, read) = timed(|| match args.input.to_str().filter(|input| is_url(input)) {
+    Some(url) => l.replay_from_url(url),
+    None if args.input.is_dir() => l.replay_from_dir(args.input.clone()),
+    None => l.replay_from_csv(args.input.clone()),
+  });
+  let records = records?;
+  let (written, write) = timed(|| {
+    let mut summaries = l.calculate_all_account_summaries();
+    summaries.retain(|summary| args.keeps(summary));
+    args.sort.sort(&mut summaries);
+    if let Some(path) = &args.append {
+      append_as_csv(path, summaries, args.merge)
+    }
+    else if args.gzip {
+      write_gzip(out, summaries, &args.columns)
+    }
+    else {
+      write_as_csv(out, summaries, &args.columns)
+    }
+  });
+  if args.timing {
+    eprintln!("{}", Timings { records, read, write });
+  }
+  written?;
+  if records == 0 && !args.allow_empty {
+    eprintln!("No valid transactions in {}, pass --allow-empty to allow this.", args.input.display());
+    return Ok(1)
+  }
+  if let Some(expected) = &args.reconcile {
+    let report = l.reconcile(&read_account_summaries(expected.clone())?);
+    if !report.is_ok() {
+      eprint!("{}", report);
+      return Ok(1)
+    }
+  }
+  Ok(0)
+}
+
+#[cfg(feature = "flate2")]
+fn write_gzip<W: Write>(out: W, summaries: Vec<AccountSummary>, columns: &[Column]) -> Result<(), Box<dyn std::error::Error>> {
+  crate::csv_handlers::write_as_csv_gzip(out, summaries, columns)
+}
+#[cfg(not(feature = "flate2"))]
+fn write_gzip<W: Write>(_: W, _: Vec<AccountSummary>, _: &[Column]) -> Result<(), Box<dyn std::error::Error>> {
+  Err(From::from("--gzip requires the flate2 feature."))
+}
+
+#[cfg(test)]
+mod cli_tests {
+  use super::*;
+
+  fn parse(args: &[&str]) -> Result<CliArgs, Box<dyn std::error::Error>> {
+    CliArgs::parse(args.iter().map(|arg| arg.to_string()))
+  }
+
+  #[test]
+  fn input_only() {
+    let args = parse(&["input.csv"]).unwrap();
+    assert_eq!(PathBuf::from("input.csv"), args.input);
+    assert_eq!(InputFormat::Auto, args.input_format);
+    assert_eq!(SortKey::Client, args.sort);
+    assert!(!args.timing);
+    assert!(!args.nonzero_only);
+    assert!(!args.locked_only);
+    assert_eq!(Column::ALL.to_vec(), args.columns);
+    assert!(!args.allow_empty);
+    assert!(!args.gzip);
+    assert_eq!(None, args.reconcile);
+    assert!(!args.warn_locked);
+    assert!(!args.features);
+  }
+  #[test]
+  fn timing_flag() {
+    assert!(parse(&["--timing", "input.csv"]).unwrap().timing);
+  }
+  #[test]
+  fn features_flag() -> Result<(), Box<dyn std::error::Error>> {
+    let args = parse(&["--features"])?;
+    assert!(args.features);
+    let mut out = Vec::new();
+    assert_eq!(0, run(&args, &mut out)?);
+    let listed: Vec<String> = String::from_utf8(out)?.lines().map(String::from).collect();
+    assert_eq!(enabled_features(), listed);
+    assert!(parse(&[]).is_err());
+    Ok(())
+  }
+  #[test]
+  fn warn_locked_flag() {
+    assert!(parse(&["input.csv", "--warn-locked"]).unwrap().warn_locked);
+  }
+  #[test]
+  fn sort_flag() {
+    assert_eq!(SortKey::TotalDesc, parse(&["--sort", "total", "input.csv"]).unwrap().sort);
+    assert_eq!(SortKey::AvailableDesc, parse(&["input.csv", "--sort", "available"]).unwrap().sort);
+    assert!(parse(&["input.csv", "--sort", "held"]).is_err());
+    assert!(parse(&["input.csv", "--sort"]).is_err());
+  }
+  #[test]
+  fn filter_flags() {
+    let args = parse(&["--nonzero-only", "input.csv", "--locked-only", "--sort", "total"]).unwrap();
+    assert!(args.nonzero_only);
+    assert!(args.locked_only);
+    assert_eq!(SortKey::TotalDesc, args.sort);
+  }
+  #[test]
+  fn columns_flag() {
+    assert_eq!(vec![Column::Client, Column::Total], parse(&["input.csv", "--columns", "client,total"]).unwrap().columns);
+    assert!(parse(&["input.csv", "--columns", "client,client"]).is_err());
+    assert!(parse(&["input.csv", "--columns"]).is_err());
+  }
+  #[test]
+  fn input_format_flag() {
+    assert_eq!(InputFormat::Json, parse(&["--input-format", "json", "input.txt"]).unwrap().input_format);
+    assert_eq!(InputFormat::Csv, parse(&["input.txt", "--input-format", "csv"]).unwrap().input_format);
+    assert!(parse(&["input.txt", "--input-format", "xml"]).is_err());
+    assert!(parse(&["input.txt", "--input-format"]).is_err());
+  }
+  #[test]
+  fn empty_input_exit_code() -> Result<(), Box<dyn std::error::Error>> {
+    let mut out = Vec::new();
+    assert_eq!(1, run(&parse(&["testdata/all_bad_rows.csv"])?, &mut out)?);
+    assert_eq!("client,available,held,total,locked\n", String::from_utf8(out)?);
+    assert_eq!(0, run(&parse(&["testdata/all_bad_rows.csv", "--allow-empty"])?, Vec::new())?);
+    assert_eq!(0, run(&parse(&["testdata/spec_example.csv"])?, Vec::new())?);
+    Ok(())
+  }
+  #[test]
+  fn gzip_flag() -> Result<(), Box<dyn std::error::Error>> {
+    let args = parse(&["testdata/spec_example.csv", "--gzip"])?;
+    assert!(args.gzip);
+    let mut out = Vec::new();
+    let written = run(&args, &mut out);
+    #[cfg(feature = "flate2")]
+    {
+      use std::io::Read;
+      assert_eq!(0, written?);
+      let mut decompressed = String::new();
+      flate2::read::GzDecoder::new(out.as_slice()).read_to_string(&mut decompressed)?;
+      assert!(decompressed.starts_with("client,available,held,total,locked\n"));
+    }
+    #[cfg(not(feature = "flate2"))]
+    assert!(written.is_err());
+    Ok(())
+  }
+  #[test]
+  fn reconcile_flag() -> Result<(), Box<dyn std::error::Error>> {
+    let args = parse(&["testdata/spec_example.csv", "--reconcile", "testdata/spec_example_expected.csv"])?;
+    assert_eq!(0, run(&args, Vec::new())?);
+    let args = parse(&["testdata/spec_example.csv", "--reconcile", "testdata/spec_example_wrong.csv"])?;
+    assert_eq!(1, run(&args, Vec::new())?);
+    assert!(parse(&["input.csv", "--reconcile"]).is_err());
+    Ok(())
+  }
+  #[test]
+  fn reconcile_own_output() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join(format!("transaction_processor_reconcile_{}.csv", std::process::id()));
+    let mut out = Vec::new();
+    assert_eq!(0, run(&parse(&["testdata/precise_amounts.csv"])?, &mut out)?);
+    std::fs::write(&path, out)?;
+    let reconciled = run(&parse(&["testdata/precise_amounts.csv", "--reconcile", path.to_str().unwrap()])?, Vec::new());
+    std::fs::remove_file(&path)?;
+    assert_eq!(0, reconciled?);
+    Ok(())
+  }
+  #[test]
+  fn append_flags() {
+    let args = parse(&["input.csv", "--append", "out.csv", "--merge", "replace"]).unwrap();
+    assert_eq!(Some(PathBuf::from("out.csv")), args.append);
+    assert_eq!(MergePolicy::Replace, args.merge);
+    assert_eq!(MergePolicy::Sum, parse(&["input.csv", "--append", "out.csv"]).unwrap().merge);
+    assert!(parse(&["input.csv", "--append"]).is_err());
+    assert!(parse(&["input.csv", "--merge", "add"]).is_err());
+    assert!(parse(&["input.csv", "--append", "out.csv", "--gzip"]).is_err());
+    assert!(parse(&["input.csv", "--append", "out.csv", "--columns", "client"]).is_err());
+  }
+  #[test]
+  fn missing_input() {
+    assert!(parse(&[]).is_err());
+    assert!(parse(&["--sort", "client"]).is_err());
+  }
+}
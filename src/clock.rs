@@ -0,0 +1,26 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::Timestamp;
+
+/// Source of the timestamps transactions are tagged with as they are ingested.
+pub trait Clock: Send + Sync {
+  fn now(&self) -> Timestamp;
+}
+
+/// Reads the system time, in seconds since the unix epoch.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+impl Clock for SystemClock {
+  fn now(&self) -> Timestamp {
+    // A system time before the epoch is a misconfigured machine, so treat it as the epoch itself
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since_epoch| since_epoch.as_secs())
+  }
+}
+
+/// Always reads the same time, for reproducible runs and tests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FixedClock(pub Timestamp);
+impl Clock for FixedClock {
+  fn now(&self) -> Timestamp {
+    self.0
+  }
+}
@@ -1,70 +1,1206 @@
-
-use std::{fs::File, path::PathBuf};
-use csv::{Reader, ReaderBuilder, StringRecord};
-use crate::ledger::AccountSummary;
-
-pub struct TransactionReader {
-  file_reader: Reader<File>,
-}
-impl TransactionReader {
-  pub fn from_file(file: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
-    return Ok(TransactionReader {
-      file_reader: ReaderBuilder::new().from_path(file)?
-    })
-  }
-  pub fn record(&mut self) -> Result<StringRecord, Box<dyn std::error::Error>> {
-    if !self.file_reader.is_done() {
-      let mut r = StringRecord::new();
-      if self.file_reader.read_record(&mut r).is_ok() {
-        return Ok(r)
-      }
-      else {
-        return Err(From::from("Problem reading record."))
-      }
-    }
-    Err(From::from("No more records!"))
-  }
-  pub fn is_done(&self) -> bool {
-    self.file_reader.is_done()
-  }
-}
-
-pub fn write_as_csv_to_stdout(account_summaries: Vec<AccountSummary>) -> Result<(), Box<dyn std::error::Error>> {
-  let mut wtr = csv::Writer::from_writer(std::io::stdout());
-  wtr.write_record(&["client", "available", "held", "total", "locked"])?;
-  for summary in &account_summaries {
-    wtr.write_record(&[
-      summary.client.to_string(),
-      summary.available.to_string(),
-      summary.held.to_string(),
-      summary.total.to_string(),
-      summary.locked.to_string()
-    ])?;
-  }
-  wtr.flush()?;
-  Ok(())
-}
-
-#[cfg(test)]
-mod reader_tests {
-  use super::*;
-
-  const SPEC_EXAMPLE: &str = "testdata/spec_example.csv";
-  const WRONG_EXT: &str = "testdata/non_csv.txt";
-
-  #[test]
-  fn from_file_valid_csv() {
-    let reader = TransactionReader::from_file(SPEC_EXAMPLE.into());
-    assert!(reader.is_ok());
-  }
-  #[test]
-  fn from_file_non_exists() {
-    let reader = TransactionReader::from_file("".into());
-    assert!(reader.is_err());
-  }
-  #[test]
-  fn from_file_valid_but_wrong_ext() {
-    let reader = TransactionReader::from_file(WRONG_EXT.into());
-    assert!(reader.is_ok());
-  }
-}
\ No newline at end of file
+
+use std::{collections::BTreeMap, fs::File, io::{BufRead, BufReader, Read, Write}, path::{Path, PathBuf}, str::FromStr};
+use csv::{ErrorKind, Reader, ReaderBuilder, StringRecord};
+use serde::{Deserialize, Serialize};
+use crate::{
+  ClientId,
+  Currency,
+  CURRENCY_SCALE,
+  ledger::{AccountState, AccountSummary, Ledger},
+  transactions::{parse_id, ColumnMap, Transaction, TransactionParseError, TypeSynonyms}
+};
+
+pub struct TransactionReader {
+  source: Source,
+  column_map: ColumnMap,
+  skipped_rows: usize,
+  failed: bool,
+  scientific_amounts: bool,
+  strict: bool,
+  unknown_types: UnknownTypes,
+  skipped_unknown_types: usize,
+  type_synonyms: TypeSynonyms,
+  path: PathBuf,
+  record_byte: u64,
+}
+/// Where a reader's records come from.
+enum Source {
+  /// Boxed so the csv can be read from either the file or a memory map of it.
+  Csv(Reader<Box<dyn Read>>),
+  /// The elements of a JSON array, parsed up front, along with how many have been read and the
+  /// size of the file.
+  #[cfg(feature = "serde_json")]
+  Json { elements: std::vec::IntoIter<serde_json::Value>, read: u64, len: u64 },
+}
+
+/// A transaction object in a JSON array, e.g. `{"type":"deposit","client":1,"tx":1,"amount":"10.0"}`.
+/// Numeric fields may be given as JSON numbers or strings.
+#[cfg(feature = "serde_json")]
+#[derive(Deserialize)]
+struct RawRecord {
+  #[serde(rename = "type")]
+  kind: String,
+  client: serde_json::Value,
+  tx: serde_json::Value,
+  #[serde(default)]
+  amount: serde_json::Value,
+  #[serde(default)]
+  timestamp: serde_json::Value,
+}
+#[cfg(feature = "serde_json")]
+impl RawRecord {
+  /// Lays the object out as a record in the standard `type,client,tx,amount,timestamp` order, so
+  /// it's parsed the same way as a csv row.
+  fn into_record(self) -> StringRecord {
+    let field = |value: serde_json::Value| match value {
+      serde_json::Value::Null => String::new(),
+      serde_json::Value::String(s) => s,
+      other => other.to_string(),
+    };
+    StringRecord::from(vec![self.kind, field(self.client), field(self.tx), field(self.amount), field(self.timestamp)])
+  }
+}
+
+impl TransactionReader {
+  /// Reads a file in the given format, working it out from the file's contents if it's `Auto`.
+  pub fn from_path(file: PathBuf, format: InputFormat) -> Result<Self, Box<dyn std::error::Error>> {
+    match format {
+      InputFormat::Auto => {
+        let sniffed = InputFormat::sniff(&file)?;
+        Self::from_path(file, sniffed)
+      },
+      InputFormat::Csv => Self::from_file(file),
+      #[cfg(feature = "serde_json")]
+      InputFormat::Json => Self::from_json_file(file),
+      #[cfg(not(feature = "serde_json"))]
+      InputFormat::Json => Err(From::from("JSON input requires the serde_json feature.")),
+    }
+  }
+  pub fn from_file(file: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+    Self::from_builder(ReaderBuilder::new(), file)
+  }
+  /// Reads csv held in memory, e.g. fetched from somewhere other than a file.
+  pub fn from_bytes(bytes: Vec<u8>) -> Self {
+    Self::from_reader(ReaderBuilder::new(), Box::new(std::io::Cursor::new(bytes)), PathBuf::new())
+  }
+  /// Reads a file with no header row, so the first row is treated as a transaction.
+  pub fn from_file_headerless(file: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+    let mut builder = ReaderBuilder::new();
+    builder.has_headers(false);
+    Self::from_builder(builder, file)
+  }
+  /// Reads a file through a memory map of it rather than buffered reads, which saves on system
+  /// calls for very large files. The file must not be modified while it's being read.
+  #[cfg(feature = "memmap2")]
+  pub fn from_mmap_file(file: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+    // Safety: the map is only undefined behaviour if the file is changed underneath it, which
+    // the caller is warned against. The reader owns the map, so it lives as long as it's read.
+    let mmap = unsafe { memmap2::Mmap::map(&File::open(&file)?)? };
+    Ok(Self::from_reader(ReaderBuilder::new(), Box::new(std::io::Cursor::new(mmap)), file))
+  }
+  /// Streams a csv file from an `http` or `https` URL, rather than downloading it first. Any
+  /// response other than `200 OK` is an error.
+  #[cfg(feature = "ureq")]
+  pub fn from_url(url: &str) -> Result<Self, Box<dyn std::error::Error>> {
+    // Error statuses come back as errors, but other successes such as 204 have no file either
+    let response = ureq::get(url).call()?;
+    if response.status() != 200 {
+      return Err(From::from(format!("Unexpected response {} from {}.", response.status(), url)))
+    }
+    Ok(Self::from_reader(ReaderBuilder::new(), Box::new(response.into_reader()), url.into()))
+  }
+  fn from_builder(builder: ReaderBuilder, file: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+    let reader = Box::new(File::open(&file)?);
+    Ok(Self::from_reader(builder, reader, file))
+  }
+  fn from_reader(mut builder: ReaderBuilder, reader: Box<dyn Read>, file: PathBuf) -> Self {
+    TransactionReader {
+      // Transaction fields never contain delimiters, so quoting is disabled. Otherwise a single
+      // unterminated quote would swallow every row after it into one field.
+      // Lines starting with '#' are annotations rather than rows.
+      source: Source::Csv(builder.quoting(false).comment(Some(b'#')).from_reader(reader)),
+      column_map: ColumnMap::default(),
+      skipped_rows: 0,
+      failed: false,
+      scientific_amounts: false,
+      strict: false,
+      unknown_types: UnknownTypes::default(),
+      skipped_unknown_types: 0,
+      type_synonyms: TypeSynonyms::default(),
+      path: file,
+      record_byte: 0,
+    }
+  }
+  /// Reads a file holding a JSON array of transaction objects, e.g.
+  /// `[{"type":"deposit","client":1,"tx":1,"amount":"10.0"}]`, or the objects one after another
+  /// without an array. Elements that aren't transaction objects are treated like malformed csv rows.
+  #[cfg(feature = "serde_json")]
+  pub fn from_json_file(file: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+    let contents = std::fs::read(&file)?;
+    let mut elements = Vec::new();
+    for value in serde_json::Deserializer::from_slice(&contents).into_iter() {
+      match value? {
+        serde_json::Value::Array(array) => elements.extend(array),
+        other => elements.push(other),
+      }
+    }
+    Ok(TransactionReader {
+      source: Source::Json { elements: elements.into_iter(), read: 0, len: contents.len() as u64 },
+      column_map: ColumnMap { timestamp: Some(4), ..ColumnMap::default() },
+      skipped_rows: 0,
+      failed: false,
+      scientific_amounts: false,
+      strict: false,
+      unknown_types: UnknownTypes::default(),
+      skipped_unknown_types: 0,
+      type_synonyms: TypeSynonyms::default(),
+      path: file,
+      record_byte: 0,
+    })
+  }
+  /// Sets which column index holds each field, for feeds not in the standard
+  /// `type,client,tx,amount` order. JSON objects are always laid out in the standard order.
+  pub fn with_column_map(mut self, kind: usize, client: usize, tx: usize, amount: usize) -> Self {
+    self.column_map = ColumnMap { kind, client, tx, amount, ..self.column_map };
+    self
+  }
+  /// Reads each deposit and withdrawal's timestamp, in seconds since the unix epoch, from a column.
+  pub fn with_timestamp_column(mut self, timestamp: usize) -> Self {
+    self.column_map.timestamp = Some(timestamp);
+    self
+  }
+  /// Sets whether amounts written in scientific notation, e.g. `1.5e3`, are accepted. They are
+  /// rejected by default as they are usually a sign of data mangled by a spreadsheet.
+  pub fn with_scientific_amounts(mut self, scientific_amounts: bool) -> Self {
+    self.scientific_amounts = scientific_amounts;
+    self
+  }
+  /// Sets whether malformed rows are returned as errors rather than skipped. Reading can carry on
+  /// past them either way.
+  pub fn with_strict(mut self, strict: bool) -> Self {
+    self.strict = strict;
+    self
+  }
+  /// Sets whether rows with a type that isn't a known transaction type, e.g. a `transfer`, are
+  /// returned as `TransactionParseError::UnknownKind` errors or passed over.
+  pub fn with_unknown_types(mut self, unknown_types: UnknownTypes) -> Self {
+    self.unknown_types = unknown_types;
+    self
+  }
+  /// Sets other names accepted for the transaction types, e.g. `credit` for a deposit. Only the
+  /// canonical names are accepted by default.
+  pub fn with_type_synonyms(mut self, type_synonyms: TypeSynonyms) -> Self {
+    self.type_synonyms = type_synonyms;
+    self
+  }
+  /// Reads the next record, skipping over any malformed rows. Returns an error once there are no
+  /// more records or if the underlying stream fails, after which the reader is done.
+  pub fn record(&mut self) -> Result<StringRecord, Box<dyn std::error::Error>> {
+    // Only infallible without the serde_json feature
+    #[allow(clippy::infallible_destructuring_match)]
+    let file_reader = match &mut self.source {
+      Source::Csv(file_reader) => file_reader,
+      #[cfg(feature = "serde_json")]
+      Source::Json { elements, read, .. } => {
+        for element in elements {
+          *read += 1;
+          match serde_json::from_value::<RawRecord>(element) {
+            Ok(raw) => return Ok(raw.into_record()),
+            Err(e) if self.strict => return Err(From::from(e)),
+            Err(_) => self.skipped_rows += 1,
+          }
+        }
+        return Err(From::from("No more records!"))
+      },
+    };
+    let mut r = StringRecord::new();
+    while !self.failed && !file_reader.is_done() {
+      match file_reader.read_record(&mut r) {
+        // Blank lines are layout rather than malformed rows, so they're passed over uncounted.
+        // A blank line of a different width still fails the length check, but is read into `r`.
+        Ok(true) if is_blank(&r) => {},
+        // Concatenated files can repeat the header row partway through
+        Ok(true) if file_reader.has_headers() && file_reader.headers().is_ok_and(|headers| is_same_row(headers, &r)) => {},
+        Ok(true) => {
+          self.record_byte = r.position().map_or(self.record_byte, |position| position.byte());
+          return Ok(r)
+        },
+        Ok(false) => break,
+        Err(e) if matches!(e.kind(), ErrorKind::UnequalLengths { .. }) && is_blank(&r) => {},
+        Err(e) if is_row_error(&e) => {
+          self.record_byte = e.position().map_or(self.record_byte, |position| position.byte());
+          if self.strict {
+            return Err(From::from(e))
+          }
+          self.skipped_rows += 1
+        },
+        Err(e) => {
+          self.failed = true;
+          return Err(From::from(e))
+        }
+      }
+    }
+    Err(From::from("No more records!"))
+  }
+  /// Reads the next record and parses it as a transaction using the reader's column map.
+  pub fn transaction(&mut self) -> Result<Transaction, Box<dyn std::error::Error>> {
+    loop {
+      let record = self.record()?;
+      let mut fields: Vec<&str> = record.iter().collect();
+      self.type_synonyms.canonicalize(&mut fields, self.column_map.kind);
+      if !self.scientific_amounts && fields.get(self.column_map.amount).is_some_and(|amount| is_scientific(amount)) {
+        return Err(From::from("Amount in scientific notation."))
+      }
+      match Transaction::from_fields(&fields, &self.column_map) {
+        Err(TransactionParseError::UnknownKind(_)) if self.unknown_types == UnknownTypes::Skip =>
+          self.skipped_unknown_types += 1,
+        transaction => {
+          let transaction = transaction?;
+          transaction.validate()?;
+          return Ok(transaction)
+        },
+      }
+    }
+  }
+  pub fn is_done(&self) -> bool {
+    match &self.source {
+      Source::Csv(file_reader) => self.failed || file_reader.is_done(),
+      #[cfg(feature = "serde_json")]
+      Source::Json { elements, .. } => elements.len() == 0,
+    }
+  }
+  /// Number of bytes of the file consumed so far, for reporting progress against the file's size.
+  /// A JSON file is read whole up front.
+  pub fn position(&self) -> u64 {
+    match &self.source {
+      Source::Csv(file_reader) => file_reader.position().byte(),
+      #[cfg(feature = "serde_json")]
+      Source::Json { len, .. } => *len,
+    }
+  }
+  /// Number of records read so far, including the header row and any malformed rows.
+  pub fn records_read(&self) -> u64 {
+    match &self.source {
+      Source::Csv(file_reader) => file_reader.position().record(),
+      #[cfg(feature = "serde_json")]
+      Source::Json { read, .. } => *read,
+    }
+  }
+  /// Whether reading stopped early because the underlying stream failed.
+  pub fn failed(&self) -> bool {
+    self.failed
+  }
+  /// Line number, starting from 1, of the last record read, including malformed rows. The csv
+  /// reader's own line count skips blank and comment lines, so this re-reads the file up to the
+  /// record to count them. For JSON input, this is the position of the element in the array.
+  pub fn line(&self) -> Result<u64, Box<dyn std::error::Error>> {
+    #[cfg(feature = "serde_json")]
+    if let Source::Json { read, .. } = &self.source {
+      return Ok(*read)
+    }
+    let mut file = BufReader::new(File::open(&self.path)?);
+    let (mut line, mut consumed, mut buf) = (1, 0, Vec::new());
+    loop {
+      buf.clear();
+      let read = file.read_until(b'\n', &mut buf)? as u64;
+      // A record's position is where the reader started looking for it, so it includes any
+      // blank and comment lines leading up to it
+      let skipped = buf.iter().all(u8::is_ascii_whitespace) || buf.starts_with(b"#");
+      if read == 0 || (consumed >= self.record_byte && !skipped) {
+        return Ok(line)
+      }
+      consumed += read;
+      line += 1;
+    }
+  }
+  /// Number of rows skipped so far because they could not be read as a csv record.
+  pub fn skipped_rows(&self) -> usize {
+    self.skipped_rows
+  }
+  /// Number of rows passed over so far because of an unknown type, see `with_unknown_types`.
+  pub fn skipped_unknown_types(&self) -> usize {
+    self.skipped_unknown_types
+  }
+}
+
+/// Whether an error only affects the row being read, so the reader can carry on from the next row.
+fn is_row_error(e: &csv::Error) -> bool {
+  matches!(e.kind(), ErrorKind::Utf8 { .. } | ErrorKind::UnequalLengths { .. })
+}
+
+/// Whether two records hold the same fields, ignoring surrounding whitespace.
+fn is_same_row(a: &StringRecord, b: &StringRecord) -> bool {
+  a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.trim() == b.trim())
+}
+
+/// Whether every field of a record is empty or whitespace.
+fn is_blank(record: &StringRecord) -> bool {
+  record.iter().all(|field| field.trim().is_empty())
+}
+
+pub(crate) fn is_scientific(amount: &str) -> bool {
+  amount.contains(['e', 'E'])
+}
+
+/// What a `TransactionReader` does with a row whose type isn't a known transaction type.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnknownTypes {
+  /// The row is returned as an error, guarding against typos in the type.
+  #[default]
+  Error,
+  /// The row is passed over and counted, for input that mixes in types this program doesn't handle.
+  Skip,
+}
+
+/// The format of a transactions file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InputFormat {
+  /// Worked out from the file's contents.
+  #[default]
+  Auto,
+  Csv,
+  /// Only readable with the `serde_json` feature enabled.
+  Json,
+}
+impl InputFormat {
+  /// Works out a file's format from its first non-whitespace byte, which opens an array or
+  /// object in JSON. Anything else, including an empty file, is taken to be csv.
+  fn sniff(file: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+    let mut reader = BufReader::new(File::open(file)?);
+    loop {
+      let buf = reader.fill_buf()?;
+      if buf.is_empty() {
+        return Ok(Self::Csv)
+      }
+      if let Some(&byte) = buf.iter().find(|byte| !byte.is_ascii_whitespace()) {
+        return Ok(if byte == b'[' || byte == b'{' { Self::Json } else { Self::Csv })
+      }
+      let len = buf.len();
+      reader.consume(len);
+    }
+  }
+}
+impl FromStr for InputFormat {
+  type Err = Box<dyn std::error::Error>;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "auto" => Ok(Self::Auto),
+      "csv" => Ok(Self::Csv),
+      "json" => Ok(Self::Json),
+      _ => Err(From::from(format!("Unknown input format: {}", s))),
+    }
+  }
+}
+
+/// Reads account states from a file in the same `client,available,held,total,locked` format
+/// the summaries are written in. The total column is ignored as it is derived from the others.
+pub fn read_account_states(file: PathBuf) -> Result<Vec<AccountState>, Box<dyn std::error::Error>> {
+  let mut reader = ReaderBuilder::new().from_path(file)?;
+  let mut states = Vec::new();
+  for record in reader.records() {
+    let record = record?;
+    let field = |i: usize| record.get(i).map(str::trim).ok_or("Missing account state field.");
+    states.push(AccountState {
+      client: parse_id::<ClientId>(field(0)?).ok_or("Invalid client field.")?,
+      available: Currency::from_str(field(1)?)?,
+      held: Currency::from_str(field(2)?)?,
+      locked: parse_bool(field(4)?).ok_or("Invalid locked field.")?,
+    });
+  }
+  Ok(states)
+}
+
+/// Reads account summaries, e.g. a known good output to reconcile against, from a file in the
+/// `client,available,held,total,locked` format they're written in.
+pub fn read_account_summaries(file: PathBuf) -> Result<Vec<AccountSummary>, Box<dyn std::error::Error>> {
+  let mut reader = ReaderBuilder::new().from_path(file)?;
+  let mut summaries = Vec::new();
+  for record in reader.records() {
+    let record = record?;
+    let field = |i: usize| record.get(i).map(str::trim).ok_or("Missing account summary field.");
+    let mut summary = AccountSummary::new();
+    summary.client = parse_id::<ClientId>(field(0)?).ok_or("Invalid client field.")?;
+    summary.available = Currency::from_str(field(1)?)?;
+    summary.held = Currency::from_str(field(2)?)?;
+    summary.total = Currency::from_str(field(3)?)?;
+    summary.locked = parse_bool(field(4)?).ok_or("Invalid locked field.")?;
+    summaries.push(summary);
+  }
+  Ok(summaries)
+}
+
+/// How `append_as_csv` combines a client's new summary with one already in the output file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MergePolicy {
+  /// Balances are added together, for runs over separate batches of transactions. The account is
+  /// locked if either summary is.
+  #[default]
+  Sum,
+  /// The new summary replaces the existing one, for runs that reprocess every transaction so far.
+  Replace,
+}
+impl FromStr for MergePolicy {
+  type Err = Box<dyn std::error::Error>;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "sum" => Ok(Self::Sum),
+      "replace" => Ok(Self::Replace),
+      _ => Err(From::from(format!("Unknown merge policy: {}", s))),
+    }
+  }
+}
+
+/// Combines existing account summaries with new ones by client, ordered by client id.
+pub fn merge_account_summaries(
+  existing: Vec<AccountSummary>,
+  new: impl IntoIterator<Item = AccountSummary>,
+  policy: MergePolicy
+) -> Vec<AccountSummary> {
+  let mut merged: BTreeMap<ClientId, AccountSummary> = existing.into_iter().map(|summary| (summary.client, summary)).collect();
+  for summary in new {
+    if let (Some(current), MergePolicy::Sum) = (merged.get_mut(&summary.client), policy) {
+      current.available += &summary.available;
+      current.held += &summary.held;
+      current.effective_available += &summary.effective_available;
+      current.pending += &summary.pending;
+      current.total += &summary.total;
+      current.locked |= summary.locked;
+      continue
+    }
+    merged.insert(summary.client, summary);
+  }
+  merged.into_values().collect()
+}
+
+/// Writes account summaries to the file at `path` in the standard format, merging them with the
+/// summaries it already holds, if it exists, so each client appears once under a single header.
+/// The file only holds balances as they were output, so new balances are rounded the same way
+/// before merging: a client's sum is the sum of what each run wrote, which can differ in the last
+/// place from processing every batch at once. The merged summaries are written to a temporary
+/// file beside `path` and moved into place, so a failed write leaves the existing file as it was.
+pub fn append_as_csv(
+  path: &Path,
+  account_summaries: impl IntoIterator<Item = AccountSummary>,
+  policy: MergePolicy
+) -> Result<(), Box<dyn std::error::Error>> {
+  let existing = if path.exists() { read_account_summaries(path.to_path_buf())? } else { Vec::new() };
+  let rounded = account_summaries.into_iter().map(|summary| summary.normalized(summary.scale().unwrap_or(CURRENCY_SCALE)));
+  let merged = merge_account_summaries(existing, rounded, policy);
+  let file_name = path.file_name().ok_or_else(|| format!("Not a file: {}", path.display()))?;
+  let temporary = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+  let written = File::create(&temporary).map_err(From::from).and_then(|mut file| {
+    write_as_csv(&mut file, merged, &Column::ALL)?;
+    Ok(file.sync_all()?)
+  });
+  match written.and_then(|()| Ok(std::fs::rename(&temporary, path)?)) {
+    Ok(()) => Ok(()),
+    Err(e) => {
+      let _ = std::fs::remove_file(&temporary);
+      Err(e)
+    },
+  }
+}
+
+/// Leniently parses a boolean, accepting `true/false`, `1/0` and `yes/no` in any case with
+/// surrounding whitespace.
+pub fn parse_bool(s: &str) -> Option<bool> {
+  match s.trim().to_ascii_lowercase().as_str() {
+    "true" | "1" | "yes" => Some(true),
+    "false" | "0" | "no" => Some(false),
+    _ => None,
+  }
+}
+
+/// Whether an input names an `http` or `https` URL rather than a local path.
+pub fn is_url(input: &str) -> bool {
+  input.starts_with("http://") || input.starts_with("https://")
+}
+
+pub fn write_as_csv_to_stdout(account_summaries: Vec<AccountSummary>) -> Result<(), Box<dyn std::error::Error>> {
+  write_as_csv(std::io::stdout(), account_summaries, &Column::ALL)
+}
+
+/// A column of the account summary output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Column {
+  Client,
+  Available,
+  Held,
+  Pending,
+  Total,
+  Locked,
+}
+impl Column {
+  /// The standard `client,available,held,total,locked` output.
+  pub const ALL: [Column; 5] = [Column::Client, Column::Available, Column::Held, Column::Total, Column::Locked];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Client => "client",
+      Self::Available => "available",
+      Self::Held => "held",
+      Self::Pending => "pending",
+      Self::Total => "total",
+      Self::Locked => "locked",
+    }
+  }
+  /// The column's field for a summary, with amounts rendered by `format`, at the summary's own
+  /// scale if the ledger rounds the client's balances to one.
+  pub fn value(&self, summary: &AccountSummary, format: &CurrencyFormat) -> String {
+    // A negative scale rounds to tens, hundreds and so on, which are still written as integers
+    let format = summary.scale().map_or(*format, |scale| format.with_precision(scale.max(0) as usize));
+    match self {
+      Self::Client => summary.client().to_string(),
+      Self::Available => format.format(summary.available()),
+      Self::Held => format.format(summary.held()),
+      Self::Pending => format.format(summary.pending()),
+      Self::Total => format.format(summary.total()),
+      Self::Locked => summary.locked().to_string(),
+    }
+  }
+  /// Parses a comma separated list of column names, e.g. `client,total`.
+  pub fn parse_list(s: &str) -> Result<Vec<Column>, Box<dyn std::error::Error>> {
+    let columns = s.split(',').map(|name| name.trim().parse()).collect::<Result<Vec<Column>, _>>()?;
+    check_columns(&columns)?;
+    Ok(columns)
+  }
+}
+impl FromStr for Column {
+  type Err = Box<dyn std::error::Error>;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "client" => Ok(Self::Client),
+      "available" => Ok(Self::Available),
+      "held" => Ok(Self::Held),
+      "pending" => Ok(Self::Pending),
+      "total" => Ok(Self::Total),
+      "locked" => Ok(Self::Locked),
+      _ => Err(From::from(format!("Unknown column: {}", s))),
+    }
+  }
+}
+
+/// How currency amounts are rendered in output. Every writer formats amounts through this, so
+/// they're rounded the same way whichever is used. Input is always parsed with `.` as the
+/// decimal separator and no grouping, whatever the output uses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CurrencyFormat {
+  decimal: char,
+  grouping: Option<char>,
+  precision: i64,
+}
+impl Default for CurrencyFormat {
+  /// `.` as the decimal separator with no grouping, at `CURRENCY_SCALE` decimal places, e.g.
+  /// `1234.5600`.
+  fn default() -> Self {
+    CurrencyFormat::new('.', None)
+  }
+}
+impl CurrencyFormat {
+  /// The most decimal places amounts can be written at, enough for the smallest unit of any
+  /// common currency, e.g. 18 for wei.
+  pub const MAX_PRECISION: usize = 18;
+  /// Uses `decimal` as the decimal separator, and separates the whole part into groups of three
+  /// digits with `grouping` if given. Amounts are written at `CURRENCY_SCALE` decimal places.
+  pub fn new(decimal: char, grouping: Option<char>) -> Self {
+    CurrencyFormat { decimal, grouping, precision: CURRENCY_SCALE }
+  }
+  /// `,` as the decimal separator with `.` grouping, e.g. `1.234,5600`.
+  pub fn european() -> Self {
+    CurrencyFormat::new(',', Some('.'))
+  }
+  /// Writes amounts at `precision` decimal places, rounding any with more. Precision beyond
+  /// `MAX_PRECISION` is capped to it.
+  pub fn with_precision(mut self, precision: usize) -> Self {
+    // Lossless, as MAX_PRECISION is small
+    self.precision = precision.min(Self::MAX_PRECISION) as i64;
+    self
+  }
+  pub fn format(&self, amount: &Currency) -> String {
+    let plain = amount.round(self.precision).with_scale(self.precision).to_string();
+    let (sign, digits) = plain.split_at(if plain.starts_with('-') { 1 } else { 0 });
+    let (whole, fraction) = digits.split_once('.').map_or((digits, None), |(whole, fraction)| (whole, Some(fraction)));
+    let mut formatted = sign.to_string();
+    for (i, digit) in whole.chars().enumerate() {
+      if let Some(grouping) = self.grouping.filter(|_| i > 0 && (whole.len() - i) % 3 == 0) {
+        formatted.push(grouping);
+      }
+      formatted.push(digit);
+    }
+    if let Some(fraction) = fraction {
+      formatted.push(self.decimal);
+      formatted.push_str(fraction);
+    }
+    formatted
+  }
+}
+
+fn check_columns(columns: &[Column]) -> Result<(), Box<dyn std::error::Error>> {
+  if columns.is_empty() {
+    return Err(From::from("No columns to write."))
+  }
+  for (i, column) in columns.iter().enumerate() {
+    if columns[..i].contains(column) {
+      return Err(From::from(format!("Duplicate column: {}", column.name())))
+    }
+  }
+  Ok(())
+}
+
+/// Writes account summaries with the given columns, in order. `Column::ALL` gives the standard
+/// `client,available,held,total,locked` format.
+pub fn write_as_csv<W: Write>(
+  w: W,
+  account_summaries: impl IntoIterator<Item = AccountSummary>,
+  columns: &[Column]
+) -> Result<(), Box<dyn std::error::Error>> {
+  write_as_csv_formatted(w, account_summaries, columns, &CurrencyFormat::default())
+}
+/// Writes account summaries like `write_as_csv`, rendering currency amounts with `format`.
+pub fn write_as_csv_formatted<W: Write>(
+  w: W,
+  account_summaries: impl IntoIterator<Item = AccountSummary>,
+  columns: &[Column],
+  format: &CurrencyFormat
+) -> Result<(), Box<dyn std::error::Error>> {
+  check_columns(columns)?;
+  stop_on_broken_pipe(write_rows(w, account_summaries, columns, format))
+}
+fn write_rows<W: Write>(
+  w: W,
+  account_summaries: impl IntoIterator<Item = AccountSummary>,
+  columns: &[Column],
+  format: &CurrencyFormat
+) -> Result<(), Box<dyn std::error::Error>> {
+  let mut wtr = csv::Writer::from_writer(w);
+  wtr.write_record(columns.iter().map(Column::name))?;
+  for summary in account_summaries {
+    wtr.write_record(row(&summary, columns, format))?;
+  }
+  wtr.flush()?;
+  Ok(())
+}
+/// The output fields for a summary, shared by every writer so their rows are identical.
+fn row<'a>(summary: &'a AccountSummary, columns: &'a [Column], format: &'a CurrencyFormat) -> impl Iterator<Item = String> + 'a {
+  columns.iter().map(move |column| column.value(summary, format))
+}
+
+/// Treats the output being closed partway through, e.g. when piped into `head`, as the end of the
+/// output rather than an error, since nothing is left to read the rest.
+fn stop_on_broken_pipe(result: Result<(), Box<dyn std::error::Error>>) -> Result<(), Box<dyn std::error::Error>> {
+  let broken_pipe = |error: &std::io::Error| error.kind() == std::io::ErrorKind::BrokenPipe;
+  match result {
+    Err(error) if error.downcast_ref().is_some_and(broken_pipe)
+      || error.downcast_ref::<csv::Error>().is_some_and(|error| matches!(error.kind(), ErrorKind::Io(error) if broken_pipe(error))) => Ok(()),
+    result => result,
+  }
+}
+
+/// Writes account summaries like `write_as_csv_formatted`, but only pulls `batch_size` summaries
+/// from the iterator at a time and flushes each batch before calculating the next. Output starts
+/// straight away and memory use stays bounded, however many clients there are.
+pub fn write_as_csv_chunked<W: Write>(
+  w: W,
+  account_summaries: impl IntoIterator<Item = AccountSummary>,
+  columns: &[Column],
+  batch_size: usize,
+  format: &CurrencyFormat
+) -> Result<(), Box<dyn std::error::Error>> {
+  check_columns(columns)?;
+  stop_on_broken_pipe(write_chunks(w, account_summaries, columns, batch_size, format))
+}
+fn write_chunks<W: Write>(
+  w: W,
+  account_summaries: impl IntoIterator<Item = AccountSummary>,
+  columns: &[Column],
+  batch_size: usize,
+  format: &CurrencyFormat
+) -> Result<(), Box<dyn std::error::Error>> {
+  let mut wtr = csv::Writer::from_writer(w);
+  wtr.write_record(columns.iter().map(Column::name))?;
+  let mut account_summaries = account_summaries.into_iter().peekable();
+  let mut batch = Vec::with_capacity(batch_size);
+  while account_summaries.peek().is_some() {
+    batch.extend(account_summaries.by_ref().take(batch_size.max(1)));
+    for summary in batch.drain(..) {
+      wtr.write_record(row(&summary, columns, format))?;
+    }
+    wtr.flush()?;
+  }
+  wtr.flush()?;
+  Ok(())
+}
+
+/// Writes account summaries like `write_as_csv`, but gzip compressed.
+#[cfg(feature = "flate2")]
+pub fn write_as_csv_gzip<W: Write>(
+  w: W,
+  account_summaries: impl IntoIterator<Item = AccountSummary>,
+  columns: &[Column]
+) -> Result<(), Box<dyn std::error::Error>> {
+  let mut encoder = flate2::write::GzEncoder::new(w, flate2::Compression::default());
+  write_as_csv(&mut encoder, account_summaries, columns)?;
+  // Dropping the encoder would also write the gzip trailer, but ignore any error doing so
+  stop_on_broken_pipe(encoder.finish().map(drop).map_err(From::from))
+}
+
+/// Writes account summaries like `write_as_csv_formatted`, ordered by client id, but formats the
+/// rows across threads first. Formatting dominates the write for large numbers of clients, and the
+/// output is identical to writing the sorted summaries sequentially.
+#[cfg(feature = "rayon")]
+pub fn write_as_csv_parallel<W: Write>(
+  w: W,
+  mut account_summaries: Vec<AccountSummary>,
+  columns: &[Column],
+  format: &CurrencyFormat
+) -> Result<(), Box<dyn std::error::Error>> {
+  use rayon::prelude::*;
+  use crate::ledger::SortKey;
+  check_columns(columns)?;
+  SortKey::Client.sort(&mut account_summaries);
+  // Collecting an indexed parallel iterator keeps the rows in their original order
+  let rows: Vec<Vec<String>> = account_summaries.par_iter()
+    .map(|summary| row(summary, columns, format).collect())
+    .collect();
+  stop_on_broken_pipe(write_formatted_rows(w, rows, columns))
+}
+#[cfg(feature = "rayon")]
+fn write_formatted_rows<W: Write>(w: W, rows: Vec<Vec<String>>, columns: &[Column]) -> Result<(), Box<dyn std::error::Error>> {
+  let mut wtr = csv::Writer::from_writer(w);
+  wtr.write_record(columns.iter().map(Column::name))?;
+  for row in rows {
+    wtr.write_record(row)?;
+  }
+  wtr.flush()?;
+  Ok(())
+}
+
+/// Writes every dispute, resolve and chargeback the ledger received, in arrival order with their
+/// arrival index, along with whether it was applied, ignored, or referenced a transaction the
+/// ledger does not hold.
+pub fn write_dispute_audit_csv<W: Write>(w: W, ledger: &Ledger) -> Result<(), Box<dyn std::error::Error>> {
+  let mut wtr = csv::Writer::from_writer(w);
+  wtr.write_record(["index", "type", "client", "tx", "outcome"])?;
+  for entry in ledger.audit_trail() {
+    wtr.write_record(&[
+      entry.index.to_string(),
+      entry.txn.kind_str().to_string(),
+      entry.txn.client_id().to_string(),
+      entry.txn.txn_id().to_string(),
+      entry.outcome.as_str().to_string()
+    ])?;
+  }
+  wtr.flush()?;
+  Ok(())
+}
+
+/// Writes a worklist of every transaction currently under dispute, ordered by client then
+/// transaction id, with the amount each dispute holds.
+pub fn write_open_disputes_csv<W: Write>(w: W, ledger: &Ledger) -> Result<(), Box<dyn std::error::Error>> {
+  let mut wtr = csv::Writer::from_writer(w);
+  wtr.write_record(["client", "tx", "amount", "kind", "held_amount"])?;
+  for (txn, held) in ledger.open_disputes() {
+    wtr.write_record(&[
+      txn.client_id().to_string(),
+      txn.txn_id().to_string(),
+      txn.amount_ref().to_string(),
+      txn.kind_str().to_string(),
+      held.to_string()
+    ])?;
+  }
+  wtr.flush()?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod reader_tests {
+  use super::*;
+
+  const SPEC_EXAMPLE: &str = "testdata/spec_example.csv";
+  const WRONG_EXT: &str = "testdata/non_csv.txt";
+  const MALFORMED_ROWS: &str = "testdata/malformed_rows.csv";
+  const HEADERLESS_REORDERED: &str = "testdata/headerless_reordered.csv";
+  const COMMENTS: &str = "testdata/comments.csv";
+  const ACCOUNT_STATES: &str = "testdata/account_states.csv";
+
+  #[test]
+  fn from_file_valid_csv() {
+    let reader = TransactionReader::from_file(SPEC_EXAMPLE.into());
+    assert!(reader.is_ok());
+  }
+  #[test]
+  fn from_file_non_exists() {
+    let reader = TransactionReader::from_file("".into());
+    assert!(reader.is_err());
+  }
+  #[test]
+  fn from_file_valid_but_wrong_ext() {
+    let reader = TransactionReader::from_file(WRONG_EXT.into());
+    assert!(reader.is_ok());
+  }
+  #[test]
+  fn record_skips_malformed_rows() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file(MALFORMED_ROWS.into())?;
+    let mut records = Vec::new();
+    while !reader.is_done() {
+      if let Ok(record) = reader.record() {
+        records.push(record);
+      }
+    }
+    // The unterminated quote row is still a record, it's just rejected during parsing
+    assert_eq!(4, records.len());
+    assert_eq!(Some("\"1"), records[1].get(1));
+    assert_eq!(1, reader.skipped_rows());
+    assert!(reader.record().is_err());
+    Ok(())
+  }
+  #[test]
+  fn headerless_column_map() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file_headerless(HEADERLESS_REORDERED.into())?
+      .with_column_map(3, 2, 1, 0);
+    let mut transactions = Vec::new();
+    while !reader.is_done() {
+      if let Ok(transaction) = reader.transaction() {
+        transactions.push(transaction);
+      }
+    }
+    assert_eq!(4, transactions.len());
+    assert_eq!(1, transactions[0].client_id());
+    assert_eq!(1, transactions[0].txn_id());
+    assert_eq!(Some("10.0".parse()?), transactions[0].amount());
+    assert_eq!(2, transactions[3].client_id());
+    assert_eq!(Some(false), transactions[2].disputed());
+    assert!(!transactions[3].is_basic());
+    Ok(())
+  }
+  #[test]
+  fn position_increases() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file(SPEC_EXAMPLE.into())?;
+    let mut last_position = reader.position();
+    let mut last_records = reader.records_read();
+    while !reader.is_done() {
+      if reader.record().is_ok() {
+        assert!(reader.position() > last_position);
+        assert!(reader.records_read() > last_records);
+      }
+      last_position = reader.position();
+      last_records = reader.records_read();
+    }
+    assert_eq!(std::fs::metadata(SPEC_EXAMPLE)?.len(), reader.position());
+    Ok(())
+  }
+  #[test]
+  fn comment_lines_skipped() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file(COMMENTS.into())?;
+    let mut transactions = 0;
+    let mut invalid = 0;
+    while !reader.is_done() {
+      match reader.transaction() {
+        Ok(_) => transactions += 1,
+        Err(_) if !reader.is_done() => invalid += 1,
+        Err(_) => {},
+      }
+    }
+    assert_eq!(3, transactions);
+    assert_eq!(0, invalid);
+    assert_eq!(0, reader.skipped_rows());
+    Ok(())
+  }
+  #[test]
+  fn sniff_input_format() -> Result<(), Box<dyn std::error::Error>> {
+    assert_eq!(InputFormat::Csv, InputFormat::sniff(Path::new(SPEC_EXAMPLE))?);
+    assert_eq!(InputFormat::Csv, InputFormat::sniff(Path::new(WRONG_EXT))?);
+    assert_eq!(InputFormat::Json, InputFormat::sniff(Path::new("testdata/spec_example.json"))?);
+    assert_eq!(InputFormat::Json, InputFormat::sniff(Path::new("testdata/spec_example_objects.txt"))?);
+    Ok(())
+  }
+  #[cfg(not(feature = "serde_json"))]
+  #[test]
+  fn json_needs_feature() {
+    assert!(TransactionReader::from_path("testdata/spec_example.json".into(), InputFormat::Auto).is_err());
+    assert!(TransactionReader::from_path("testdata/spec_example.json".into(), InputFormat::Csv).is_ok());
+  }
+  #[test]
+  fn unknown_types() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file("testdata/unknown_types.csv".into())?;
+    assert_eq!("deposit", reader.transaction()?.kind_str());
+    let error = reader.transaction().unwrap_err();
+    assert!(matches!(error.downcast_ref(), Some(TransactionParseError::UnknownKind(kind)) if kind == "transfer"));
+    assert_eq!("withdrawal", reader.transaction()?.kind_str());
+    let mut reader = TransactionReader::from_file("testdata/unknown_types.csv".into())?.with_unknown_types(UnknownTypes::Skip);
+    assert_eq!("deposit", reader.transaction()?.kind_str());
+    assert_eq!("withdrawal", reader.transaction()?.kind_str());
+    assert!(reader.transaction().is_err());
+    assert_eq!(1, reader.skipped_unknown_types());
+    Ok(())
+  }
+  #[cfg(feature = "wide_ids")]
+  #[test]
+  fn wide_ids() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::transactions::BasicTransaction;
+    let csv = "type,client,tx,amount\ndeposit,70000,5000000000,1.5\nwithdrawal,70000,5000000001,0.5\n";
+    let mut reader = TransactionReader::from_bytes(csv.as_bytes().to_vec());
+    let mut l = Ledger::new();
+    while let Ok(transaction) = reader.transaction() {
+      assert_eq!(70000, transaction.client_id());
+      l.add_transaction(transaction);
+    }
+    let summary = l.calculate_client_account_summary(70000).ok_or("no summary for client 70000")?;
+    assert_eq!(Currency::new(1.into(), 0), summary.available);
+    assert_eq!(Some(5_000_000_001), l.transactions_for(70000).last().map(BasicTransaction::txn_id));
+    Ok(())
+  }
+  #[test]
+  fn blank_lines_skipped() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file("testdata/blank_lines.csv".into())?;
+    let mut transactions = Vec::new();
+    let mut invalid = 0;
+    while !reader.is_done() {
+      match reader.transaction() {
+        Ok(transaction) => transactions.push(transaction),
+        Err(_) if !reader.is_done() => invalid += 1,
+        Err(_) => {},
+      }
+    }
+    assert_eq!(3, transactions.len());
+    assert_eq!(0, invalid);
+    assert_eq!(0, reader.skipped_rows());
+    let mut l = Ledger::new();
+    for transaction in transactions {
+      l.add_transaction(transaction);
+    }
+    assert_eq!(&Currency::from_str("2.5")?, l.calculate_client_account_summary(1).unwrap().total());
+    Ok(())
+  }
+  #[test]
+  fn parse_bool_tolerant() {
+    for s in &["true", "TRUE", " True ", "1", "yes", "Yes", "\tYES"] {
+      assert_eq!(Some(true), parse_bool(s), "{:?}", s);
+    }
+    for s in &["false", "False", " 0", "no", "NO "] {
+      assert_eq!(Some(false), parse_bool(s), "{:?}", s);
+    }
+    for s in &["", "y", "2", "truthy", "t rue"] {
+      assert_eq!(None, parse_bool(s), "{:?}", s);
+    }
+  }
+  #[test]
+  fn account_states() -> Result<(), Box<dyn std::error::Error>> {
+    let states = read_account_states(ACCOUNT_STATES.into())?;
+    assert_eq!(3, states.len());
+    assert_eq!(2, states[1].client);
+    assert_eq!(Currency::from_str("3.0")?, states[1].available);
+    assert_eq!(Currency::from_str("1.0")?, states[1].held);
+    assert!(!states[0].locked);
+    assert!(states[1].locked);
+    assert!(states[2].locked);
+    Ok(())
+  }
+  #[test]
+  fn dispute_audit_csv() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    l.replay_from_csv("testdata/disputes.csv".into())?;
+    l.add_transaction(Transaction::new_res(1, 2));
+    l.add_transaction(Transaction::new_cha(1, 100));
+    let mut out = Vec::new();
+    write_dispute_audit_csv(&mut out, &l)?;
+    let out = String::from_utf8(out)?;
+    let mut expected = String::from("index,type,client,tx,outcome\n");
+    for client in 1..=9 {
+      expected += &format!("{},dispute,{},{},applied\n", client * 4 - 1, client, client * 3 - 2);
+    }
+    expected += "36,resolve,1,2,ignored\n37,chargeback,1,100,dangling\n";
+    assert_eq!(expected, out);
+    Ok(())
+  }
+  #[test]
+  fn open_disputes_csv() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    l.replay_from_csv("testdata/open_disputes.csv".into())?;
+    let mut out = Vec::new();
+    write_open_disputes_csv(&mut out, &l)?;
+    let expected = "client,tx,amount,kind,held_amount\n1,2,5.0,deposit,5.0\n2,6,1.25,withdrawal,1.25\n";
+    assert_eq!(expected, String::from_utf8(out)?);
+    Ok(())
+  }
+  #[test]
+  fn chunked_matches_one_shot() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    l.replay_from_csv("testdata/many_clients.csv".into())?;
+    let mut one_shot = Vec::new();
+    write_as_csv(&mut one_shot, l.summaries(), &Column::ALL)?;
+    for batch_size in [1, 100] {
+      let mut chunked = Vec::new();
+      write_as_csv_chunked(&mut chunked, l.summaries(), &Column::ALL, batch_size, &CurrencyFormat::default())?;
+      assert_eq!(String::from_utf8(one_shot.clone())?, String::from_utf8(chunked)?);
+    }
+    let european = CurrencyFormat::european().with_precision(2);
+    let mut one_shot = Vec::new();
+    write_as_csv_formatted(&mut one_shot, l.summaries(), &Column::ALL, &european)?;
+    let mut chunked = Vec::new();
+    write_as_csv_chunked(&mut chunked, l.summaries(), &Column::ALL, 7, &european)?;
+    assert_eq!(String::from_utf8(one_shot)?, String::from_utf8(chunked)?);
+    let mut empty = Vec::new();
+    write_as_csv_chunked(&mut empty, Ledger::new().summaries(), &Column::ALL, 100, &CurrencyFormat::default())?;
+    assert_eq!("client,available,held,total,locked\n", String::from_utf8(empty)?);
+    let mut w = ClosingWriter { written: Vec::new(), capacity: 100, kind: std::io::ErrorKind::BrokenPipe };
+    write_as_csv_chunked(&mut w, l.summaries(), &Column::ALL, 1, &CurrencyFormat::default())?;
+    assert_eq!(100, w.written.len());
+    Ok(())
+  }
+  #[cfg(feature = "flate2")]
+  #[test]
+  fn gzip_decompresses_to_csv() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    l.replay_from_csv(SPEC_EXAMPLE.into())?;
+    let mut plain = Vec::new();
+    write_as_csv(&mut plain, l.summaries(), &Column::ALL)?;
+    let mut gzipped = Vec::new();
+    write_as_csv_gzip(&mut gzipped, l.summaries(), &Column::ALL)?;
+    let mut decompressed = String::new();
+    flate2::read::GzDecoder::new(gzipped.as_slice()).read_to_string(&mut decompressed)?;
+    assert_eq!(String::from_utf8(plain)?, decompressed);
+    assert!(decompressed.starts_with("client,available,held,total,locked\n"));
+    Ok(())
+  }
+  #[cfg(feature = "rayon")]
+  #[test]
+  fn parallel_matches_sequential() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::ledger::SortKey;
+    let mut l = Ledger::new();
+    l.replay_from_csv("testdata/many_clients.csv".into())?;
+    let mut summaries = l.calculate_all_account_summaries();
+    summaries.reverse();
+    let mut parallel = Vec::new();
+    write_as_csv_parallel(&mut parallel, summaries.clone(), &Column::ALL, &CurrencyFormat::default())?;
+    let european = CurrencyFormat::european().with_precision(2);
+    let mut parallel_european = Vec::new();
+    write_as_csv_parallel(&mut parallel_european, summaries.clone(), &Column::ALL, &european)?;
+    let mut w = ClosingWriter { written: Vec::new(), capacity: 100, kind: std::io::ErrorKind::BrokenPipe };
+    write_as_csv_parallel(&mut w, summaries.clone(), &Column::ALL, &CurrencyFormat::default())?;
+    assert_eq!(100, w.written.len());
+    SortKey::Client.sort(&mut summaries);
+    let mut sequential = Vec::new();
+    write_as_csv(&mut sequential, summaries.clone(), &Column::ALL)?;
+    assert_eq!(sequential, parallel);
+    let mut sequential_european = Vec::new();
+    write_as_csv_formatted(&mut sequential_european, summaries, &Column::ALL, &european)?;
+    assert_eq!(sequential_european, parallel_european);
+    Ok(())
+  }
+  /// Accepts `capacity` bytes, then fails every write with `kind`.
+  struct ClosingWriter {
+    written: Vec<u8>,
+    capacity: usize,
+    kind: std::io::ErrorKind,
+  }
+  impl Write for ClosingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+      let n = buf.len().min(self.capacity - self.written.len());
+      if n == 0 {
+        return Err(self.kind.into())
+      }
+      self.written.extend_from_slice(&buf[..n]);
+      Ok(n)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+      Ok(())
+    }
+  }
+  #[test]
+  fn broken_pipe_ends_output() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    for client_id in 0..5000 {
+      l.add_transaction(Transaction::new_dep(client_id, client_id.into(), Currency::from(1)));
+    }
+    let mut w = ClosingWriter { written: Vec::new(), capacity: 100, kind: std::io::ErrorKind::BrokenPipe };
+    write_as_csv(&mut w, l.summaries(), &Column::ALL)?;
+    assert_eq!(100, w.written.len());
+    assert!(w.written.starts_with(b"client,available,held,total,locked\n"));
+    let mut w = ClosingWriter { written: Vec::new(), capacity: 100, kind: std::io::ErrorKind::PermissionDenied };
+    assert!(write_as_csv(&mut w, l.summaries(), &Column::ALL).is_err());
+    Ok(())
+  }
+  #[test]
+  fn selected_columns() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    l.replay_from_csv("testdata/chargeback.csv".into())?;
+    let mut out = Vec::new();
+    write_as_csv(&mut out, l.summaries(), &[Column::Client, Column::Total])?;
+    assert_eq!("client,total\n1,6.0000\n", String::from_utf8(out)?);
+    let mut out = Vec::new();
+    write_as_csv(&mut out, l.summaries(), &Column::parse_list("locked, client")?)?;
+    assert_eq!("locked,client\ntrue,1\n", String::from_utf8(out)?);
+    let mut out = Vec::new();
+    write_as_csv(&mut out, l.summaries(), &Column::ALL)?;
+    assert_eq!("client,available,held,total,locked\n1,6.0000,0.0000,6.0000,true\n", String::from_utf8(out)?);
+    assert!(Column::parse_list("client,balance").is_err());
+    assert!(Column::parse_list("client,total,client").is_err());
+    assert!(write_as_csv(Vec::new(), l.summaries(), &[Column::Held, Column::Held]).is_err());
+    assert!(write_as_csv(Vec::new(), l.summaries(), &[]).is_err());
+    Ok(())
+  }
+  #[test]
+  fn currency_format() -> Result<(), Box<dyn std::error::Error>> {
+    let european = CurrencyFormat::european().with_precision(2);
+    assert_eq!("1.234,56", european.format(&Currency::from_str("1234.56")?));
+    assert_eq!("-1.234.567,50", european.format(&Currency::from_str("-1234567.5")?));
+    assert_eq!("123,40", european.format(&Currency::from_str("123.4")?));
+    assert_eq!("1.000,00", european.format(&Currency::from_str("1000")?));
+    assert_eq!("1.234,57", european.format(&Currency::from_str("1234.5678")?));
+    // Output is at `CURRENCY_SCALE` places by default
+    assert_eq!("1234.5600", CurrencyFormat::default().format(&Currency::from_str("1234.56")?));
+    assert_eq!("1.2346", CurrencyFormat::default().format(&Currency::from_str("1.23456")?));
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(1, 1, Currency::from_str("1234.56")?));
+    let mut out = Vec::new();
+    write_as_csv_formatted(&mut out, l.summaries(), &[Column::Client, Column::Total], &european)?;
+    // The decimal comma means the field is quoted
+    assert_eq!("client,total\n1,\"1.234,56\"\n", String::from_utf8(out)?);
+    // Parsing is unaffected
+    let mut reader = TransactionReader::from_bytes(b"type,client,tx,amount\ndeposit,1,1,1.234,56\n".to_vec());
+    assert!(reader.transaction().is_err());
+    Ok(())
+  }
+  #[test]
+  fn append_merges_by_client() -> Result<(), Box<dyn std::error::Error>> {
+    let path = std::env::temp_dir().join(format!("transaction_processor_append_{}.csv", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let batch = |txns: &[Transaction]| {
+      let mut l = Ledger::new();
+      for txn in txns {
+        l.add_transaction(txn.clone());
+      }
+      l.calculate_all_account_summaries()
+    };
+    let first = batch(&[Transaction::new_dep(1, 1, Currency::from(5)), Transaction::new_dep(2, 2, Currency::from(3))]);
+    append_as_csv(&path, first, MergePolicy::Sum)?;
+    let second = batch(&[
+      Transaction::new_dep(2, 3, Currency::from(4)),
+      Transaction::new_dis(2, 3),
+      Transaction::new_dep(3, 4, Currency::from(1)),
+    ]);
+    append_as_csv(&path, second.clone(), MergePolicy::Sum)?;
+    assert_eq!(
+      "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n2,3.0000,4.0000,7.0000,false\n3,1.0000,0.0000,1.0000,false\n",
+      std::fs::read_to_string(&path)?
+    );
+    append_as_csv(&path, second, MergePolicy::Replace)?;
+    assert_eq!(
+      "client,available,held,total,locked\n1,5.0000,0.0000,5.0000,false\n2,0.0000,4.0000,4.0000,false\n3,1.0000,0.0000,1.0000,false\n",
+      std::fs::read_to_string(&path)?
+    );
+    // Each run adds what it wrote, rounded, rather than its full precision balance
+    let precise = batch(&[Transaction::new_dep(4, 5, Currency::from_str("0.00006")?)]);
+    append_as_csv(&path, precise.clone(), MergePolicy::Sum)?;
+    append_as_csv(&path, precise, MergePolicy::Sum)?;
+    assert!(std::fs::read_to_string(&path)?.ends_with("\n4,0.0002,0.0000,0.0002,false\n"));
+    let temporary = path.with_file_name(format!(".{}.tmp", path.file_name().unwrap().to_string_lossy()));
+    assert!(!temporary.exists());
+    std::fs::remove_file(&path)?;
+    // Failing to create the merged file is an error
+    assert!(append_as_csv(&std::env::temp_dir().join("missing_dir").join("out.csv"), Vec::new(), MergePolicy::Sum).is_err());
+    assert!("add".parse::<MergePolicy>().is_err());
+    Ok(())
+  }
+}
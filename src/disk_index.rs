@@ -0,0 +1,95 @@
+//! An on-disk index of a ledger's stored transactions, so they needn't all be held in memory.
+use std::fmt;
+#[cfg(feature = "sled")]
+use std::path::Path;
+use crate::{TxnId, transactions::BasicTransaction};
+
+/// Transactions keyed by id in a sled database. Reads or writes that fail are treated as the
+/// transaction not being held, so the ledger keeps it in memory instead.
+#[cfg(feature = "sled")]
+pub struct DiskIndex {
+  db: sled::Db,
+}
+#[cfg(feature = "sled")]
+impl DiskIndex {
+  /// Opens the index at `path`, clearing anything a previous run left there.
+  pub fn open(path: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+    let db = sled::open(path)?;
+    db.clear()?;
+    Ok(DiskIndex { db })
+  }
+  pub fn get(&self, txn_id: TxnId) -> Option<BasicTransaction> {
+    let bytes = self.db.get(txn_id.to_be_bytes()).ok()??;
+    bincode::deserialize(&bytes).ok()
+  }
+  pub fn contains(&self, txn_id: TxnId) -> bool {
+    self.db.contains_key(txn_id.to_be_bytes()).unwrap_or(false)
+  }
+  /// Returns whether the transaction was written.
+  pub fn insert(&self, txn: &BasicTransaction) -> bool {
+    bincode::serialize(txn).is_ok_and(|bytes| self.db.insert(txn.txn_id().to_be_bytes(), bytes).is_ok())
+  }
+  pub fn remove(&self, txn_id: TxnId) -> Option<BasicTransaction> {
+    let bytes = self.db.remove(txn_id.to_be_bytes()).ok()??;
+    bincode::deserialize(&bytes).ok()
+  }
+  /// Every transaction in the index, in id order.
+  pub fn txns(&self) -> impl Iterator<Item = BasicTransaction> + '_ {
+    self.db.iter().values().filter_map(|bytes| bincode::deserialize(&bytes.ok()?).ok())
+  }
+  pub fn len(&self) -> usize {
+    self.db.len()
+  }
+}
+/// A clone copies the transactions into a new temporary index, so it can be changed without
+/// affecting the original.
+#[cfg(feature = "sled")]
+impl Clone for DiskIndex {
+  fn clone(&self) -> Self {
+    let db = sled::Config::new().temporary(true).open().expect("Failed to create a temporary disk index.");
+    let copy = DiskIndex { db };
+    for txn in self.txns() {
+      copy.insert(&txn);
+    }
+    copy
+  }
+}
+/// Indexes are equal if they hold the same transactions.
+#[cfg(feature = "sled")]
+impl PartialEq for DiskIndex {
+  fn eq(&self, other: &Self) -> bool {
+    self.txns().eq(other.txns())
+  }
+}
+
+/// Without the `sled` feature there is no disk index, so a ledger never has one.
+#[cfg(not(feature = "sled"))]
+#[derive(Clone, PartialEq)]
+pub enum DiskIndex {}
+#[cfg(not(feature = "sled"))]
+impl DiskIndex {
+  pub fn get(&self, _: TxnId) -> Option<BasicTransaction> {
+    match *self {}
+  }
+  pub fn contains(&self, _: TxnId) -> bool {
+    match *self {}
+  }
+  pub fn insert(&self, _: &BasicTransaction) -> bool {
+    match *self {}
+  }
+  pub fn remove(&self, _: TxnId) -> Option<BasicTransaction> {
+    match *self {}
+  }
+  pub fn txns(&self) -> impl Iterator<Item = BasicTransaction> + '_ {
+    std::iter::empty()
+  }
+  pub fn len(&self) -> usize {
+    match *self {}
+  }
+}
+
+impl fmt::Debug for DiskIndex {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("DiskIndex")
+  }
+}
@@ -1,122 +1,2081 @@
-
-use std::collections::{HashMap, HashSet, BTreeSet};
-
-use bigdecimal::BigDecimal;
-
-use crate::{
-  ClientId,
-  TxnId,
-  Currency,
-  transactions::{
-  BasicTransaction,
-  ReferentialTransaction,
-  Transaction
-}};
-
-#[derive(Clone, Debug)]
-pub struct Ledger {
-  pub txns: HashMap<TxnId, BasicTransaction>,
-  pub clients: HashMap<ClientId, BTreeSet<TxnId>>, // BTreeSet to preserve ordering of transactions (IMPORTANT!)
-  pub locked_clients: HashSet<ClientId>,
-}
-impl Ledger {
-  pub fn new() -> Self {
-      Ledger {
-          txns: HashMap::new(),
-          clients: HashMap::new(),
-          locked_clients: HashSet::new(),
-      }
-  }
-  pub fn add_simple_transaction(&mut self, txn: BasicTransaction) {
-    if !self.locked_clients.contains(&txn.client_id()) {
-      if !self.clients.contains_key(&txn.client_id()) {
-        self.clients.insert(txn.client_id(), BTreeSet::new());
-      }
-      if let Some(transaction_ids) = self.clients.get_mut(&txn.client_id()) {
-        transaction_ids.insert(txn.txn_id());
-        self.txns.insert(txn.txn_id(), txn);
-      }
-    }
-  }
-  pub fn add_transaction(&mut self, txn: Transaction) {
-    match txn {
-      Transaction::Basic(inner_txn) => return self.add_simple_transaction(inner_txn),
-      Transaction::Referential(ReferentialTransaction::Dispute {client_id: _, txn_id}) =>
-      if let Some(txn) = self.txns.get_mut(&txn_id) {
-        txn.set_disputed(true);
-      },
-      Transaction::Referential(ReferentialTransaction::Resolve {client_id: _, txn_id}) =>
-      if let Some(txn) = self.txns.get_mut(&txn_id) {
-        txn.set_disputed(false);
-      },
-      Transaction::Referential(ReferentialTransaction::Chargeback{client_id, txn_id})
-      if self.txns.contains_key(&txn_id)
-      // Unwrap safety: Due to short-circuiting, is self.txns does not contain txn_id then self.txns.get(&txn_id).unwrap() will never be evaluated
-      && self.txns.get(&txn_id).unwrap().disputed()
-      && self.clients.contains_key(&client_id) => {
-        self.txns.remove(&txn_id);
-        // Unwrap safety: Already checked self.clients contains client_id 
-        self.clients.get_mut(&client_id).unwrap().remove(&txn_id);
-        self.locked_clients.insert(txn.client_id());
-      },
-      _ => {},
-    }
-  }
-  pub fn calculate_all_account_summaries(&self) -> Vec<AccountSummary> {
-      let mut summaries = Vec::new();
-      for (&client_id, _) in &self.clients {
-        if let Some(summary) = self.calculate_client_account_summary(client_id) {
-          summaries.push(summary);
-        }
-      }
-      summaries
-  }
-  pub fn calculate_client_account_summary(&self, client_id: ClientId) -> Option<AccountSummary> {
-    // Grab transaction ids for client account
-    if let Some(txn_ids) = self.clients.get(&client_id) {
-      let mut acc = AccountSummary::new();
-      acc.client = client_id;
-      // For every transaction id, get the transaction and add if deposit else minus if withdrawal
-      for txn_id in txn_ids {
-        match self.txns.get(txn_id) {
-          Some(BasicTransaction::Deposit{client_id: _, txn_id: _, amount, disputed: false}) => acc.available += amount.clone(),
-          Some(BasicTransaction::Withdrawal{client_id: _, txn_id: _, amount, disputed: false}) if *amount <= acc.available => acc.available -= amount.clone(),
-          Some(BasicTransaction::Deposit{client_id: _, txn_id: _, amount, disputed: true}) => acc.held += amount.clone(),
-          Some(BasicTransaction::Withdrawal{client_id: _, txn_id: _, amount, disputed: true}) if *amount <= acc.available => {
-            // Funds are still removed from available funds (transaction pending)
-            // but funds placed in held until dispute resolved
-            acc.available -= amount.clone();
-            acc.held += amount.clone();
-          },
-          _ => {/* Do nothing when a withdrawal would have put account in negative balance */},
-        }
-      }
-      acc.total = acc.available.clone() + acc.held.clone();
-      acc.locked = self.locked_clients.contains(&client_id);
-      Some(acc)
-    }
-    else {
-      None
-    }
-  }
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub struct AccountSummary {
-  pub client: ClientId,
-  pub available: Currency,
-  pub held: Currency,
-  pub total: Currency,
-  pub locked: bool,
-}
-impl AccountSummary {
-  pub fn new() -> Self {
-    AccountSummary {
-      client: 0,
-      available: BigDecimal::new(num::zero(), 4),
-      held: BigDecimal::new(num::zero(), 4),
-      total: BigDecimal::new(num::zero(), 4),
-      locked: false
-    }
-  }
-}
\ No newline at end of file
+
+use std::{
+  borrow::Cow,
+  collections::{BTreeMap, HashMap, HashSet, BTreeSet, hash_map::{self, DefaultHasher, RandomState}},
+  fmt,
+  hash::{BuildHasher, Hash},
+  fs,
+  path::PathBuf,
+  str::FromStr,
+  sync::Arc
+};
+#[cfg(feature = "bincode")]
+use std::io::{Read, Write};
+use csv::StringRecord;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+  ClientId,
+  TxnId,
+  Currency,
+  CurrencyExt,
+  CURRENCY_SCALE,
+  Timestamp,
+  clock::Clock,
+  csv_handlers::{is_scientific, Column, CurrencyFormat, InputFormat, TransactionReader},
+  disk_index::DiskIndex,
+  transactions::{
+  BasicTransaction,
+  ColumnMap,
+  DisputeState,
+  ReferentialTransaction,
+  Transaction,
+  TypeSynonyms,
+  ValidationError
+}};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ledger {
+  /// Stored deposits and withdrawals. With a disk index, only those that are under or past
+  /// dispute, or awaiting settlement, are held here.
+  pub txns: HashMap<TxnId, BasicTransaction, LedgerHasher>,
+  // The index is backed by a database on disk, so isn't part of the ledger's serialized state
+  #[serde(skip)]
+  disk: Option<DiskIndex>,
+  pub clients: HashMap<ClientId, BTreeSet<TxnId>, LedgerHasher>,
+  pub locked_clients: HashSet<ClientId, LedgerHasher>,
+  /// The arrival index of each stored transaction, as ids needn't ascend in arrival order.
+  arrivals: HashMap<TxnId, u64>,
+  /// The arrival index of the dispute that opened each transaction's current dispute.
+  dispute_opened: HashMap<TxnId, u64>,
+  /// Ids of transactions removed by `compact`, kept so they can't be reused.
+  compacted: HashSet<TxnId>,
+  dispute_window: Option<u64>,
+  balances: HashMap<ClientId, Balance>,
+  /// Funds not backed by a stored transaction, i.e. opening balances and adjustments.
+  untracked: HashMap<ClientId, Balance>,
+  disputable_kinds: DisputableKinds,
+  dispute_shortfall: DisputeShortfall,
+  total_model: TotalModel,
+  /// Amounts held by disputes which held less than the transaction's full amount, under
+  /// `DisputeShortfall::HoldAvailable`, or reversed by a chargeback of only part of a dispute.
+  /// Kept after a chargeback, as the rest of the transaction stands.
+  shortfall_holds: HashMap<TxnId, Currency>,
+  settlement: bool,
+  scientific_amounts: bool,
+  input_format: InputFormat,
+  type_synonyms: TypeSynonyms,
+  // Closures can't be serialized, so a loaded ledger has no client filter
+  #[serde(skip)]
+  client_filter: Option<ClientFilter>,
+  #[serde(skip)]
+  clock: Option<LedgerClock>,
+  client_scales: HashMap<ClientId, i64>,
+  max_scale: Option<i64>,
+  velocity_limit: Option<VelocityLimit>,
+  velocity: HashMap<ClientId, (usize, Currency)>,
+  flagged: BTreeSet<ClientId>,
+  skipped_by_filter: usize,
+  dropped_by_lock: usize,
+  lock_warnings: bool,
+  anomalies: HashMap<ClientId, Vec<Anomaly>>,
+  audit: Vec<AuditEntry>,
+  /// The arrival index the next transaction to be applied takes.
+  next_arrival: u64,
+  /// Transactions given an arrival index ahead of `next_arrival`, waiting on the ones before them.
+  early_arrivals: BTreeMap<u64, Transaction>,
+}
+impl Ledger {
+  pub fn new() -> Self {
+      Ledger {
+          txns: HashMap::default(),
+          disk: None,
+          clients: HashMap::default(),
+          locked_clients: HashSet::default(),
+          arrivals: HashMap::new(),
+          dispute_opened: HashMap::new(),
+          compacted: HashSet::new(),
+          dispute_window: None,
+          balances: HashMap::new(),
+          untracked: HashMap::new(),
+          disputable_kinds: DisputableKinds::default(),
+          dispute_shortfall: DisputeShortfall::default(),
+          total_model: TotalModel::default(),
+          shortfall_holds: HashMap::new(),
+          settlement: false,
+          scientific_amounts: false,
+          input_format: InputFormat::default(),
+          type_synonyms: TypeSynonyms::default(),
+          client_filter: None,
+          clock: None,
+          client_scales: HashMap::new(),
+          max_scale: None,
+          velocity_limit: None,
+          velocity: HashMap::new(),
+          flagged: BTreeSet::new(),
+          skipped_by_filter: 0,
+          dropped_by_lock: 0,
+          lock_warnings: false,
+          anomalies: HashMap::new(),
+          audit: Vec::new(),
+          next_arrival: 0,
+          early_arrivals: BTreeMap::new(),
+      }
+  }
+  /// Creates a ledger starting from previously calculated account states, e.g. a prior run's output.
+  pub fn from_account_states(states: impl IntoIterator<Item = AccountState>) -> Self {
+    let mut ledger = Self::new();
+    for state in states {
+      ledger.credit_untracked(state.client, &state.available, &state.held);
+      if state.locked {
+        ledger.locked_clients.insert(state.client);
+      }
+    }
+    ledger
+  }
+  /// Adds an opening balance to each client's available funds, e.g. carried over from a prior
+  /// period. Opening balances are not transactions, so they can't be disputed.
+  pub fn seed_balances(&mut self, balances: HashMap<ClientId, Currency>) {
+    for (client_id, amount) in balances {
+      self.credit_untracked(client_id, &amount, &Currency::zero_with_scale());
+    }
+  }
+  /// Places an administrative hold, moving `amount` of a client's available funds into held
+  /// regardless of any dispute. Returns whether the hold was placed, which it isn't if the amount
+  /// is negative or more than the client has available.
+  pub fn place_hold(&mut self, client_id: ClientId, amount: &Currency) -> bool {
+    let amount = &self.limit_scale(amount);
+    match self.balances.get_mut(&client_id) {
+      Some(balance) if *amount >= Currency::from(0) && *amount <= balance.available => {
+        // The move isn't backed by a transaction, so it's tracked alongside other untracked funds
+        for balance in [balance, self.untracked.entry(client_id).or_default()] {
+          balance.available -= amount;
+          balance.admin_held += amount;
+        }
+        true
+      },
+      _ => false,
+    }
+  }
+  /// Releases an administrative hold, moving `amount` back into the client's available funds.
+  /// Returns whether it was released, which it isn't if the amount is negative or more than is
+  /// administratively held, so funds held by disputes can't be released this way.
+  pub fn release_hold(&mut self, client_id: ClientId, amount: &Currency) -> bool {
+    let amount = &self.limit_scale(amount);
+    match self.balances.get_mut(&client_id) {
+      Some(balance) if *amount >= Currency::from(0) && *amount <= balance.admin_held => {
+        for balance in [balance, self.untracked.entry(client_id).or_default()] {
+          balance.admin_held -= amount;
+          balance.available += amount;
+        }
+        true
+      },
+      _ => false,
+    }
+  }
+  /// Clears every open dispute against a transaction, releasing its held funds, for correcting
+  /// disputes that were applied in error. Returns whether the transaction was under dispute; a
+  /// charged back transaction can't be reset.
+  pub fn reset_dispute(&mut self, txn_id: TxnId) -> bool {
+    self.with_loaded(txn_id, |ledger| match ledger.txns.get_mut(&txn_id) {
+      Some(txn) if txn.disputed() => {
+        txn.clear_disputes();
+        ledger.balances.entry(txn.client_id()).or_default().release(txn, dispute_hold(&ledger.shortfall_holds, txn));
+        ledger.shortfall_holds.remove(&txn_id);
+        ledger.dispute_opened.remove(&txn_id);
+        true
+      },
+      _ => false,
+    })
+  }
+  /// Adds funds that aren't backed by a stored transaction to a client's account.
+  fn credit_untracked(&mut self, client_id: ClientId, available: &Currency, held: &Currency) {
+    let (available, held) = (&self.limit_scale(available), &self.limit_scale(held));
+    self.clients.entry(client_id).or_default();
+    for balances in [&mut self.balances, &mut self.untracked] {
+      let balance = balances.entry(client_id).or_default();
+      balance.available += available;
+      balance.held += held;
+    }
+  }
+  /// Sets which kinds of transaction can be referenced by a dispute, resolve or chargeback.
+  pub fn with_disputable_kinds(mut self, disputable_kinds: DisputableKinds) -> Self {
+    self.disputable_kinds = disputable_kinds;
+    self
+  }
+  /// Sets how a dispute is handled when it's for more than the client has available, e.g. a
+  /// deposit that has since been partly withdrawn.
+  pub fn with_dispute_shortfall(mut self, dispute_shortfall: DisputeShortfall) -> Self {
+    self.dispute_shortfall = dispute_shortfall;
+    self
+  }
+  /// Sets how account summaries calculate `total`, which only differs while a withdrawal is disputed.
+  pub fn with_total_model(mut self, total_model: TotalModel) -> Self {
+    self.total_model = total_model;
+    self
+  }
+  /// When enabled, deposits are pending until settled with `settle` or `settle_all` and their
+  /// funds cannot be withdrawn until then. Otherwise deposits are settled as they arrive.
+  pub fn with_settlement(mut self, settlement: bool) -> Self {
+    self.settlement = settlement;
+    self
+  }
+  /// Sets whether amounts in scientific notation are accepted when replaying csv files. They are
+  /// rejected by default.
+  pub fn with_scientific_amounts(mut self, scientific_amounts: bool) -> Self {
+    self.scientific_amounts = scientific_amounts;
+    self
+  }
+  /// Sets the format files are read in when replaying them. By default it's worked out from each
+  /// file's contents.
+  pub fn with_input_format(mut self, input_format: InputFormat) -> Self {
+    self.input_format = input_format;
+    self
+  }
+  /// Sets other names accepted for the transaction types when replaying files or processing
+  /// records, e.g. `credit` for a deposit. Only the canonical names are accepted by default.
+  pub fn with_type_synonyms(mut self, type_synonyms: TypeSynonyms) -> Self {
+    self.type_synonyms = type_synonyms;
+    self
+  }
+  /// Only transactions for clients accepted by `filter` are added to the ledger, the rest are
+  /// skipped and counted in `skipped_by_filter`.
+  pub fn with_client_filter(mut self, filter: impl Fn(ClientId) -> bool + Send + Sync + 'static) -> Self {
+    self.client_filter = Some(ClientFilter(Arc::new(filter)));
+    self
+  }
+  /// Prints a warning to stderr for each transaction dropped because its client's account is
+  /// locked. They're counted in `dropped_by_lock` either way.
+  pub fn with_lock_warnings(mut self, lock_warnings: bool) -> Self {
+    self.lock_warnings = lock_warnings;
+    self
+  }
+  /// Tags each deposit and withdrawal that doesn't already have a timestamp with the clock's time
+  /// as it is added. Without a clock, only timestamps read from the input are kept.
+  pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+    self.clock = Some(LedgerClock(Arc::new(clock)));
+    self
+  }
+  /// Rounds a client's summary balances to `scale` decimal places, for accounts held in a
+  /// currency with a different natural precision, e.g. 0 for JPY or 8 for BTC. Balances are
+  /// tracked at full precision, or the maximum scale if set, only summaries are rounded.
+  pub fn with_client_scale(mut self, client_id: ClientId, scale: i64) -> Self {
+    self.client_scales.insert(client_id, scale);
+    self
+  }
+  /// Limits every balance to `scale` decimal places. Amounts with more are rounded as they enter
+  /// the ledger, so every addition and subtraction stays within the scale rather than growing to
+  /// the finest amount seen, at the cost of tracking sub-scale amounts.
+  pub fn with_max_scale(mut self, scale: i64) -> Self {
+    self.max_scale = Some(scale);
+    self
+  }
+  /// Rounds an amount entering the ledger to the maximum scale, if there is one.
+  fn limit_scale(&self, amount: &Currency) -> Currency {
+    self.max_scale.map_or_else(|| amount.clone(), |scale| amount.limit_scale(scale))
+  }
+  /// Keeps stored transactions in an on-disk index at `path` rather than in `txns`, so inputs too
+  /// large to hold every transaction in memory can still be disputed. Transactions under or past
+  /// dispute, or awaiting settlement, stay in memory, as do balances. Anything left at `path` by
+  /// a previous run is cleared. Requires the `sled` feature.
+  pub fn with_disk_index(self, path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+    #[cfg(feature = "sled")]
+    {
+      let mut ledger = self;
+      ledger.disk = Some(DiskIndex::open(&path)?);
+      let txn_ids: Vec<TxnId> = ledger.txns.keys().copied().collect();
+      for txn_id in txn_ids {
+        ledger.unload(txn_id);
+      }
+      Ok(ledger)
+    }
+    #[cfg(not(feature = "sled"))]
+    {
+      drop(self);
+      Err(From::from(format!("A disk index at {} requires the sled feature.", path.display())))
+    }
+  }
+  /// Hashes `txns`, `clients` and `locked_clients` with fixed keys rather than random ones, so
+  /// ledgers built the same way iterate them in the same order, e.g. to compare debugging output
+  /// between runs. A ledger loaded by `load_bincode` hashes randomly again.
+  pub fn with_deterministic_hasher(mut self) -> Self {
+    let txns = std::mem::replace(&mut self.txns, HashMap::with_hasher(LedgerHasher::Fixed));
+    self.txns.extend(txns);
+    let clients = std::mem::replace(&mut self.clients, HashMap::with_hasher(LedgerHasher::Fixed));
+    self.clients.extend(clients);
+    let locked_clients = std::mem::replace(&mut self.locked_clients, HashSet::with_hasher(LedgerHasher::Fixed));
+    self.locked_clients.extend(locked_clients);
+    self
+  }
+  /// Only transactions among the last `arrivals` to arrive, counting referential transactions,
+  /// can be disputed; a dispute of an older one is ignored. Disputes already open can still be
+  /// resolved or charged back however old the transaction gets. Transactions that can no longer
+  /// be disputed can be dropped with `compact`.
+  pub fn with_dispute_window(mut self, arrivals: u64) -> Self {
+    self.dispute_window = Some(arrivals);
+    self
+  }
+  /// Whether the transaction arrived too long ago to be disputed under the dispute window.
+  fn outside_dispute_window(&self, txn_id: TxnId) -> bool {
+    let arrival = self.arrivals.get(&txn_id);
+    self.dispute_window.is_some_and(|window| arrival.is_some_and(|&arrival| self.next_arrival - arrival > window))
+  }
+  /// Flags clients sending more than `max_txns` deposits and withdrawals, or more than
+  /// `max_volume` in total across them. Flagging is only recorded, transactions are still processed.
+  pub fn with_velocity_limit(mut self, max_txns: usize, max_volume: Currency) -> Self {
+    self.velocity_limit = Some(VelocityLimit { max_txns, max_volume });
+    self
+  }
+  /// Clients that have exceeded the velocity limit, in client id order.
+  #[must_use]
+  pub fn flagged_accounts(&self) -> Vec<ClientId> {
+    self.flagged.iter().copied().collect()
+  }
+  /// Clients whose accounts are locked, in client id order.
+  #[must_use]
+  pub fn locked_client_ids(&self) -> Vec<ClientId> {
+    let mut client_ids: Vec<ClientId> = self.locked_clients.iter().copied().collect();
+    client_ids.sort_unstable();
+    client_ids
+  }
+  /// Whether a chargeback has locked the client's account. A locked account takes no more deposits,
+  /// withdrawals or adjustments, but any other disputes open when it locked are frozen as they
+  /// are: their funds stay held until each is resolved or charged back as usual.
+  #[must_use]
+  pub fn is_locked(&self, client_id: ClientId) -> bool {
+    self.locked_clients.contains(&client_id)
+  }
+  /// Number of transactions skipped because their client was rejected by the client filter.
+  #[must_use]
+  pub fn skipped_by_filter(&self) -> usize {
+    self.skipped_by_filter
+  }
+  /// Number of deposits, withdrawals and adjustments dropped because their client's account was
+  /// locked. Disputes, resolves and chargebacks still act on a locked account, so aren't counted.
+  #[must_use]
+  pub fn dropped_by_lock(&self) -> usize {
+    self.dropped_by_lock
+  }
+  /// Records a deposit or withdrawal as reusing a transaction id without applying it, for ids
+  /// taken outside this ledger, e.g. by another shard of a `ShardedLedger`.
+  pub(crate) fn reject_duplicate(&mut self, txn: &BasicTransaction) {
+    self.clients.entry(txn.client_id()).or_default();
+    self.record_anomaly(txn.client_id(), Anomaly::DuplicateTxnId { txn_id: txn.txn_id() });
+  }
+  /// Adds a deposit or withdrawal, the same as `add_transaction`.
+  pub fn add_simple_transaction(&mut self, txn: BasicTransaction) {
+    self.add_transaction(Transaction::Basic(txn));
+  }
+  fn store_simple_transaction(&mut self, index: u64, mut txn: BasicTransaction) {
+    let client_id = txn.client_id();
+    if let Some(limit) = &self.velocity_limit {
+      // Every attempt counts towards the limit, whether or not it ends up applied
+      let (count, volume) = self.velocity.entry(client_id).or_insert_with(|| (0, Currency::zero_with_scale()));
+      *count += 1;
+      *volume += txn.amount_ref();
+      if *count > limit.max_txns || *volume > limit.max_volume {
+        self.flagged.insert(client_id);
+      }
+    }
+    if self.locked_clients.contains(&client_id) {
+      return
+    }
+    // Transaction ids are globally unique, so reusing one, even for another client, is rejected
+    // rather than replacing the transaction already held
+    if self.is_stored(txn.txn_id()) || self.compacted.contains(&txn.txn_id()) {
+      self.clients.entry(client_id).or_default();
+      self.record_anomaly(client_id, Anomaly::DuplicateTxnId { txn_id: txn.txn_id() });
+      return
+    }
+    if self.settlement {
+      txn.set_settled(false);
+    }
+    if let (None, Some(LedgerClock(clock))) = (txn.timestamp(), &self.clock) {
+      txn.set_timestamp(clock.now());
+    }
+    // Balances are updated as transactions arrive, so a withdrawal is checked against the funds
+    // available at that moment rather than what a later dispute leaves behind
+    let balance = self.balances.entry(client_id).or_default();
+    match &txn {
+      BasicTransaction::Deposit { amount, settled: false, .. } => balance.pending += amount,
+      BasicTransaction::Deposit { amount, .. } => balance.available += amount,
+      BasicTransaction::Withdrawal { amount, .. } if *amount <= balance.available => balance.available -= amount,
+      BasicTransaction::Withdrawal { amount, .. } => {
+        // Withdrawals that would put the account into a negative balance are never applied
+        let anomaly = Anomaly::Overdraft { txn_id: txn.txn_id(), amount: amount.clone() };
+        self.clients.entry(client_id).or_default();
+        self.record_anomaly(client_id, anomaly);
+        return
+      },
+    }
+    self.clients.entry(client_id).or_default().insert(txn.txn_id());
+    let txn_id = txn.txn_id();
+    self.arrivals.insert(txn_id, index);
+    self.txns.insert(txn_id, txn);
+    self.unload(txn_id);
+  }
+  /// Looks up a stored transaction, whether it's held in memory or in the disk index.
+  fn stored(&self, txn_id: TxnId) -> Option<Cow<'_, BasicTransaction>> {
+    stored_in(&self.txns, &self.disk, txn_id)
+  }
+  fn is_stored(&self, txn_id: TxnId) -> bool {
+    self.txns.contains_key(&txn_id) || self.disk.as_ref().is_some_and(|disk| disk.contains(txn_id))
+  }
+  /// Moves a transaction from the disk index into memory, so it can be changed in place.
+  fn load(&mut self, txn_id: TxnId) {
+    if let Some(txn) = self.disk.as_ref().and_then(|disk| disk.remove(txn_id)) {
+      self.txns.insert(txn_id, txn);
+    }
+  }
+  /// Moves a transaction to the disk index, if there is one, unless it's under or past dispute
+  /// or awaiting settlement. Those are kept in memory, as every open dispute and pending deposit
+  /// is looked through when calculating summaries and settling.
+  fn unload(&mut self, txn_id: TxnId) {
+    if let (Some(disk), Some(txn)) = (&self.disk, self.txns.get(&txn_id)) {
+      if txn.settled() && txn.dispute_state() == DisputeState::None && disk.insert(txn) {
+        self.txns.remove(&txn_id);
+      }
+    }
+  }
+  /// Runs `f` with the transaction loaded into memory, unloading it again afterwards.
+  fn with_loaded<R>(&mut self, txn_id: TxnId, f: impl FnOnce(&mut Self) -> R) -> R {
+    self.load(txn_id);
+    let result = f(self);
+    self.unload(txn_id);
+    result
+  }
+  pub fn add_transaction(&mut self, txn: Transaction) {
+    self.apply_transaction_at(self.next_arrival, txn);
+  }
+  /// Adds a transaction as the `index`th to arrive, counting from zero, rather than after every
+  /// transaction added so far. A transaction whose index is ahead of the ledger waits until every
+  /// earlier index has been added, so transactions are always applied in index order whatever
+  /// order they're given in. `add_transaction` takes the next index in turn.
+  /// Returns false, without adding the transaction, if its index has already been taken.
+  pub fn apply_transaction_at(&mut self, index: u64, txn: Transaction) -> bool {
+    if index < self.next_arrival || self.early_arrivals.contains_key(&index) {
+      return false
+    }
+    self.early_arrivals.insert(index, txn);
+    while let Some(txn) = self.early_arrivals.remove(&self.next_arrival) {
+      self.apply_arrival(self.next_arrival, txn);
+      self.next_arrival += 1;
+    }
+    true
+  }
+  /// The number of transactions waiting on an earlier arrival index before they can be applied.
+  pub fn awaiting_arrivals(&self) -> usize {
+    self.early_arrivals.len()
+  }
+  fn apply_arrival(&mut self, index: u64, mut txn: Transaction) {
+    if let Some(scale) = self.max_scale {
+      txn.limit_scale(scale);
+    }
+    if let Some(ClientFilter(filter)) = &self.client_filter {
+      if !filter(txn.client_id()) {
+        self.skipped_by_filter += 1;
+        return
+      }
+    }
+    let (client_id, txn_id) = (txn.client_id(), txn.txn_id());
+    if self.locked_clients.contains(&client_id) && !matches!(txn, Transaction::Referential(_)) {
+      self.dropped_by_lock += 1;
+      if self.lock_warnings {
+        eprintln!("Warning: dropped {} {} for locked client {}.", txn.kind_str(), txn_id, client_id);
+      }
+    }
+    let was_negative = self.has_negative_total(client_id);
+    match txn {
+      Transaction::Basic(inner_txn) => self.store_simple_transaction(index, inner_txn),
+      Transaction::Referential(ref_txn) => {
+        let outcome = if self.compacted.contains(&ref_txn.txn_id()) {
+          AuditOutcome::Ignored
+        }
+        else if !self.is_stored(ref_txn.txn_id()) {
+          self.record_anomaly(ref_txn.client_id(), Anomaly::DanglingReference { txn_id: ref_txn.txn_id() });
+          AuditOutcome::Dangling
+        }
+        else if self.with_loaded(ref_txn.txn_id(), |ledger| ledger.add_referential_transaction(&ref_txn)) {
+          self.track_dispute_opened(index, &ref_txn);
+          AuditOutcome::Applied
+        }
+        else {
+          AuditOutcome::Ignored
+        };
+        self.audit.push(AuditEntry { index, txn: ref_txn, outcome });
+      },
+      Transaction::Adjustment { client_id, txn_id: _, amount } if !self.locked_clients.contains(&client_id) =>
+        self.credit_untracked(client_id, &amount, &Currency::zero_with_scale()),
+      _ => {},
+    }
+    if !was_negative && self.has_negative_total(client_id) {
+      self.record_anomaly(client_id, Anomaly::NegativeTotal { txn_id });
+    }
+  }
+  /// Records when the transaction's dispute was opened, after an applied dispute, resolve or
+  /// chargeback, or forgets it once the transaction is no longer under dispute.
+  fn track_dispute_opened(&mut self, index: u64, ref_txn: &ReferentialTransaction) {
+    // Disputed transactions are never moved to the disk index
+    match self.txns.get(&ref_txn.txn_id()).map(BasicTransaction::dispute_state) {
+      Some(DisputeState::Disputed(1)) if matches!(ref_txn, ReferentialTransaction::Dispute { .. }) => {
+        self.dispute_opened.insert(ref_txn.txn_id(), index);
+      },
+      Some(DisputeState::Disputed(_)) => {},
+      _ => {
+        self.dispute_opened.remove(&ref_txn.txn_id());
+      },
+    }
+  }
+  /// Applies a dispute, resolve or chargeback, returning whether it had any effect.
+  fn add_referential_transaction(&mut self, ref_txn: &ReferentialTransaction) -> bool {
+    let txn = match self.txns.get(&ref_txn.txn_id()) {
+      Some(txn) if txn.client_id() == ref_txn.client_id() => txn,
+      // A client can only act on their own transactions
+      _ => return false,
+    };
+    if let Some(stated) = ref_txn.stated_amount().filter(|_| !self.stated_amount_matches(ref_txn, txn)) {
+      self.record_anomaly(ref_txn.client_id(), Anomaly::AmountMismatch { txn_id: ref_txn.txn_id(), stated: stated.clone() });
+      return false
+    }
+    if !self.disputable_kinds.allows(txn) {
+      return false
+    }
+    if matches!(ref_txn, ReferentialTransaction::Dispute { .. }) && self.outside_dispute_window(ref_txn.txn_id()) {
+      return false
+    }
+    let state = txn.dispute_state();
+    if !state.accepts(ref_txn) {
+      self.record_anomaly(ref_txn.client_id(), Anomaly::InvalidDisputeTransition { txn: ref_txn.clone(), state });
+      return false
+    }
+    match *ref_txn {
+      ReferentialTransaction::Dispute {client_id, txn_id, ..} =>
+      if let Some(txn) = self.txns.get_mut(&txn_id) {
+        let mut overdrawn = false;
+        // Funds are only held by the first of any concurrent disputes
+        if !txn.disputed() {
+          let balance = self.balances.entry(client_id).or_default();
+          if matches!(txn, BasicTransaction::Deposit { settled: true, .. }) && *txn.amount_ref() > balance.available {
+            match self.dispute_shortfall {
+              DisputeShortfall::AllowNegative => overdrawn = true,
+              DisputeShortfall::HoldAvailable => {
+                let held = balance.available.clone().max(Currency::zero_with_scale());
+                self.shortfall_holds.insert(txn_id, held);
+              },
+            }
+          }
+          balance.hold(txn, dispute_hold(&self.shortfall_holds, txn));
+        }
+        txn.open_dispute();
+        if overdrawn {
+          self.record_anomaly(client_id, Anomaly::NegativeAvailable { txn_id });
+        }
+        true
+      }
+      else {
+        false
+      },
+      ReferentialTransaction::Resolve {client_id: _, txn_id, ..} =>
+      if let Some(txn) = self.txns.get_mut(&txn_id) {
+        txn.resolve_dispute();
+        if !txn.disputed() {
+          self.balances.entry(txn.client_id()).or_default().release(txn, dispute_hold(&self.shortfall_holds, txn));
+          self.shortfall_holds.remove(&txn_id);
+        }
+        true
+      }
+      else {
+        false
+      },
+      ReferentialTransaction::Chargeback {client_id, txn_id, ref amount} =>
+      if let Some(txn) = self.txns.get_mut(&txn_id) {
+        let balance = self.balances.entry(client_id).or_default();
+        // A chargeback stating less than its dispute holds only reverses that much, the rest of the
+        // hold is released
+        if let Some(partial) = amount.as_ref().filter(|&amount| amount != txn.amount_ref()) {
+          balance.release(txn, &(dispute_hold(&self.shortfall_holds, txn) - partial));
+          self.shortfall_holds.insert(txn_id, partial.clone());
+        }
+        // Charged back transactions are kept so their id can't be reused or disputed again. Only
+        // this transaction's hold is taken, the client's other open disputes keep theirs.
+        txn.charge_back();
+        balance.charge_back(txn, dispute_hold(&self.shortfall_holds, txn));
+        self.locked_clients.insert(client_id);
+        true
+      }
+      else {
+        false
+      },
+    }
+  }
+  /// Whether a dispute, resolve or chargeback's stated amount, if it has one, is the amount of the
+  /// transaction it references. A chargeback can instead state part of what its dispute holds,
+  /// to reverse only that part.
+  fn stated_amount_matches(&self, ref_txn: &ReferentialTransaction, txn: &BasicTransaction) -> bool {
+    match ref_txn.stated_amount() {
+      Some(stated) if stated != txn.amount_ref() =>
+        matches!(ref_txn, ReferentialTransaction::Chargeback { .. }) && stated <= dispute_hold(&self.shortfall_holds, txn),
+      _ => true,
+    }
+  }
+  /// Reports what applying a transaction would do given the ledger's current state, without
+  /// changing anything.
+  pub fn simulate(&self, txn: &Transaction) -> ApplyOutcome {
+    // Amounts are compared as they would be once rounded on entry
+    let mut limited;
+    let txn = match self.max_scale {
+      Some(scale) => {
+        limited = txn.clone();
+        limited.limit_scale(scale);
+        &limited
+      },
+      None => txn,
+    };
+    if let Some(ClientFilter(filter)) = &self.client_filter {
+      if !filter(txn.client_id()) {
+        return ApplyOutcome::FilteredOut
+      }
+    }
+    match txn {
+      Transaction::Basic(_) | Transaction::Adjustment { .. } if self.locked_clients.contains(&txn.client_id()) =>
+        ApplyOutcome::AccountLocked,
+      Transaction::Basic(basic_txn) if self.is_stored(basic_txn.txn_id()) || self.compacted.contains(&basic_txn.txn_id()) =>
+        ApplyOutcome::DuplicateTxnId,
+      Transaction::Basic(BasicTransaction::Withdrawal { client_id, amount, .. }) => {
+        let zero = Currency::zero_with_scale();
+        let available = self.balances.get(client_id).map_or(&zero, |balance| &balance.available);
+        if amount <= available {
+          ApplyOutcome::Applied
+        }
+        else {
+          ApplyOutcome::InsufficientFunds
+        }
+      },
+      Transaction::Basic(_) | Transaction::Adjustment { .. } => ApplyOutcome::Applied,
+      Transaction::Referential(ref_txn) => match self.stored(ref_txn.txn_id()).as_deref() {
+        None if self.compacted.contains(&ref_txn.txn_id()) => ApplyOutcome::Ignored,
+        None => ApplyOutcome::DanglingReference,
+        Some(txn) if txn.client_id() != ref_txn.client_id() => ApplyOutcome::Ignored,
+        Some(txn) if !self.stated_amount_matches(ref_txn, txn) => ApplyOutcome::AmountMismatch,
+        Some(txn) if !self.disputable_kinds.allows(txn) => ApplyOutcome::Ignored,
+        Some(txn) if matches!(ref_txn, ReferentialTransaction::Dispute { .. }) && self.outside_dispute_window(txn.txn_id()) =>
+          ApplyOutcome::Ignored,
+        Some(txn) if !txn.dispute_state().accepts(ref_txn) => ApplyOutcome::InvalidDisputeTransition,
+        Some(_) => ApplyOutcome::Applied,
+      },
+    }
+  }
+  /// The client's transactions that a new dispute can be raised against, in ascending order:
+  /// those of a kind that can be disputed, within the dispute window, which aren't already
+  /// disputed or charged back. A locked account has none, as it's already had a chargeback.
+  #[must_use]
+  pub fn disputable_transaction_ids(&self, client_id: ClientId) -> Vec<TxnId> {
+    if self.is_locked(client_id) {
+      return Vec::new()
+    }
+    self.client_txns(client_id)
+      .filter(|txn| self.disputable_kinds.allows(txn) && !self.outside_dispute_window(txn.txn_id()))
+      .filter(|txn| txn.dispute_state() == DisputeState::None)
+      .map(|txn| txn.txn_id())
+      .collect()
+  }
+  /// Every transaction currently under dispute, ordered by client then transaction id, along with
+  /// the amount its dispute holds.
+  #[must_use]
+  pub fn open_disputes(&self) -> Vec<(&BasicTransaction, &Currency)> {
+    // Disputed transactions are never moved to the disk index
+    let mut disputes: Vec<(&BasicTransaction, &Currency)> = self.txns.values()
+      .filter(|txn| txn.disputed())
+      .map(|txn| (txn, dispute_hold(&self.shortfall_holds, txn)))
+      .collect();
+    disputes.sort_unstable_by_key(|(txn, _)| (txn.client_id(), txn.txn_id()));
+    disputes
+  }
+  /// Every transaction whose dispute was opened by a dispute arriving before `older_than_index`
+  /// and is still open, with that dispute's arrival index, oldest first. Concurrent disputes
+  /// count from the first, as it's the one that held the funds.
+  #[must_use]
+  pub fn stale_disputes(&self, older_than_index: u64) -> Vec<(&BasicTransaction, u64)> {
+    let mut disputes: Vec<(&BasicTransaction, u64)> = self.txns.values()
+      .filter(|txn| txn.disputed())
+      .filter_map(|txn| Some((txn, *self.dispute_opened.get(&txn.txn_id())?)))
+      .filter(|&(_, opened)| opened < older_than_index)
+      .collect();
+    disputes.sort_unstable_by_key(|&(txn, opened)| (opened, txn.client_id(), txn.txn_id()));
+    disputes
+  }
+  /// Every dispute, resolve and chargeback received, in arrival order, with what came of it.
+  #[must_use]
+  pub fn audit_trail(&self) -> &[AuditEntry] {
+    &self.audit
+  }
+  pub fn settle(&mut self, txn_id: TxnId) -> bool {
+    self.with_loaded(txn_id, |ledger| match ledger.txns.get_mut(&txn_id) {
+      Some(txn) if !txn.settled() && !txn.charged_back() => {
+        // Disputed funds stay held, they become available once the dispute is resolved
+        if !txn.disputed() {
+          let balance = ledger.balances.entry(txn.client_id()).or_default();
+          balance.pending -= txn.amount_ref();
+          balance.available += txn.amount_ref();
+        }
+        txn.set_settled(true);
+        true
+      },
+      _ => false,
+    })
+  }
+  /// Settles every pending deposit, returning how many were settled.
+  pub fn settle_all(&mut self) -> usize {
+    // Pending deposits are never moved to the disk index
+    let pending: Vec<TxnId> = self.txns.values()
+      .filter(|txn| !txn.settled())
+      .map(|txn| txn.txn_id())
+      .collect();
+    pending.into_iter().filter(|&txn_id| self.settle(txn_id)).count()
+  }
+  /// Drops every stored transaction that can no longer affect the ledger, to free the memory it
+  /// holds: settled transactions that have never been disputed, and either are of a kind that
+  /// can't be disputed or have fallen outside the dispute window. Their effect on balances is
+  /// kept as an opening balance, and their ids stay taken, so summaries are unchanged. Disputes,
+  /// resolves and chargebacks referencing them are ignored without recording an anomaly, and
+  /// they're left out of statements and anything else listing transactions.
+  /// Returns the number of transactions dropped.
+  pub fn compact(&mut self) -> usize {
+    let finished: Vec<(TxnId, ClientId, Currency)> = stored_txns(&self.txns, &self.disk)
+      .filter(|txn| txn.settled() && txn.dispute_state() == DisputeState::None)
+      .filter(|txn| !self.disputable_kinds.allows(txn) || self.outside_dispute_window(txn.txn_id()))
+      .map(|txn| {
+        let amount = match &*txn {
+          BasicTransaction::Deposit { amount, .. } => amount.clone(),
+          BasicTransaction::Withdrawal { amount, .. } => -amount,
+        };
+        (txn.txn_id(), txn.client_id(), amount)
+      })
+      .collect();
+    for (txn_id, client_id, amount) in &finished {
+      if self.txns.remove(txn_id).is_none() {
+        self.disk.as_ref().and_then(|disk| disk.remove(*txn_id));
+      }
+      self.untracked.entry(*client_id).or_default().available += amount;
+      if let Some(txn_ids) = self.clients.get_mut(client_id) {
+        txn_ids.remove(txn_id);
+      }
+      self.arrivals.remove(txn_id);
+      self.compacted.insert(*txn_id);
+    }
+    finished.len()
+  }
+  /// The number of deposits and withdrawals stored, whether in memory or in the disk index.
+  #[must_use]
+  pub fn transaction_count(&self) -> usize {
+    self.txns.len() + self.disk.as_ref().map_or(0, DiskIndex::len)
+  }
+  /// Copies of every deposit and withdrawal held for the client, in the order they arrived. Empty
+  /// for a client the ledger has never seen.
+  #[must_use]
+  pub fn transactions_for(&self, client_id: ClientId) -> Vec<BasicTransaction> {
+    let mut txns: Vec<Cow<'_, BasicTransaction>> = self.client_txns(client_id).collect();
+    txns.sort_by_key(|txn| self.arrivals.get(&txn.txn_id()));
+    txns.into_iter().map(Cow::into_owned).collect()
+  }
+  /// A statement of the client's account: every deposit and withdrawal held, and every dispute,
+  /// resolve and chargeback applied, in arrival order with the available balance after each.
+  /// Opening balances and adjustments aren't kept as transactions, so aren't included, and
+  /// balances start from zero. A dispute that was later partly charged back is shown holding only
+  /// the part charged back.
+  #[must_use]
+  pub fn statement(&self, client_id: ClientId) -> Vec<StatementLine> {
+    let mut entries: Vec<(u64, Option<&ReferentialTransaction>, Cow<'_, BasicTransaction>)> = self.client_txns(client_id)
+      .map(|txn| (self.arrivals.get(&txn.txn_id()).copied().unwrap_or_default(), None, txn))
+      .collect();
+    for entry in &self.audit {
+      if entry.outcome == AuditOutcome::Applied && entry.txn.client_id() == client_id {
+        if let Some(txn) = self.stored(entry.txn.txn_id()) {
+          entries.push((entry.index, Some(&entry.txn), txn));
+        }
+      }
+    }
+    entries.sort_by_key(|(index, _, _)| *index);
+    let mut balance = Balance::default();
+    // Dispute states as of each point in the replay, rather than the current ones
+    let mut replayed: HashMap<TxnId, BasicTransaction> = HashMap::new();
+    entries.into_iter()
+      .map(|(_, ref_txn, stored)| {
+        let held = dispute_hold(&self.shortfall_holds, &stored);
+        let txn = replayed.entry(stored.txn_id()).or_insert_with(|| {
+          let mut txn = stored.clone().into_owned();
+          txn.clear_disputes();
+          txn
+        });
+        let (kind, amount) = match ref_txn {
+          None => {
+            match &*txn {
+              BasicTransaction::Deposit { amount, settled: false, .. } => balance.pending += amount,
+              BasicTransaction::Deposit { amount, .. } => balance.available += amount,
+              BasicTransaction::Withdrawal { amount, .. } => balance.available -= amount,
+            }
+            (txn.kind_str(), txn.amount())
+          },
+          Some(ref_txn) => {
+            match ref_txn {
+              ReferentialTransaction::Dispute { .. } => {
+                if !txn.disputed() {
+                  balance.hold(txn, held);
+                }
+                txn.open_dispute();
+              },
+              ReferentialTransaction::Resolve { .. } => {
+                txn.resolve_dispute();
+                if !txn.disputed() {
+                  balance.release(txn, held);
+                }
+              },
+              ReferentialTransaction::Chargeback { .. } => {
+                txn.charge_back();
+                balance.charge_back(txn, held);
+              },
+            }
+            (ref_txn.kind_str(), held.clone())
+          },
+        };
+        StatementLine { txn_id: stored.txn_id(), kind, amount, balance_after: balance.available.clone() }
+      })
+      .collect()
+  }
+  fn client_txns(&self, client_id: ClientId) -> impl Iterator<Item = Cow<'_, BasicTransaction>> + '_ {
+    self.clients.get(&client_id).into_iter().flatten().filter_map(move |&txn_id| self.stored(txn_id))
+  }
+  /// The client's transactions held in memory, which include every one under dispute.
+  fn client_txns_in_memory(&self, client_id: ClientId) -> impl Iterator<Item = &BasicTransaction> + '_ {
+    self.clients.get(&client_id).into_iter().flatten().filter_map(move |txn_id| self.txns.get(txn_id))
+  }
+  fn has_negative_total(&self, client_id: ClientId) -> bool {
+    self.balances.get(&client_id).is_some_and(|balance| balance.total() < Currency::zero_with_scale())
+  }
+  /// Clients whose total funds are negative, in ascending order. This should never happen with
+  /// well formed input, but can when e.g. a deposit is charged back after being withdrawn.
+  #[must_use]
+  pub fn negative_totals(&self) -> Vec<ClientId> {
+    let mut client_ids: Vec<ClientId> = self.balances.keys()
+      .copied()
+      .filter(|&client_id| self.has_negative_total(client_id))
+      .collect();
+    client_ids.sort_unstable();
+    client_ids
+  }
+  fn record_anomaly(&mut self, client_id: ClientId, anomaly: Anomaly) {
+    self.anomalies.entry(client_id).or_default().push(anomaly);
+  }
+  /// Adds every valid transaction in a csv file, or a JSON file if the `serde_json` feature is
+  /// enabled, to the ledger, in the order they appear. The file is read in the ledger's input
+  /// format, which by default is worked out from its contents.
+  /// Returns the number of valid transactions read.
+  pub fn replay_from_csv(&mut self, file: PathBuf) -> Result<usize, Box<dyn std::error::Error>> {
+    let reader = TransactionReader::from_path(file, self.input_format)?;
+    Ok(self.replay_reader(reader, |_, _| {}))
+  }
+  /// Like `replay_from_csv`, but calls `f` after each transaction is added with its client's
+  /// updated summary, e.g. to keep a live view of the accounts. Balances are kept as transactions
+  /// are added, so this costs little more than the replay itself.
+  pub fn replay_from_csv_with_callback(
+    &mut self,
+    file: PathBuf,
+    mut f: impl FnMut(ClientId, &AccountSummary)
+  ) -> Result<usize, Box<dyn std::error::Error>> {
+    let reader = TransactionReader::from_path(file, self.input_format)?;
+    Ok(self.replay_reader(reader, |ledger, client_id| {
+      if let Some(summary) = ledger.calculate_client_account_summary(client_id) {
+        f(client_id, &summary);
+      }
+    }))
+  }
+  /// Like `replay_from_csv`, but streams a csv file from an `http` or `https` URL. Requires the
+  /// `ureq` feature.
+  pub fn replay_from_url(&mut self, url: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    #[cfg(feature = "ureq")]
+    return Ok(self.replay_reader(TransactionReader::from_url(url)?, |_, _| {}));
+    #[cfg(not(feature = "ureq"))]
+    return Err(From::from(format!("Reading {} requires the ureq feature.", url)));
+  }
+  /// Adds every valid transaction from the reader, calling `after` with each one's client once
+  /// it's added.
+  fn replay_reader(&mut self, reader: TransactionReader, mut after: impl FnMut(&Self, ClientId)) -> usize {
+    let mut reader = reader.with_scientific_amounts(self.scientific_amounts).with_type_synonyms(self.type_synonyms.clone());
+    let mut count = 0;
+    while !reader.is_done() {
+      if let Ok(transaction) = reader.transaction() {
+        let client_id = transaction.client_id();
+        self.add_transaction(transaction);
+        after(self, client_id);
+        count += 1;
+      }
+    }
+    count
+  }
+  /// Parses and applies every record, in the standard `type,client,tx,amount` layout, returning
+  /// counts of how they were handled. Header rows are skipped without being counted.
+  pub fn process_stream<I: IntoIterator<Item = StringRecord>>(&mut self, records: I) -> ProcessingStats {
+    let mut stats = ProcessingStats::default();
+    for record in records {
+      // Concatenated files can repeat the header row partway through, which isn't a transaction
+      if record.iter().map(str::trim).eq(["type", "client", "tx", "amount"]) {
+        continue
+      }
+      stats.records += 1;
+      // Rejected as `TransactionReader` would, unless the ledger accepts scientific notation
+      if !self.scientific_amounts && record.get(ColumnMap::default().amount).is_some_and(is_scientific) {
+        stats.parse_errors += 1;
+        continue
+      }
+      match Transaction::from_record(&record, &self.type_synonyms) {
+        Ok(transaction) if transaction.validate().is_err() => stats.invalid += 1,
+        Ok(transaction) => {
+          match self.simulate(&transaction) {
+            ApplyOutcome::Applied => stats.applied += 1,
+            ApplyOutcome::AccountLocked => stats.locked += 1,
+            _ => stats.rejected += 1,
+          }
+          self.add_transaction(transaction);
+        },
+        Err(_) => stats.parse_errors += 1,
+      }
+    }
+    stats
+  }
+  /// Like `replay_from_csv`, but stops at the first record that is malformed or would not be
+  /// applied, e.g. a dispute of an unknown transaction, returning its line number. Also stops
+  /// after the first transaction to leave a client's total negative.
+  pub fn replay_from_csv_strict(&mut self, file: PathBuf) -> Result<usize, StrictError> {
+    let mut reader = TransactionReader::from_path(file, self.input_format)
+      .map_err(|e| StrictError { line: 0, kind: StrictErrorKind::Malformed(e.to_string()) })?
+      .with_scientific_amounts(self.scientific_amounts)
+      .with_type_synonyms(self.type_synonyms.clone())
+      .with_strict(true);
+    let mut count = 0;
+    while !reader.is_done() {
+      match reader.transaction() {
+        Ok(transaction) => {
+          self.add_strict(transaction).map_err(|kind| StrictError { line: reader.line().unwrap_or_default(), kind })?;
+          count += 1;
+        },
+        // Reaching the end of the file is the only error that isn't a problem with the data
+        Err(_) if reader.is_done() && !reader.failed() => {},
+        Err(e) => return Err(StrictError { line: reader.line().unwrap_or_default(), kind: StrictErrorKind::Malformed(e.to_string()) }),
+      }
+    }
+    Ok(count)
+  }
+  /// Adds a transaction unless it would not be applied, failing after adding it if it leaves the
+  /// client's total negative.
+  fn add_strict(&mut self, transaction: Transaction) -> Result<(), StrictErrorKind> {
+    match self.simulate(&transaction) {
+      ApplyOutcome::Applied => {},
+      ApplyOutcome::InsufficientFunds =>
+        return Err(StrictErrorKind::Overdraft { client: transaction.client_id(), tx: transaction.txn_id() }),
+      outcome => return Err(StrictErrorKind::Rejected(outcome)),
+    }
+    let client_id = transaction.client_id();
+    let was_negative = self.has_negative_total(client_id);
+    self.add_transaction(transaction);
+    if !was_negative && self.has_negative_total(client_id) {
+      return Err(StrictErrorKind::NegativeTotal(client_id))
+    }
+    Ok(())
+  }
+  /// Applies a batch of transactions all or nothing: if any is invalid, would not be applied, or
+  /// leaves its client's total negative, the ledger is restored to how it was before the batch
+  /// and the offending transaction's position in the batch is returned along with the problem.
+  /// Only the state each transaction can change is saved to restore from, so the cost grows with
+  /// the batch rather than the ledger.
+  pub fn apply_batch_transactional(&mut self, txns: impl IntoIterator<Item = Transaction>) -> Result<(), (usize, StrictErrorKind)> {
+    let mut undo = UndoLog::new(self);
+    // Transactions waiting on an earlier arrival index are applied by whichever fills the gap
+    for txn in self.early_arrivals.values() {
+      undo.save(self, txn);
+    }
+    for (i, txn) in txns.into_iter().enumerate() {
+      undo.save(self, &txn);
+      let added = match txn.validate() {
+        Ok(()) => self.add_strict(txn),
+        Err(e) => Err(StrictErrorKind::Invalid(e)),
+      };
+      if let Err(kind) = added {
+        undo.restore(self);
+        return Err((i, kind))
+      }
+    }
+    Ok(())
+  }
+  /// Replays every `.csv` file in a directory into the ledger, ordered by filename, along with
+  /// any `.json` files if the `serde_json` feature is enabled.
+  /// Returns the number of valid transactions read.
+  pub fn replay_from_dir(&mut self, dir: PathBuf) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+      let path = entry?.path();
+      if path.is_file() && path.extension().is_some_and(|ext| ext == "csv" || (cfg!(feature = "serde_json") && ext == "json")) {
+        files.push(path);
+      }
+    }
+    files.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+    let mut count = 0;
+    for file in files {
+      count += self.replay_from_csv(file)?;
+    }
+    Ok(count)
+  }
+  #[must_use]
+  pub fn calculate_all_account_summaries(&self) -> Vec<AccountSummary> {
+      self.summaries().collect()
+  }
+  /// Lazily calculates the summary of each client account, one client at a time.
+  #[must_use]
+  pub fn summaries(&self) -> AccountSummaries<'_> {
+    AccountSummaries {
+      ledger: self,
+      client_ids: self.clients.keys(),
+    }
+  }
+  /// Folds over the summary of each client account in turn, e.g. to count locked accounts,
+  /// without collecting every summary first. Clients are visited in no particular order.
+  pub fn fold_summaries<B>(&self, init: B, f: impl FnMut(B, AccountSummary) -> B) -> B {
+    self.summaries().fold(init, f)
+  }
+  #[must_use]
+  pub fn calculate_client_account_summary(&self, client_id: ClientId) -> Option<AccountSummary> {
+    if !self.clients.contains_key(&client_id) {
+      return None
+    }
+    Some(self.summarize(client_id, self.balances.get(&client_id), &Currency::zero_with_scale(), &Currency::zero_with_scale()))
+  }
+  /// Builds a client's summary from `balance`, with `extra_credit` more returned by open disputes
+  /// going the client's way and `extra_withdrawal_holds` more held by disputed withdrawals than
+  /// the ledger's own disputes account for.
+  fn summarize(&self, client_id: ClientId, balance: Option<&Balance>, extra_credit: &Currency, extra_withdrawal_holds: &Currency) -> AccountSummary {
+    let mut acc = AccountSummary::new();
+    acc.client = client_id;
+    if let Some(balance) = balance {
+      acc.available = balance.available.clone();
+      acc.held = &balance.held + &balance.admin_held;
+      acc.pending = balance.pending.clone();
+      acc.effective_available = &balance.available + open_dispute_credit(self.client_txns_in_memory(client_id), &self.shortfall_holds) + extra_credit;
+    }
+    acc.total = &acc.available + &acc.held + &acc.pending;
+    if self.total_model == TotalModel::DepositsMinusWithdrawals {
+      acc.total -= withdrawal_dispute_holds(self.client_txns_in_memory(client_id), &self.shortfall_holds) + extra_withdrawal_holds;
+    }
+    acc.locked = self.is_locked(client_id);
+    if let Some(&scale) = self.client_scales.get(&client_id) {
+      // Rounded separately, so the total is the rounded sum rather than the sum of rounded parts
+      for balance in [&mut acc.available, &mut acc.held, &mut acc.pending, &mut acc.total, &mut acc.effective_available] {
+        *balance = balance.round(scale).with_scale(scale);
+      }
+      acc.scale = Some(scale);
+    }
+    acc
+  }
+  /// Like `calculate_client_account_summary`, but distinguishes a client the ledger has never seen
+  /// from one whose account simply has a zero balance.
+  pub fn try_client_account_summary(&self, client_id: ClientId) -> Result<AccountSummary, UnknownClient> {
+    self.calculate_client_account_summary(client_id).ok_or(UnknownClient(client_id))
+  }
+  /// Calculates a client's summary as if each of `txn_ids` were disputed, without changing the
+  /// ledger, to see what disputes would do before they're raised. The disputes follow the same
+  /// rules as real ones, so ids that aren't the client's, or couldn't be disputed, have no effect.
+  /// Only the client's balance is copied, so this costs about as much as the disputes themselves.
+  #[must_use]
+  pub fn summary_with_hypothetical_disputes(&self, client_id: ClientId, txn_ids: &[TxnId]) -> Option<AccountSummary> {
+    if !self.clients.contains_key(&client_id) {
+      return None
+    }
+    let mut balance = self.balances.get(&client_id).cloned().unwrap_or_default();
+    let (mut credit, mut withdrawal_holds) = (Currency::zero_with_scale(), Currency::zero_with_scale());
+    let mut seen = HashSet::new();
+    for &txn_id in txn_ids {
+      if !seen.insert(txn_id) || self.compacted.contains(&txn_id) || self.outside_dispute_window(txn_id) {
+        continue
+      }
+      // Transactions already under dispute hold their funds, so another dispute changes nothing
+      let txn = match self.stored(txn_id) {
+        Some(txn) if txn.client_id() == client_id && txn.dispute_state() == DisputeState::None && self.disputable_kinds.allows(&txn) => txn,
+        _ => continue,
+      };
+      let mut held = txn.amount();
+      if matches!(*txn, BasicTransaction::Deposit { settled: true, .. }) && held > balance.available
+        && self.dispute_shortfall == DisputeShortfall::HoldAvailable {
+        held = balance.available.clone().max(Currency::zero_with_scale());
+      }
+      balance.hold(&txn, &held);
+      match *txn {
+        BasicTransaction::Deposit { settled: false, .. } => {},
+        BasicTransaction::Deposit { .. } => credit += &held,
+        BasicTransaction::Withdrawal { .. } => {
+          credit += &held;
+          withdrawal_holds += held;
+        },
+      }
+    }
+    Some(self.summarize(client_id, Some(&balance), &credit, &withdrawal_holds))
+  }
+  /// Calculates a client's summary from only their deposits and withdrawals timestamped within
+  /// `from..to`. Adjustments and untimestamped transactions are not included, and `locked` is
+  /// the account's current state. Disputes aren't timestamped, so each transaction's dispute
+  /// state is as it is now: a dispute raised after `to` still holds its transaction's funds, and
+  /// can leave the range's available balance negative when the range also has withdrawals.
+  pub fn summary_between(&self, client_id: ClientId, from: Timestamp, to: Timestamp) -> Option<AccountSummary> {
+    let txn_ids = self.clients.get(&client_id)?;
+    let mut balance = Balance::default();
+    let in_range: Vec<Cow<'_, BasicTransaction>> = txn_ids.iter()
+      .filter_map(|&txn_id| self.stored(txn_id))
+      .filter(|txn| txn.timestamp().is_some_and(|timestamp| (from..to).contains(&timestamp)))
+      .collect();
+    for txn in &in_range {
+      balance.replay(txn, dispute_hold(&self.shortfall_holds, txn));
+    }
+    let mut acc = AccountSummary::new();
+    acc.client = client_id;
+    acc.total = &balance.available + &balance.held + &balance.pending;
+    if self.total_model == TotalModel::DepositsMinusWithdrawals {
+      acc.total -= withdrawal_dispute_holds(in_range.iter().map(AsRef::as_ref), &self.shortfall_holds);
+    }
+    acc.effective_available = &balance.available + open_dispute_credit(in_range.iter().map(AsRef::as_ref), &self.shortfall_holds);
+    acc.available = balance.available;
+    acc.held = balance.held;
+    acc.pending = balance.pending;
+    acc.locked = self.is_locked(client_id);
+    Some(acc)
+  }
+  /// Brings the ledger into a canonical minimal state: transaction ids are listed under exactly
+  /// the client they belong to, balances are rebuilt from the stored transactions, clients with
+  /// no transactions, funds or lock are dropped, and clients with a charged back transaction are
+  /// locked. Afterwards `integrity_check` passes.
+  pub fn canonicalize(&mut self) {
+    for (client_id, txn_ids) in self.clients.iter_mut() {
+      let (txns, disk) = (&self.txns, &self.disk);
+      txn_ids.retain(|&txn_id| stored_in(txns, disk, txn_id).is_some_and(|txn| txn.client_id() == *client_id));
+    }
+    for txn in stored_txns(&self.txns, &self.disk) {
+      self.clients.entry(txn.client_id()).or_default().insert(txn.txn_id());
+      if txn.charged_back() {
+        self.locked_clients.insert(txn.client_id());
+      }
+    }
+    let mut balances = self.untracked.clone();
+    for txn in stored_txns(&self.txns, &self.disk) {
+      balances.entry(txn.client_id()).or_default().replay(&txn, dispute_hold(&self.shortfall_holds, &txn));
+    }
+    self.balances = balances;
+    let (balances, locked_clients) = (&self.balances, &self.locked_clients);
+    self.clients.retain(|client_id, txn_ids| !txn_ids.is_empty()
+      || locked_clients.contains(client_id)
+      || balances.get(client_id).is_some_and(|balance| *balance != Balance::default()));
+    let clients = &self.clients;
+    self.balances.retain(|client_id, _| clients.contains_key(client_id));
+    self.untracked.retain(|client_id, _| clients.contains_key(client_id));
+  }
+  /// Totals a client's gross deposits and gross withdrawals, returned as `(deposited, withdrawn)`.
+  /// Every retained deposit and withdrawal counts, including disputed and pending ones as their
+  /// funds did move, but charged back ones are left out as they have been reversed. Rejected
+  /// withdrawals were never retained, and adjustments are not counted as either.
+  pub fn gross_flows(&self, client_id: ClientId) -> (Currency, Currency) {
+    let mut deposited = Currency::zero_with_scale();
+    let mut withdrawn = Currency::zero_with_scale();
+    let txns = self.client_txns(client_id);
+    for txn in txns.filter(|txn| !txn.charged_back()) {
+      match &*txn {
+        BasicTransaction::Deposit { amount, .. } => deposited += amount,
+        BasicTransaction::Withdrawal { amount, .. } => withdrawn += amount,
+      }
+    }
+    (deposited, withdrawn)
+  }
+  /// Verifies the ledger's incrementally updated state is consistent: every client's balance
+  /// matches a fresh replay of their stored transactions, and every transaction is held under
+  /// the client it belongs to.
+  pub fn integrity_check(&self) -> Result<(), IntegrityError> {
+    for (&client_id, txn_ids) in &self.clients {
+      let mut expected = self.untracked.get(&client_id).cloned().unwrap_or_default();
+      for &txn_id in txn_ids {
+        match self.stored(txn_id) {
+          Some(txn) if txn.client_id() == client_id => expected.replay(&txn, dispute_hold(&self.shortfall_holds, &txn)),
+          Some(_) => return Err(IntegrityError::WrongClient { client_id, txn_id }),
+          None => return Err(IntegrityError::MissingTxn { client_id, txn_id }),
+        }
+      }
+      if self.balances.get(&client_id).cloned().unwrap_or_default() != expected {
+        return Err(IntegrityError::BalanceMismatch { client_id })
+      }
+    }
+    for txn in stored_txns(&self.txns, &self.disk) {
+      if !self.clients.get(&txn.client_id()).is_some_and(|txn_ids| txn_ids.contains(&txn.txn_id())) {
+        return Err(IntegrityError::UnlistedTxn { txn_id: txn.txn_id() })
+      }
+    }
+    if let Some(&client_id) = self.balances.keys().find(|client_id| !self.clients.contains_key(client_id)) {
+      return Err(IntegrityError::UnknownClientBalance { client_id })
+    }
+    Ok(())
+  }
+  /// Calculates the summary of every client account along with any anomalies affecting it.
+  #[must_use]
+  pub fn detailed_summaries(&self) -> Vec<DetailedAccountSummary> {
+    self.summaries()
+      .map(|summary| DetailedAccountSummary {
+        anomalies: self.anomalies.get(&summary.client()).cloned().unwrap_or_default(),
+        summary,
+      })
+      .collect()
+  }
+  /// Writes the ledger's full state in the compact bincode format. A ledger with a disk index
+  /// can't be saved, as the transactions in the index aren't part of its serialized state.
+  #[cfg(feature = "bincode")]
+  pub fn save_bincode<W: Write>(&self, w: W) -> Result<(), Box<dyn std::error::Error>> {
+    if self.disk.is_some() {
+      return Err(From::from("A ledger with a disk index can't be saved."))
+    }
+    bincode::serialize_into(w, self)?;
+    Ok(())
+  }
+  /// Reads a ledger previously written by `save_bincode`.
+  #[cfg(feature = "bincode")]
+  pub fn load_bincode<R: Read>(r: R) -> Result<Self, Box<dyn std::error::Error>> {
+    Ok(bincode::deserialize_from(r)?)
+  }
+  /// Compares this ledger against another, listing how each client's account differs in `other`.
+  /// Clients present in only one of the ledgers are compared against an empty account.
+  pub fn diff(&self, other: &Ledger) -> LedgerDiff {
+    let clients: BTreeSet<ClientId> = self.clients.keys().chain(other.clients.keys()).copied().collect();
+    let summary_of = |ledger: &Ledger, client_id| ledger.calculate_client_account_summary(client_id)
+      .unwrap_or_else(|| AccountSummary { client: client_id, ..AccountSummary::new() });
+    let changes = clients.into_iter()
+      .map(|client_id| (summary_of(self, client_id), summary_of(other, client_id)))
+      .filter(|(before, after)| before != after)
+      .map(|(before, after)| ClientDiff {
+        client: before.client,
+        available: &after.available - &before.available,
+        held: &after.held - &before.held,
+        pending: &after.pending - &before.pending,
+        total: &after.total - &before.total,
+        locked: if before.locked != after.locked { Some(after.locked) } else { None },
+      })
+      .collect();
+    LedgerDiff { changes }
+  }
+  /// Checks the ledger's account summaries against an expected set, e.g. a known good output, in
+  /// any order. Only the standard output columns are compared: available, held, total and locked.
+  /// Balances are compared as they're output, at the client's scale or `CURRENCY_SCALE` places,
+  /// so the ledger reconciles against its own output.
+  #[must_use]
+  pub fn reconcile(&self, expected: &[AccountSummary]) -> ReconcileReport {
+    let mut expected: Vec<&AccountSummary> = expected.iter().collect();
+    expected.sort_by_key(|summary| summary.client);
+    let mut report = ReconcileReport::default();
+    for expected in &expected {
+      match self.calculate_client_account_summary(expected.client) {
+        None => report.missing.push(expected.client),
+        Some(actual) => {
+          let scale = actual.scale.unwrap_or(CURRENCY_SCALE);
+          let output = |summary: &AccountSummary|
+            (summary.available.round(scale), summary.held.round(scale), summary.total.round(scale), summary.locked);
+          if output(&actual) != output(expected) {
+            report.mismatches.push(((*expected).clone(), actual));
+          }
+        },
+      }
+    }
+    report.extra = self.clients.keys()
+      .filter(|client_id| expected.binary_search_by_key(client_id, |summary| &summary.client).is_err())
+      .copied()
+      .collect();
+    report.extra.sort_unstable();
+    report
+  }
+}
+impl Default for Ledger {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+/// Renders every account as a table aligned for reading, ordered by client. Use `write_as_csv`
+/// for output meant to be parsed.
+impl fmt::Display for Ledger {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut summaries = self.calculate_all_account_summaries();
+    SortKey::Client.sort(&mut summaries);
+    let header = ["client", "available", "held", "total", "locked"].map(String::from);
+    let rows: Vec<[String; 5]> = summaries.iter()
+      .map(|summary| [
+        summary.client.to_string(),
+        summary.available.to_string(),
+        summary.held.to_string(),
+        summary.total.to_string(),
+        summary.locked.to_string(),
+      ])
+      .collect();
+    let mut widths = header.clone().map(|title| title.len());
+    for row in &rows {
+      for (width, cell) in widths.iter_mut().zip(row) {
+        *width = (*width).max(cell.len());
+      }
+    }
+    for row in std::iter::once(header).chain(rows) {
+      let cells: Vec<String> = row.iter().zip(widths).map(|(cell, width)| format!("{:>width$}", cell, width = width)).collect();
+      writeln!(f, "{}", cells.join("  "))?;
+    }
+    Ok(())
+  }
+}
+impl<'a> IntoIterator for &'a Ledger {
+  type Item = AccountSummary;
+  type IntoIter = AccountSummaries<'a>;
+  fn into_iter(self) -> Self::IntoIter {
+    self.summaries()
+  }
+}
+
+/// Funds that would return to available if every open dispute among `txns` went the client's
+/// way: a disputed deposit resolved, or a disputed withdrawal reversed. An unsettled deposit
+/// returns to pending rather than available.
+fn open_dispute_credit<'a>(txns: impl Iterator<Item = &'a BasicTransaction>, shortfall_holds: &HashMap<TxnId, Currency>) -> Currency {
+  txns
+    .filter(|txn| txn.disputed() && !matches!(txn, BasicTransaction::Deposit { settled: false, .. }))
+    .fold(Currency::zero_with_scale(), |credit, txn| credit + dispute_hold(shortfall_holds, txn))
+}
+
+/// Funds held by open disputes of withdrawals among `txns`, which have already left the account.
+fn withdrawal_dispute_holds<'a>(txns: impl Iterator<Item = &'a BasicTransaction>, shortfall_holds: &HashMap<TxnId, Currency>) -> Currency {
+  txns
+    .filter(|txn| txn.disputed() && matches!(txn, BasicTransaction::Withdrawal { .. }))
+    .fold(Currency::zero_with_scale(), |held, txn| held + dispute_hold(shortfall_holds, txn))
+}
+
+/// Looks up a stored transaction in the ledger's parts, for when the ledger is partly borrowed.
+fn stored_in<'a>(txns: &'a HashMap<TxnId, BasicTransaction, LedgerHasher>, disk: &Option<DiskIndex>, txn_id: TxnId) -> Option<Cow<'a, BasicTransaction>> {
+  match txns.get(&txn_id) {
+    Some(txn) => Some(Cow::Borrowed(txn)),
+    None => disk.as_ref().and_then(|disk| disk.get(txn_id)).map(Cow::Owned),
+  }
+}
+/// Every stored transaction, those held in memory followed by those in the disk index.
+fn stored_txns<'a>(txns: &'a HashMap<TxnId, BasicTransaction, LedgerHasher>, disk: &'a Option<DiskIndex>) -> impl Iterator<Item = Cow<'a, BasicTransaction>> + 'a {
+  txns.values().map(Cow::Borrowed).chain(disk.iter().flat_map(DiskIndex::txns).map(Cow::Owned))
+}
+/// The amount a dispute of `txn` holds, which is its full amount unless it fell short.
+fn dispute_hold<'a>(shortfall_holds: &'a HashMap<TxnId, Currency>, txn: &'a BasicTransaction) -> &'a Currency {
+  shortfall_holds.get(&txn.txn_id()).unwrap_or_else(|| txn.amount_ref())
+}
+
+/// Running balance of a client account, updated as each transaction arrives.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct Balance {
+  available: Currency,
+  /// Funds held by disputes.
+  held: Currency,
+  pending: Currency,
+  /// Funds held administratively, unrelated to any dispute.
+  admin_held: Currency,
+}
+impl Balance {
+  fn total(&self) -> Currency {
+    &self.available + &self.held + &self.pending + &self.admin_held
+  }
+  /// Moves `amount` of a newly disputed transaction's funds into held.
+  fn hold(&mut self, txn: &BasicTransaction, amount: &Currency) {
+    match txn {
+      BasicTransaction::Deposit { settled: false, .. } => {
+        self.pending -= amount;
+        self.held += amount;
+      },
+      BasicTransaction::Deposit { .. } => {
+        self.available -= amount;
+        self.held += amount;
+      },
+      // Funds have already left available (transaction pending), but are placed in held until the
+      // dispute is resolved
+      BasicTransaction::Withdrawal { .. } => self.held += amount,
+    }
+  }
+  /// Releases the `amount` held for a transaction once its disputes have been resolved.
+  fn release(&mut self, txn: &BasicTransaction, amount: &Currency) {
+    match txn {
+      BasicTransaction::Deposit { settled: false, .. } => {
+        self.held -= amount;
+        self.pending += amount;
+      },
+      BasicTransaction::Deposit { .. } => {
+        self.held -= amount;
+        self.available += amount;
+      },
+      BasicTransaction::Withdrawal { .. } => self.held -= amount,
+    }
+  }
+  /// Adds the current effect of a stored transaction, for rebuilding a balance from scratch.
+  /// `held` is the amount its dispute holds, or would hold.
+  fn replay(&mut self, txn: &BasicTransaction, held: &Currency) {
+    match txn {
+      // Only what was held of a charged back deposit is gone
+      BasicTransaction::Deposit { amount, .. } if txn.charged_back() => self.available += amount - held,
+      // What was held of a charged back withdrawal has been returned
+      BasicTransaction::Withdrawal { amount, .. } if txn.charged_back() => self.available -= amount - held,
+      BasicTransaction::Deposit { amount, settled: true, .. } if txn.disputed() => {
+        self.available += amount - held;
+        self.held += held;
+      },
+      BasicTransaction::Deposit { amount, .. } if txn.disputed() => self.held += amount,
+      BasicTransaction::Deposit { amount, settled: false, .. } => self.pending += amount,
+      BasicTransaction::Deposit { amount, .. } => self.available += amount,
+      BasicTransaction::Withdrawal { amount, .. } => {
+        self.available -= amount;
+        if txn.disputed() {
+          self.held += amount;
+        }
+      },
+    }
+  }
+  /// Reverses a disputed transaction that has been charged back, `amount` being what its dispute held.
+  fn charge_back(&mut self, txn: &BasicTransaction, amount: &Currency) {
+    match txn {
+      BasicTransaction::Deposit { .. } => self.held -= amount,
+      BasicTransaction::Withdrawal { .. } => {
+        self.held -= amount;
+        self.available += amount;
+      },
+    }
+  }
+}
+impl Default for Balance {
+  fn default() -> Self {
+    Balance {
+      available: Currency::zero_with_scale(),
+      held: Currency::zero_with_scale(),
+      pending: Currency::zero_with_scale(),
+      admin_held: Currency::zero_with_scale(),
+    }
+  }
+}
+
+/// The parts of a ledger a batch of transactions can change, as they were before the batch, so
+/// `apply_batch_transactional` can roll back without copying the whole ledger.
+struct UndoLog {
+  clients: HashMap<ClientId, ClientUndo>,
+  txns: HashMap<TxnId, TxnUndo>,
+  audit_len: usize,
+  skipped_by_filter: usize,
+  dropped_by_lock: usize,
+  next_arrival: u64,
+  early_arrivals: BTreeMap<u64, Transaction>,
+}
+/// A client's state before the batch.
+struct ClientUndo {
+  known: bool,
+  locked: bool,
+  flagged: bool,
+  velocity: Option<(usize, Currency)>,
+  anomaly_count: Option<usize>,
+  balance: Option<Balance>,
+  untracked: Option<Balance>,
+}
+/// A transaction id's state before the batch, `None` where nothing was stored under it.
+struct TxnUndo {
+  in_memory: Option<BasicTransaction>,
+  on_disk: Option<BasicTransaction>,
+  arrival: Option<u64>,
+  dispute_opened: Option<u64>,
+  shortfall_hold: Option<Currency>,
+}
+impl UndoLog {
+  fn new(ledger: &Ledger) -> Self {
+    UndoLog {
+      clients: HashMap::new(),
+      txns: HashMap::new(),
+      audit_len: ledger.audit.len(),
+      skipped_by_filter: ledger.skipped_by_filter,
+      dropped_by_lock: ledger.dropped_by_lock,
+      next_arrival: ledger.next_arrival,
+      early_arrivals: ledger.early_arrivals.clone(),
+    }
+  }
+  /// Saves the state applying `txn` can change, unless it was saved for an earlier transaction.
+  fn save(&mut self, ledger: &Ledger, txn: &Transaction) {
+    let client_id = txn.client_id();
+    self.clients.entry(client_id).or_insert_with(|| ClientUndo {
+      known: ledger.clients.contains_key(&client_id),
+      locked: ledger.locked_clients.contains(&client_id),
+      flagged: ledger.flagged.contains(&client_id),
+      velocity: ledger.velocity.get(&client_id).cloned(),
+      anomaly_count: ledger.anomalies.get(&client_id).map(Vec::len),
+      balance: ledger.balances.get(&client_id).cloned(),
+      untracked: ledger.untracked.get(&client_id).cloned(),
+    });
+    let txn_id = txn.txn_id();
+    self.txns.entry(txn_id).or_insert_with(|| TxnUndo {
+      in_memory: ledger.txns.get(&txn_id).cloned(),
+      on_disk: ledger.disk.as_ref().and_then(|disk| disk.get(txn_id)),
+      arrival: ledger.arrivals.get(&txn_id).copied(),
+      dispute_opened: ledger.dispute_opened.get(&txn_id).copied(),
+      shortfall_hold: ledger.shortfall_holds.get(&txn_id).cloned(),
+    });
+  }
+  fn restore(self, ledger: &mut Ledger) {
+    for (txn_id, saved) in self.txns {
+      // Transactions first stored by the batch are taken off their client's list
+      if saved.in_memory.is_none() && saved.on_disk.is_none() {
+        if let Some(client_id) = ledger.stored(txn_id).map(|txn| txn.client_id()) {
+          if let Some(txn_ids) = ledger.clients.get_mut(&client_id) {
+            txn_ids.remove(&txn_id);
+          }
+        }
+      }
+      restore_entry(&mut ledger.txns, txn_id, saved.in_memory);
+      if let Some(disk) = &ledger.disk {
+        disk.remove(txn_id);
+        // As when storing, a transaction the index fails to take is kept in memory instead
+        if let Some(txn) = saved.on_disk.filter(|txn| !disk.insert(txn)) {
+          ledger.txns.insert(txn_id, txn);
+        }
+      }
+      restore_entry(&mut ledger.arrivals, txn_id, saved.arrival);
+      restore_entry(&mut ledger.dispute_opened, txn_id, saved.dispute_opened);
+      restore_entry(&mut ledger.shortfall_holds, txn_id, saved.shortfall_hold);
+    }
+    for (client_id, saved) in self.clients {
+      if !saved.known {
+        ledger.clients.remove(&client_id);
+      }
+      if !saved.locked {
+        ledger.locked_clients.remove(&client_id);
+      }
+      if !saved.flagged {
+        ledger.flagged.remove(&client_id);
+      }
+      restore_entry(&mut ledger.velocity, client_id, saved.velocity);
+      match saved.anomaly_count {
+        Some(count) => ledger.anomalies.entry(client_id).or_default().truncate(count),
+        None => {
+          ledger.anomalies.remove(&client_id);
+        },
+      }
+      restore_entry(&mut ledger.balances, client_id, saved.balance);
+      restore_entry(&mut ledger.untracked, client_id, saved.untracked);
+    }
+    ledger.audit.truncate(self.audit_len);
+    ledger.skipped_by_filter = self.skipped_by_filter;
+    ledger.dropped_by_lock = self.dropped_by_lock;
+    ledger.next_arrival = self.next_arrival;
+    ledger.early_arrivals = self.early_arrivals;
+  }
+}
+/// Puts a map's entry for `key` back to `saved`, removing it if there was none.
+fn restore_entry<K: Eq + Hash, V, S: BuildHasher>(map: &mut HashMap<K, V, S>, key: K, saved: Option<V>) {
+  match saved {
+    Some(value) => {
+      map.insert(key, value);
+    },
+    None => {
+      map.remove(&key);
+    },
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+struct VelocityLimit {
+  max_txns: usize,
+  max_volume: Currency,
+}
+
+/// Predicate deciding which clients' transactions are accepted by a `Ledger`.
+#[derive(Clone)]
+struct ClientFilter(Arc<dyn Fn(ClientId) -> bool + Send + Sync>);
+#[derive(Clone)]
+struct LedgerClock(Arc<dyn Clock>);
+/// Clocks are only equal if they are the same instance.
+impl PartialEq for LedgerClock {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.0, &other.0)
+  }
+}
+impl fmt::Debug for LedgerClock {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("LedgerClock")
+  }
+}
+
+/// How the ledger's `txns`, `clients` and `locked_clients` hash their keys.
+#[derive(Clone, Debug)]
+pub enum LedgerHasher {
+  /// Random keys, chosen for each ledger, as a plain `HashMap` uses.
+  Random(RandomState),
+  /// The same keys for every ledger, set by `Ledger::with_deterministic_hasher`.
+  Fixed,
+}
+impl Default for LedgerHasher {
+  fn default() -> Self {
+    Self::Random(RandomState::new())
+  }
+}
+impl BuildHasher for LedgerHasher {
+  type Hasher = DefaultHasher;
+  fn build_hasher(&self) -> DefaultHasher {
+    match self {
+      Self::Random(state) => state.build_hasher(),
+      Self::Fixed => DefaultHasher::new(),
+    }
+  }
+}
+
+/// Filters are only equal if they are the same closure.
+impl PartialEq for ClientFilter {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.0, &other.0)
+  }
+}
+impl fmt::Debug for ClientFilter {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str("ClientFilter")
+  }
+}
+
+/// Which kinds of `BasicTransaction` referential transactions are allowed to act upon.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputableKinds {
+  /// Only deposits can be disputed; referential transactions targeting withdrawals are ignored.
+  DepositsOnly,
+  /// Both deposits and withdrawals can be disputed.
+  #[default]
+  Both,
+}
+impl DisputableKinds {
+  pub fn allows(&self, txn: &BasicTransaction) -> bool {
+    !matches!((self, txn), (Self::DepositsOnly, BasicTransaction::Withdrawal { .. }))
+  }
+}
+
+/// How a dispute of a deposit is handled when the client no longer has its full amount available.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputeShortfall {
+  /// The full amount is held regardless, taking available below zero. This is recorded as an
+  /// `Anomaly::NegativeAvailable`.
+  #[default]
+  AllowNegative,
+  /// Only what is available is held. A chargeback then only reverses the held part, as the rest
+  /// of the deposit has already been spent.
+  HoldAvailable,
+}
+
+/// How an account summary's `total` is calculated. The models only differ while a withdrawal is
+/// disputed, as its funds are held even though they have already left the account.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TotalModel {
+  /// `available + held`, plus any pending funds. Funds held by a disputed withdrawal count
+  /// towards the total, as they would return to the client were the withdrawal reversed.
+  #[default]
+  AvailablePlusHeld,
+  /// Funds deposited less funds withdrawn, i.e. what the account actually holds. Funds held by
+  /// a disputed withdrawal are left out until a chargeback returns them.
+  DepositsMinusWithdrawals,
+}
+
+/// Ordering applied to account summaries before they are output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortKey {
+  /// Ascending client id.
+  #[default]
+  Client,
+  /// Descending total funds, ties broken by ascending client id.
+  TotalDesc,
+  /// Descending available funds, ties broken by ascending client id.
+  AvailableDesc,
+}
+impl SortKey {
+  pub fn sort(&self, summaries: &mut [AccountSummary]) {
+    match self {
+      Self::Client => summaries.sort_by_key(|summary| summary.client()),
+      Self::TotalDesc => summaries.sort_by(|a, b| b.total.cmp(&a.total).then(a.client.cmp(&b.client))),
+      Self::AvailableDesc => summaries.sort_by(|a, b| b.available.cmp(&a.available).then(a.client.cmp(&b.client))),
+    }
+  }
+}
+impl FromStr for SortKey {
+  type Err = String;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "client" => Ok(Self::Client),
+      "total" => Ok(Self::TotalDesc),
+      "available" => Ok(Self::AvailableDesc),
+      _ => Err(format!("Unknown sort key: {}", s)),
+    }
+  }
+}
+
+/// Iterator over the account summaries of a `Ledger`, created by `Ledger::summaries`.
+pub struct AccountSummaries<'a> {
+  ledger: &'a Ledger,
+  client_ids: hash_map::Keys<'a, ClientId, BTreeSet<TxnId>>,
+}
+impl<'a> Iterator for AccountSummaries<'a> {
+  type Item = AccountSummary;
+  fn next(&mut self) -> Option<Self::Item> {
+    for &client_id in self.client_ids.by_ref() {
+      if let Some(summary) = self.ledger.calculate_client_account_summary(client_id) {
+        return Some(summary)
+      }
+    }
+    None
+  }
+}
+
+/// Something unexpected about a client's transactions, usually one that was skipped rather than
+/// applied.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Anomaly {
+  /// A withdrawal was skipped because it would have put the account into a negative balance.
+  Overdraft { txn_id: TxnId, amount: Currency },
+  /// A dispute, resolve or chargeback referenced a transaction the ledger does not hold.
+  DanglingReference { txn_id: TxnId },
+  /// A deposit or withdrawal was skipped because its transaction id was already in use.
+  DuplicateTxnId { txn_id: TxnId },
+  /// A transaction was applied but took the account's total below zero.
+  NegativeTotal { txn_id: TxnId },
+  /// A deposit's dispute held more than the client had available, taking available below zero.
+  NegativeAvailable { txn_id: TxnId },
+  /// A dispute, resolve or chargeback was skipped because the amount it stated differs from the
+  /// referenced transaction's, or for a chargeback, is more than its dispute holds.
+  AmountMismatch { txn_id: TxnId, stated: Currency },
+  /// A dispute, resolve or chargeback was skipped because it isn't a valid next step from the
+  /// referenced transaction's dispute state, e.g. a chargeback after the dispute was resolved.
+  InvalidDisputeTransition { txn: ReferentialTransaction, state: DisputeState },
+}
+
+/// Error for a summary requested of a client the ledger has no record of.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UnknownClient(pub ClientId);
+impl fmt::Display for UnknownClient {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Unknown client {}.", self.0)
+  }
+}
+impl std::error::Error for UnknownClient {}
+
+/// An inconsistency in a ledger's internal state, found by `Ledger::integrity_check`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IntegrityError {
+  /// The client's balance differs from a replay of their transactions.
+  BalanceMismatch { client_id: ClientId },
+  /// The client lists a transaction the ledger does not hold.
+  MissingTxn { client_id: ClientId, txn_id: TxnId },
+  /// The client lists a transaction belonging to another client.
+  WrongClient { client_id: ClientId, txn_id: TxnId },
+  /// A transaction is not listed under its client.
+  UnlistedTxn { txn_id: TxnId },
+  /// A balance is held for a client the ledger has no record of.
+  UnknownClientBalance { client_id: ClientId },
+}
+impl fmt::Display for IntegrityError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::BalanceMismatch { client_id } =>
+        write!(f, "Balance of client {} does not match its transactions.", client_id),
+      Self::MissingTxn { client_id, txn_id } =>
+        write!(f, "Client {} lists missing transaction {}.", client_id, txn_id),
+      Self::WrongClient { client_id, txn_id } =>
+        write!(f, "Client {} lists transaction {} of another client.", client_id, txn_id),
+      Self::UnlistedTxn { txn_id } =>
+        write!(f, "Transaction {} is not listed under its client.", txn_id),
+      Self::UnknownClientBalance { client_id } =>
+        write!(f, "Balance held for unknown client {}.", client_id),
+    }
+  }
+}
+impl std::error::Error for IntegrityError {}
+
+/// Counts of how the records given to `Ledger::process_stream` were handled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ProcessingStats {
+  pub records: usize,
+  /// Transactions that changed the ledger.
+  pub applied: usize,
+  /// Transactions that parsed but were not applied, e.g. an overdrawing withdrawal, other than
+  /// those counted in `locked`.
+  pub rejected: usize,
+  /// Transactions dropped because their client's account is locked.
+  pub locked: usize,
+  /// Records that could not be parsed as a transaction, including amounts in scientific notation
+  /// unless the ledger accepts them.
+  pub parse_errors: usize,
+  /// Records that parsed but failed `Transaction::validate`, e.g. a negative deposit.
+  pub invalid: usize,
+}
+
+/// The first problem found by `Ledger::replay_from_csv_strict`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StrictError {
+  /// Line number of the offending record, starting from 1, or 0 if it couldn't be determined.
+  pub line: u64,
+  pub kind: StrictErrorKind,
+}
+#[derive(Clone, Debug, PartialEq)]
+pub enum StrictErrorKind {
+  /// The record could not be read or parsed as a transaction.
+  Malformed(String),
+  /// The transaction parsed but would not have been applied.
+  Rejected(ApplyOutcome),
+  /// A withdrawal was for more than the client had available.
+  Overdraft { client: ClientId, tx: TxnId },
+  /// The transaction was applied, but left the client's total negative.
+  NegativeTotal(ClientId),
+  /// The transaction parsed but failed `Transaction::validate`, e.g. a negative deposit.
+  Invalid(ValidationError),
+}
+impl fmt::Display for StrictError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.kind {
+      StrictErrorKind::Malformed(reason) => write!(f, "Line {}: {}", self.line, reason),
+      StrictErrorKind::Rejected(outcome) => write!(f, "Line {}: transaction rejected ({:?}).", self.line, outcome),
+      StrictErrorKind::Overdraft { client, tx } =>
+        write!(f, "Line {}: withdrawal {} overdraws client {}.", self.line, tx, client),
+      StrictErrorKind::NegativeTotal(client_id) =>
+        write!(f, "Line {}: transaction left client {} with a negative total.", self.line, client_id),
+      StrictErrorKind::Invalid(e) => write!(f, "Line {}: {}", self.line, e),
+    }
+  }
+}
+impl std::error::Error for StrictError {}
+
+/// What applying a transaction to a ledger would do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ApplyOutcome {
+  /// The transaction would be applied.
+  Applied,
+  /// The transaction's client is rejected by the ledger's client filter.
+  FilteredOut,
+  /// The client's account is locked.
+  AccountLocked,
+  /// A deposit or withdrawal's transaction id is already in use.
+  DuplicateTxnId,
+  /// A withdrawal is more than the client's available funds.
+  InsufficientFunds,
+  /// A dispute, resolve or chargeback references a transaction the ledger does not hold.
+  DanglingReference,
+  /// A dispute, resolve or chargeback states a different amount to the transaction it references,
+  /// or a chargeback states more than its dispute holds.
+  AmountMismatch,
+  /// A dispute, resolve or chargeback isn't a valid next step for the referenced transaction's
+  /// dispute, e.g. a chargeback of a transaction that isn't disputed.
+  InvalidDisputeTransition,
+  /// A dispute, resolve or chargeback references a transaction it can't act on.
+  Ignored,
+}
+impl ApplyOutcome {
+  pub fn is_applied(&self) -> bool {
+    *self == Self::Applied
+  }
+}
+
+/// What came of a dispute, resolve or chargeback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuditOutcome {
+  /// The transaction changed the state of the referenced transaction.
+  Applied,
+  /// The referenced transaction exists but was not in a state the transaction could act on.
+  Ignored,
+  /// The referenced transaction is not held by the ledger.
+  Dangling,
+}
+impl AuditOutcome {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Applied => "applied",
+      Self::Ignored => "ignored",
+      Self::Dangling => "dangling",
+    }
+  }
+}
+
+/// A line of a client's statement, from `Ledger::statement`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatementLine {
+  /// The transaction's id, or for a dispute, resolve or chargeback the id of the one it references.
+  pub txn_id: TxnId,
+  /// The type of the transaction, spelt as it is in csv input.
+  pub kind: &'static str,
+  /// The transaction's amount, or for a dispute, resolve or chargeback the amount it moved.
+  pub amount: Currency,
+  /// Available funds once the transaction was applied.
+  pub balance_after: Currency,
+}
+
+/// A dispute, resolve or chargeback along with its outcome.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+  /// The transaction's arrival index, see `Ledger::apply_transaction_at`.
+  pub index: u64,
+  pub txn: ReferentialTransaction,
+  pub outcome: AuditOutcome,
+}
+
+/// An account summary along with every anomaly affecting that client.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DetailedAccountSummary {
+  pub summary: AccountSummary,
+  pub anomalies: Vec<Anomaly>,
+}
+
+/// How the client accounts of two ledgers differ, ordered by client id.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LedgerDiff {
+  pub changes: Vec<ClientDiff>,
+}
+impl LedgerDiff {
+  /// Whether every client account is the same in both ledgers.
+  pub fn is_empty(&self) -> bool {
+    self.changes.is_empty()
+  }
+}
+
+/// How a ledger's account summaries differ from an expected set, from `Ledger::reconcile`. Each
+/// list is ordered by client id.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReconcileReport {
+  /// Summaries of clients whose account differs from what was expected, as `(expected, actual)`.
+  pub mismatches: Vec<(AccountSummary, AccountSummary)>,
+  /// Expected clients the ledger has no account for.
+  pub missing: Vec<ClientId>,
+  /// Clients with an account that weren't expected.
+  pub extra: Vec<ClientId>,
+}
+impl ReconcileReport {
+  /// Whether the ledger matched the expected summaries exactly.
+  pub fn is_ok(&self) -> bool {
+    self.mismatches.is_empty() && self.missing.is_empty() && self.extra.is_empty()
+  }
+}
+impl fmt::Display for ReconcileReport {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (expected, actual) in &self.mismatches {
+      writeln!(
+        f,
+        "Client {}: expected {}, {}, {}, {} but got {}, {}, {}, {}.",
+        expected.client, expected.available, expected.held, expected.total, expected.locked,
+        actual.available, actual.held, actual.total, actual.locked
+      )?;
+    }
+    for client_id in &self.missing {
+      writeln!(f, "Client {}: expected but not found.", client_id)?;
+    }
+    for client_id in &self.extra {
+      writeln!(f, "Client {}: found but not expected.", client_id)?;
+    }
+    Ok(())
+  }
+}
+
+/// The change in a client's account between two ledgers. Balances are the amount each changed
+/// by, and `locked` holds the new lock state if it changed.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientDiff {
+  pub client: ClientId,
+  pub available: Currency,
+  pub held: Currency,
+  pub pending: Currency,
+  pub total: Currency,
+  pub locked: Option<bool>,
+}
+
+/// The state of a client account to start a ledger from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccountState {
+  pub client: ClientId,
+  pub available: Currency,
+  pub held: Currency,
+  pub locked: bool,
+}
+
+/// Marked `non_exhaustive` so fields can be added without breaking downstream code, which should
+/// start from `AccountSummary::new()` rather than a struct literal.
+#[derive(Clone, Debug, PartialEq)]
+#[non_exhaustive]
+pub struct AccountSummary {
+  pub client: ClientId,
+  pub available: Currency,
+  pub held: Currency,
+  /// Available funds assuming every open dispute goes the client's way, i.e. disputed deposits are
+  /// resolved and disputed withdrawals reversed.
+  pub effective_available: Currency,
+  /// Deposited funds which have not yet settled, only non-zero when the ledger tracks settlement.
+  pub pending: Currency,
+  pub total: Currency,
+  pub locked: bool,
+  /// The decimal places the ledger rounds this client's balances to, if it sets one for them.
+  pub(crate) scale: Option<i64>,
+}
+impl AccountSummary {
+  pub fn new() -> Self {
+    AccountSummary {
+      client: 0,
+      available: Currency::zero_with_scale(),
+      held: Currency::zero_with_scale(),
+      effective_available: Currency::zero_with_scale(),
+      pending: Currency::zero_with_scale(),
+      total: Currency::zero_with_scale(),
+      locked: false,
+      scale: None,
+    }
+  }
+  pub fn client(&self) -> ClientId {
+    self.client
+  }
+  pub fn available(&self) -> &Currency {
+    &self.available
+  }
+  pub fn held(&self) -> &Currency {
+    &self.held
+  }
+  pub fn effective_available(&self) -> &Currency {
+    &self.effective_available
+  }
+  pub fn pending(&self) -> &Currency {
+    &self.pending
+  }
+  pub fn total(&self) -> &Currency {
+    &self.total
+  }
+  pub fn locked(&self) -> bool {
+    self.locked
+  }
+  /// The decimal places the ledger rounds this client's balances to, set by
+  /// `Ledger::with_client_scale`. The csv writers output balances at this scale when there is one.
+  pub fn scale(&self) -> Option<i64> {
+    self.scale
+  }
+  /// The `client,available,held,total,locked` output fields, with balances rounded to `precision`
+  /// decimal places, or the client's own scale if the ledger sets one, and written as plain
+  /// decimals, as the csv writers output them. `precision` is capped at
+  /// `CurrencyFormat::MAX_PRECISION`.
+  #[must_use]
+  pub fn to_csv_record(&self, precision: usize) -> Vec<String> {
+    let format = CurrencyFormat::default().with_precision(precision);
+    Column::ALL.iter().map(|column| column.value(self, &format)).collect()
+  }
+  /// A copy with every balance at `scale` decimal places, rounded if it had more, so summaries
+  /// that are equal at that scale also display the same.
+  #[must_use]
+  pub fn normalized(&self, scale: i64) -> AccountSummary {
+    let mut summary = self.clone();
+    for balance in [&mut summary.available, &mut summary.held, &mut summary.effective_available, &mut summary.pending, &mut summary.total] {
+      *balance = balance.round(scale).with_scale(scale);
+    }
+    summary
+  }
+  /// Whether two summaries are equal once normalized to `CURRENCY_SCALE`, i.e. they are output the
+  /// same. `==` compares balances by value alone, so `1.0` equals `1.0000` though they display
+  /// differently, while `1.00001` doesn't equal `1.0` though both are output as `1.0000`.
+  #[must_use]
+  pub fn approx_eq(&self, other: &Self) -> bool {
+    self.normalized(CURRENCY_SCALE) == other.normalized(CURRENCY_SCALE)
+  }
+}
+impl Default for AccountSummary {
+  fn default() -> Self {
+    Self::new()
+  }
+}
@@ -0,0 +1,2336 @@
+#![warn(rust_2018_idioms)]
+
+/*!
+This crate takes .csv files containing lists of transactions as input and calculates details of
+each clients' account.
+
+The `transaction_processor` binary drives the library; use the following syntax to run it:
+```bash
+cargo run -- "path/to/file.csv"
+```
+*/
+
+pub mod cli;
+pub mod clock;
+pub mod csv_handlers;
+mod disk_index;
+pub mod transactions;
+pub mod ledger;
+pub mod sharded;
+pub mod timing;
+
+use bigdecimal::BigDecimal;
+
+#[cfg(not(feature = "wide_ids"))]
+pub type ClientId = u16;
+#[cfg(not(feature = "wide_ids"))]
+pub type TxnId = u32;
+/// Wider ids, for inputs with more clients or transactions than the standard widths allow.
+#[cfg(feature = "wide_ids")]
+pub type ClientId = u32;
+#[cfg(feature = "wide_ids")]
+pub type TxnId = u64;
+pub type Currency = BigDecimal;
+/// Seconds since the unix epoch.
+pub type Timestamp = u64;
+
+/// Number of decimal places currency amounts are tracked to.
+pub const CURRENCY_SCALE: i64 = 4;
+
+/// Helpers for constructing `Currency` values consistently.
+pub trait CurrencyExt {
+  /// Zero, at the scale currency amounts are tracked to.
+  fn zero_with_scale() -> Self;
+  /// Rounds to `scale` decimal places if there are more, otherwise leaves the amount as it is.
+  fn limit_scale(&self, scale: i64) -> Self;
+}
+impl CurrencyExt for Currency {
+  fn zero_with_scale() -> Self {
+    BigDecimal::new(0.into(), CURRENCY_SCALE)
+  }
+  fn limit_scale(&self, scale: i64) -> Self {
+    if self.as_bigint_and_exponent().1 > scale {
+      self.round(scale).with_scale(scale)
+    }
+    else {
+      self.clone()
+    }
+  }
+}
+
+/// Every cargo feature of the crate, and whether this build was compiled with it.
+const FEATURES: [(&str, bool); 8] = [
+  ("bincode", cfg!(feature = "bincode")),
+  ("serde_json", cfg!(feature = "serde_json")),
+  ("memmap2", cfg!(feature = "memmap2")),
+  ("ureq", cfg!(feature = "ureq")),
+  ("rayon", cfg!(feature = "rayon")),
+  ("flate2", cfg!(feature = "flate2")),
+  ("wide_ids", cfg!(feature = "wide_ids")),
+  ("sled", cfg!(feature = "sled")),
+];
+
+/// The cargo features this build was compiled with, e.g. to check why JSON input isn't accepted.
+#[must_use]
+pub fn enabled_features() -> Vec<&'static str> {
+  FEATURES.iter().filter(|(_, enabled)| *enabled).map(|(name, _)| *name).collect()
+}
+
+/// Parses an expected amount exactly, unlike going through a float.
+#[cfg(test)]
+fn currency(amount: &str) -> Currency {
+  // Unwrap safety: Only used with literal amounts in tests
+  amount.parse().unwrap()
+}
+
+#[cfg(test)]
+fn new_currency(input: u32) -> Currency {
+  use num::BigInt;
+  // Input is scaled down by 10,000 e.g. 151234 -> 15.1234
+  Currency::new(BigInt::new(num::bigint::Sign::Plus, vec![input]), 4)
+}
+
+#[cfg(test)]
+mod ledger_tests {
+  use super::*;
+  use crate::{
+    csv_handlers::{write_as_csv, Column, CurrencyFormat},
+    transactions::{BasicTransaction, DisputeState, ReferentialTransaction, Transaction},
+    ledger::{AccountSummary, Anomaly, ApplyOutcome, ClientDiff, DisputableKinds, DisputeShortfall, IntegrityError, Ledger, SortKey, TotalModel, UnknownClient}
+  };
+  #[test]
+  fn deposit_summary_0() -> Result<(), ()> {
+    let mut l = Ledger::new();
+    let t = BasicTransaction::new_dep(0, 0, new_currency(10000));
+    l.add_simple_transaction(t);
+    let actual = l.calculate_client_account_summary(0);
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(10000),
+      held: new_currency(0),
+      effective_available: new_currency(10000),
+      pending: new_currency(0),
+      total: new_currency(10000),
+      locked: false,
+      scale: None,
+    };
+    assert_eq!(actual, Some(expected));
+    Ok(())
+  }
+  #[test]
+  fn deposit_summary_1() -> Result<(), ()> {
+      let mut l = Ledger::new();
+      let mut t = BasicTransaction::new_dep(0, 0, new_currency(100000));
+      l.add_simple_transaction(t);
+      t = BasicTransaction::new_dep(0, 1, new_currency(52500));
+      l.add_simple_transaction(t);
+      let actual = l.calculate_client_account_summary(0);
+      let expected = AccountSummary {
+          client: 0,
+          available: new_currency(152500),
+          held: new_currency(0),
+          effective_available: new_currency(152500),
+          pending: new_currency(0),
+          total: new_currency(152500),
+          locked: false,
+          scale: None,
+      };
+      assert_eq!(actual, Some(expected));
+      Ok(())
+  }
+  #[test]
+  fn deposit_withdrawal_summary_0() -> Result<(), ()> {
+      let mut l = Ledger::new();
+      let mut t = BasicTransaction::new_dep(0, 0, new_currency(100000));
+      l.add_simple_transaction(t);
+      t = BasicTransaction::new_dep(0, 1, new_currency(52500));
+      l.add_simple_transaction(t);
+      t = BasicTransaction::new_wit(0, 2, new_currency(37500));
+      l.add_simple_transaction(t);
+      let actual = l.calculate_client_account_summary(0);
+      let expected = AccountSummary {
+          client: 0,
+          available: new_currency(115000),
+          held: new_currency(0),
+          effective_available: new_currency(115000),
+          pending: new_currency(0),
+          total: new_currency(115000),
+          locked: false,
+          scale: None,
+      };
+      assert_eq!(actual, Some(expected));
+      Ok(())
+  }
+  #[test]
+  fn deposit_0() {
+    let mut l = Ledger::new();
+    let t = BasicTransaction::new_dep(0, 0, new_currency(100000));
+    l.add_simple_transaction(t);
+    assert!(l.clients.contains_key(&0));
+    assert!(l.clients.get(&0).unwrap().contains(&0));
+    assert!(l.txns.contains_key(&0));
+    assert_eq!(BasicTransaction::new_dep(0, 0, new_currency(100000)), *l.txns.get(&0).unwrap());
+  }
+  #[test]
+  fn multiple_summaries_0() -> Result<(), ()> {
+    let mut l = Ledger::new();
+    let mut t = BasicTransaction::new_dep(0, 0, new_currency(100000));
+    l.add_simple_transaction(t);
+    t = BasicTransaction::new_dep(1, 1, new_currency(999900));
+    l.add_simple_transaction(t);
+    let actual = l.calculate_all_account_summaries();
+    let expected_0 = AccountSummary {
+      client: 0,
+      available: new_currency(100000),
+      held: new_currency(0),
+      effective_available: new_currency(100000),
+      pending: new_currency(0),
+      total: new_currency(100000),
+      locked: false,
+      scale: None,
+    };
+    let expected_1 = AccountSummary {
+      client: 1,
+      available: new_currency(999900),
+      held: new_currency(0),
+      effective_available: new_currency(999900),
+      pending: new_currency(0),
+      total: new_currency(999900),
+      locked: false,
+      scale: None,
+    };
+    assert!(actual.contains(&expected_0));
+    assert!(actual.contains(&expected_1));
+    Ok(())
+  }
+  #[test]
+  fn iterate_summaries_0() {
+    let mut l = Ledger::new();
+    l.add_simple_transaction(BasicTransaction::new_dep(0, 0, new_currency(100000)));
+    l.add_simple_transaction(BasicTransaction::new_dep(1, 1, new_currency(999900)));
+    l.add_simple_transaction(BasicTransaction::new_wit(1, 2, new_currency(900)));
+    let mut actual = Vec::new();
+    for summary in &l {
+      actual.push(summary);
+    }
+    assert_eq!(2, actual.len());
+    assert_eq!(l.calculate_all_account_summaries(), actual);
+  }
+  #[test]
+  fn sort_summaries_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(2, 0, new_currency(10000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(30000)));
+    l.add_transaction(Transaction::new_dep(1, 2, new_currency(20000)));
+    l.add_transaction(Transaction::new_dep(3, 3, new_currency(50000)));
+    l.add_transaction(Transaction::new_dis(3, 3));
+    let client_order = |key: SortKey| {
+      let mut summaries = l.calculate_all_account_summaries();
+      key.sort(&mut summaries);
+      summaries.iter().map(|summary| summary.client()).collect::<Vec<_>>()
+    };
+    assert_eq!(vec![0, 1, 2, 3], client_order(SortKey::Client));
+    assert_eq!(vec![3, 0, 1, 2], client_order(SortKey::TotalDesc));
+    assert_eq!(vec![0, 1, 2, 3], client_order(SortKey::AvailableDesc));
+  }
+  #[test]
+  fn detailed_summaries_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(10000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(50000)));
+    l.add_transaction(Transaction::new_dis(0, 7));
+    l.add_transaction(Transaction::new_dep(1, 2, new_currency(10000)));
+    let mut detailed = l.detailed_summaries();
+    detailed.sort_by_key(|detailed| detailed.summary.client());
+    assert_eq!(2, detailed.len());
+    assert_eq!(l.calculate_client_account_summary(0).unwrap(), detailed[0].summary);
+    assert_eq!(&new_currency(10000), detailed[0].summary.available());
+    assert_eq!(vec![
+      Anomaly::Overdraft { txn_id: 1, amount: new_currency(50000) },
+      Anomaly::DanglingReference { txn_id: 7 },
+    ], detailed[0].anomalies);
+    assert!(detailed[1].anomalies.is_empty());
+  }
+  #[test]
+  fn deposit_dispute_0() -> Result<(), ()> {
+      let mut l = Ledger::new();
+      let mut t = Transaction::Basic(BasicTransaction::new_dep(0, 0, new_currency(100000)));
+      l.add_transaction(t);
+      t = Transaction::Basic(BasicTransaction::new_dep(0, 1, new_currency(52500)));
+      l.add_transaction(t);
+      t = Transaction::Referential(ReferentialTransaction::Dispute{
+          client_id: 0,
+          txn_id: 1,
+          amount: None,
+      });
+      l.add_transaction(t);
+      let actual = l.calculate_client_account_summary(0);
+      let expected = AccountSummary {
+          client: 0,
+          available: new_currency(100000),
+          held: new_currency(52500),
+          effective_available: new_currency(152500),
+          pending: new_currency(0),
+          total: new_currency(152500),
+          locked: false,
+          scale: None,
+      };
+      assert_eq!(actual, Some(expected));
+      Ok(())
+  }
+  #[test]
+  fn deposit_dispute_resolve_0() -> Result<(), ()> {
+      let mut l = Ledger::new();
+      let mut t = Transaction::Basic(BasicTransaction::new_dep(0, 0, new_currency(100000)));
+      l.add_transaction(t);
+      t = Transaction::Basic(BasicTransaction::new_dep(0, 1, new_currency(52500)));
+      l.add_transaction(t);
+      t = Transaction::Referential(ReferentialTransaction::Dispute{
+          client_id: 0,
+          txn_id: 1,
+          amount: None,
+      });
+      l.add_transaction(t);
+      t = Transaction::Referential(ReferentialTransaction::Resolve{
+          client_id: 0,
+          txn_id: 1,
+          amount: None,
+      });
+      l.add_transaction(t);
+      let actual = l.calculate_client_account_summary(0);
+      let expected = AccountSummary {
+          client: 0,
+          available: new_currency(152500),
+          held: new_currency(0),
+          effective_available: new_currency(152500),
+          pending: new_currency(0),
+          total: new_currency(152500),
+          locked: false,
+          scale: None,
+      };
+      assert_eq!(actual, Some(expected));
+      Ok(())
+  }
+  #[test]
+  fn deposit_dispute_chargeback_0() -> Result<(), ()> {
+    let mut l = Ledger::new();
+    let mut t = Transaction::Basic(BasicTransaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(t);
+    t = Transaction::Basic(BasicTransaction::new_dep(0, 1, new_currency(52500)));
+    l.add_transaction(t);
+    t = Transaction::Referential(ReferentialTransaction::Dispute{
+      client_id: 0,
+      txn_id: 1,
+      amount: None,
+    });
+    l.add_transaction(t);
+    t = Transaction::Referential(ReferentialTransaction::Chargeback{
+      client_id: 0,
+      txn_id: 1,
+      amount: None,
+    });
+    l.add_transaction(t);
+    let actual = l.calculate_client_account_summary(0);
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(100000),
+      held: new_currency(0),
+      effective_available: new_currency(100000),
+      pending: new_currency(0),
+      total: new_currency(100000),
+      locked: true,
+      scale: None,
+    };
+    assert_eq!(actual, Some(expected));
+    Ok(())
+  }
+  #[test]
+  fn deposit_double_chargeback_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_cha(0, 1));
+    let charged_back = l.clone();
+    l.add_transaction(Transaction::new_cha(0, 1));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_cha(0, 1));
+    assert!(charged_back.diff(&l).is_empty());
+    assert!(l.txns.get(&1).unwrap().charged_back());
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(100000), summary.available());
+    assert_eq!(&new_currency(0), summary.held());
+    assert_eq!(&new_currency(100000), summary.total());
+  }
+  #[test]
+  fn withdraw_dispute_0() -> Result<(), ()> {
+      let mut l = Ledger::new();
+      let mut t = Transaction::Basic(BasicTransaction::new_dep(0, 0, new_currency(100000)));
+      l.add_transaction(t);
+      t = Transaction::Basic(BasicTransaction::new_wit(0, 1, new_currency(52500)));
+      l.add_transaction(t);
+      t = Transaction::Referential(ReferentialTransaction::Dispute{
+          client_id: 0,
+          txn_id: 1,
+          amount: None,
+      });
+      l.add_transaction(t);
+      let actual = l.calculate_client_account_summary(0);
+      let expected = AccountSummary {
+          client: 0,
+          available: new_currency(47500),
+          held: new_currency(52500),
+          effective_available: new_currency(100000),
+          pending: new_currency(0),
+          total: new_currency(100000),
+          locked: false,
+          scale: None,
+      };
+      assert_eq!(actual, Some(expected));
+      Ok(())
+  }
+  #[test]
+  fn withdraw_dispute_resolve_0() -> Result<(), ()> {
+      let mut l = Ledger::new();
+      let mut t = Transaction::Basic(BasicTransaction::new_dep(0, 0, new_currency(100000)));
+      l.add_transaction(t);
+      t = Transaction::Basic(BasicTransaction::new_wit(0, 1, new_currency(52500)));
+      l.add_transaction(t);
+      t = Transaction::Referential(ReferentialTransaction::Dispute{
+          client_id: 0,
+          txn_id: 1,
+          amount: None,
+      });
+      l.add_transaction(t);
+      t = Transaction::Referential(ReferentialTransaction::Resolve{
+          client_id: 0,
+          txn_id: 1,
+          amount: None,
+      });
+      l.add_transaction(t);
+      let actual = l.calculate_client_account_summary(0);
+      let expected = AccountSummary {
+          client: 0,
+          available: new_currency(47500),
+          held: new_currency(0),
+          effective_available: new_currency(47500),
+          pending: new_currency(0),
+          total: new_currency(47500),
+          locked: false,
+          scale: None,
+      };
+      assert_eq!(actual, Some(expected));
+      Ok(())
+  }
+  #[test]
+  fn withdraw_dispute_chargeback_0() -> Result<(), ()> {
+      let mut l = Ledger::new();
+      let mut t = Transaction::Basic(BasicTransaction::new_dep(0, 0, new_currency(100000)));
+      l.add_transaction(t);
+      t = Transaction::Basic(BasicTransaction::new_wit(0, 1, new_currency(52500)));
+      l.add_transaction(t);
+      t = Transaction::Referential(ReferentialTransaction::Dispute{
+          client_id: 0,
+          txn_id: 1,
+          amount: None,
+      });
+      l.add_transaction(t);
+      t = Transaction::Referential(ReferentialTransaction::Chargeback{
+          client_id: 0,
+          txn_id: 1,
+          amount: None,
+      });
+      l.add_transaction(t);
+      let actual = l.calculate_client_account_summary(0);
+      let expected = AccountSummary {
+          client: 0,
+          available: new_currency(100000),
+          held: new_currency(0),
+          effective_available: new_currency(100000),
+          pending: new_currency(0),
+          total: new_currency(100000),
+          locked: true,
+          scale: None,
+      };
+      assert_eq!(actual, Some(expected));
+      Ok(())
+  }
+  #[test]
+  fn deposit_withdraw_dispute_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(50000)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    // The withdrawal went through before the dispute, so must not be undone by it
+    assert!(l.txns.contains_key(&1));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(100000), summary.held());
+    assert_eq!(&new_currency(50000), summary.total());
+  }
+  #[test]
+  fn pending_deposit_0() {
+    let mut l = Ledger::new().with_settlement(true);
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(50000)));
+    assert!(!l.txns.contains_key(&1));
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(0),
+      held: new_currency(0),
+      effective_available: new_currency(0),
+      pending: new_currency(100000),
+      total: new_currency(100000),
+      locked: false,
+      scale: None,
+    };
+    assert_eq!(l.calculate_client_account_summary(0), Some(expected));
+    assert!(l.settle(0));
+    assert!(!l.settle(0));
+    l.add_transaction(Transaction::new_wit(0, 2, new_currency(50000)));
+    assert!(l.txns.contains_key(&2));
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(50000),
+      held: new_currency(0),
+      effective_available: new_currency(50000),
+      pending: new_currency(0),
+      total: new_currency(50000),
+      locked: false,
+      scale: None,
+    };
+    assert_eq!(l.calculate_client_account_summary(0), Some(expected));
+  }
+  #[test]
+  fn pending_deposit_dispute_settle_all_0() {
+    let mut l = Ledger::new().with_settlement(true);
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(20000)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    assert_eq!(2, l.settle_all());
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(20000), summary.available());
+    assert_eq!(&new_currency(100000), summary.held());
+    assert_eq!(&new_currency(0), summary.pending());
+    l.add_transaction(Transaction::new_res(0, 0));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(120000), summary.available());
+    assert_eq!(&new_currency(120000), summary.total());
+  }
+  #[test]
+  fn adjustment_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    l.add_transaction(Transaction::new_adj(0, 1, new_currency(2500)));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(2500), summary.available());
+    assert_eq!(&new_currency(100000), summary.held());
+    assert_eq!(&new_currency(102500), summary.total());
+    l.add_transaction(Transaction::new_adj(0, 2, -new_currency(1000)));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(1500), summary.available());
+    assert_eq!(&new_currency(100000), summary.held());
+  }
+  #[test]
+  fn adjustment_not_disputable_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_adj(0, 0, new_currency(2500)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    l.add_transaction(Transaction::new_cha(0, 0));
+    assert!(!l.txns.contains_key(&0));
+    assert!(l.locked_client_ids().is_empty());
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(2500), summary.available());
+    assert_eq!(&new_currency(0), summary.held());
+  }
+  #[test]
+  fn deposit_dispute_withdraw_held_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(1000000)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(500000)));
+    assert!(!l.txns.contains_key(&1));
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(0),
+      held: new_currency(1000000),
+      effective_available: new_currency(1000000),
+      pending: new_currency(0),
+      total: new_currency(1000000),
+      locked: false,
+      scale: None,
+    };
+    assert_eq!(l.calculate_client_account_summary(0), Some(expected));
+  }
+  #[test]
+  fn deposit_dispute_withdraw_held_lower_txn_id_0() {
+    // Arrival order decides what funds are available, not transaction id order
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 5, new_currency(1000000)));
+    l.add_transaction(Transaction::new_dis(0, 5));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(500000)));
+    assert!(!l.txns.contains_key(&1));
+    l.add_transaction(Transaction::new_res(0, 5));
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(1000000),
+      held: new_currency(0),
+      effective_available: new_currency(1000000),
+      pending: new_currency(0),
+      total: new_currency(1000000),
+      locked: false,
+      scale: None,
+    };
+    assert_eq!(l.calculate_client_account_summary(0), Some(expected));
+  }
+  #[test]
+  fn deposit_double_dispute_single_resolve_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_res(0, 1));
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(100000),
+      held: new_currency(52500),
+      effective_available: new_currency(152500),
+      pending: new_currency(0),
+      total: new_currency(152500),
+      locked: false,
+      scale: None,
+    };
+    assert_eq!(l.calculate_client_account_summary(0), Some(expected));
+  }
+  #[test]
+  fn deposit_double_dispute_double_resolve_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_res(0, 1));
+    l.add_transaction(Transaction::new_res(0, 1));
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(152500),
+      held: new_currency(0),
+      effective_available: new_currency(152500),
+      pending: new_currency(0),
+      total: new_currency(152500),
+      locked: false,
+      scale: None,
+    };
+    assert_eq!(l.calculate_client_account_summary(0), Some(expected));
+  }
+  #[test]
+  fn withdraw_dispute_deposits_only_0() {
+    let mut l = Ledger::new().with_disputable_kinds(DisputableKinds::DepositsOnly);
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    assert!(!l.txns.get(&1).unwrap().disputed());
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(47500),
+      held: new_currency(0),
+      effective_available: new_currency(47500),
+      pending: new_currency(0),
+      total: new_currency(47500),
+      locked: false,
+      scale: None,
+    };
+    assert_eq!(l.calculate_client_account_summary(0), Some(expected));
+  }
+  #[test]
+  fn withdraw_dispute_both_0() {
+    let mut l = Ledger::new().with_disputable_kinds(DisputableKinds::Both);
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    assert!(l.txns.get(&1).unwrap().disputed());
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(47500),
+      held: new_currency(52500),
+      effective_available: new_currency(100000),
+      pending: new_currency(0),
+      total: new_currency(100000),
+      locked: false,
+      scale: None,
+    };
+    assert_eq!(l.calculate_client_account_summary(0), Some(expected));
+  }
+  #[test]
+  fn deposit_dispute_deposits_only_0() {
+    let mut l = Ledger::new().with_disputable_kinds(DisputableKinds::DepositsOnly);
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    l.add_transaction(Transaction::new_cha(0, 0));
+    assert!(l.is_locked(0));
+  }
+  #[test]
+  fn client_allowlist_0() {
+    let mut l = Ledger::new().with_client_filter(|client_id| client_id == 1);
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(1, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dep(2, 2, new_currency(52500)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    assert_eq!(1, l.clients.len());
+    assert!(l.clients.contains_key(&1));
+    assert!(!l.txns.get(&1).unwrap().disputed());
+    assert_eq!(3, l.skipped_by_filter());
+  }
+  #[test]
+  fn client_denylist_0() {
+    let mut l = Ledger::new().with_client_filter(|client_id| client_id != 2);
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(1, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dep(2, 2, new_currency(52500)));
+    l.add_transaction(Transaction::new_wit(2, 3, new_currency(500)));
+    assert_eq!(2, l.clients.len());
+    assert!(!l.clients.contains_key(&2));
+    assert!(!l.txns.contains_key(&2));
+    assert_eq!(2, l.skipped_by_filter());
+  }
+  #[test]
+  fn unknown_client_vs_empty_summary_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(100000)));
+    let summary = l.try_client_account_summary(0).unwrap();
+    assert_eq!(new_currency(0), *summary.total());
+    assert_eq!(Err(UnknownClient(1)), l.try_client_account_summary(1));
+    assert_eq!(None, l.calculate_client_account_summary(1));
+  }
+  #[test]
+  fn diff_chargeback_0() {
+    let mut before = Ledger::new();
+    before.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    before.add_transaction(Transaction::new_dep(0, 1, new_currency(52500)));
+    before.add_transaction(Transaction::new_dep(1, 2, new_currency(52500)));
+    before.add_transaction(Transaction::new_dis(0, 1));
+    let mut after = before.clone();
+    after.add_transaction(Transaction::new_cha(0, 1));
+    assert!(before.diff(&before).is_empty());
+    let diff = before.diff(&after);
+    assert_eq!(vec![ClientDiff {
+      client: 0,
+      available: new_currency(0),
+      held: -new_currency(52500),
+      pending: new_currency(0),
+      total: -new_currency(52500),
+      locked: Some(true),
+    }], diff.changes);
+    let diff = after.diff(&before);
+    assert_eq!(new_currency(52500), diff.changes[0].total);
+    assert_eq!(Some(false), diff.changes[0].locked);
+  }
+  #[test]
+  fn simulate_overdraft_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    let before = l.clone();
+    assert_eq!(ApplyOutcome::InsufficientFunds, l.simulate(&Transaction::new_wit(0, 1, new_currency(100001))));
+    assert_eq!(ApplyOutcome::InsufficientFunds, l.simulate(&Transaction::new_wit(1, 1, new_currency(1))));
+    assert_eq!(ApplyOutcome::Applied, l.simulate(&Transaction::new_wit(0, 1, new_currency(100000))));
+    assert!(before.diff(&l).is_empty());
+    assert!(!l.txns.contains_key(&1));
+  }
+  #[test]
+  fn simulate_matches_add_transaction_0() {
+    let txns = [
+      Transaction::new_dep(0, 0, new_currency(100000)),
+      Transaction::new_dep(0, 0, new_currency(100000)),
+      Transaction::new_res(0, 0),
+      Transaction::new_dis(1, 0),
+      Transaction::new_dis(0, 0),
+      Transaction::new_dis(0, 7),
+      Transaction::new_wit(0, 1, new_currency(1)),
+      Transaction::new_cha(0, 0),
+      Transaction::new_dep(0, 2, new_currency(1)),
+      Transaction::new_adj(0, 3, new_currency(1)),
+    ];
+    let expected = [
+      ApplyOutcome::Applied,
+      ApplyOutcome::DuplicateTxnId,
+      ApplyOutcome::InvalidDisputeTransition,
+      ApplyOutcome::Ignored,
+      ApplyOutcome::Applied,
+      ApplyOutcome::DanglingReference,
+      ApplyOutcome::InsufficientFunds,
+      ApplyOutcome::Applied,
+      ApplyOutcome::AccountLocked,
+      ApplyOutcome::AccountLocked,
+    ];
+    let mut l = Ledger::new();
+    for (txn, expected) in txns.iter().zip(&expected) {
+      assert_eq!(*expected, l.simulate(txn), "{:?}", txn);
+      let before = l.clone();
+      l.add_transaction(txn.clone());
+      assert_eq!(expected.is_applied(), !before.diff(&l).is_empty() || before.txns != l.txns, "{:?}", txn);
+    }
+    assert_eq!(ApplyOutcome::FilteredOut, Ledger::new().with_client_filter(|c| c != 0).simulate(&txns[0]));
+  }
+  #[test]
+  fn seed_balances_0() {
+    let mut l = Ledger::new();
+    l.seed_balances(std::iter::once((0, new_currency(1000000))).collect());
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(500000)));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(new_currency(1500000), *summary.available());
+    assert_eq!(new_currency(1500000), *summary.total());
+    l.add_transaction(Transaction::new_dis(0, 0));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(new_currency(1000000), *summary.available());
+    assert_eq!(new_currency(500000), *summary.held());
+  }
+  #[test]
+  fn clock_timestamps_0() {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use crate::clock::Clock;
+    /// Ticks forward by 10 seconds every time it's read.
+    struct MockClock(AtomicU64);
+    impl Clock for MockClock {
+      fn now(&self) -> crate::Timestamp {
+        self.0.fetch_add(10, Ordering::SeqCst)
+      }
+    }
+    let mut l = Ledger::new().with_clock(MockClock(AtomicU64::new(100)));
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_wit(0, 2, new_currency(2500)));
+    let mut stamped = BasicTransaction::new_dep(0, 3, new_currency(10000));
+    stamped.set_timestamp(5);
+    l.add_simple_transaction(stamped);
+    l.add_transaction(Transaction::new_dis(0, 1));
+    assert_eq!(Some(100), l.txns.get(&0).unwrap().timestamp());
+    assert_eq!(Some(120), l.txns.get(&2).unwrap().timestamp());
+    assert_eq!(Some(5), l.txns.get(&3).unwrap().timestamp());
+    // Transaction 1's dispute holds its funds, though the dispute itself came after the range
+    let summary = l.summary_between(0, 110, 120).unwrap();
+    assert_eq!(&new_currency(0), summary.available());
+    assert_eq!(&new_currency(52500), summary.held());
+    let summary = l.summary_between(0, 0, 121).unwrap();
+    assert_eq!(&new_currency(107500), summary.available());
+    assert_eq!(&new_currency(52500), summary.held());
+    assert_eq!(&new_currency(160000), summary.total());
+    let summary = l.summary_between(0, 0, 101).unwrap();
+    assert_eq!(&new_currency(110000), summary.total());
+    let summary = l.summary_between(0, 200, 300).unwrap();
+    assert_eq!(&new_currency(0), summary.total());
+    assert_eq!(None, l.summary_between(1, 0, 300));
+  }
+  #[test]
+  fn fixed_clock_0() {
+    let mut l = Ledger::new().with_clock(crate::clock::FixedClock(42));
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    assert_eq!(Some(42), l.txns.get(&0).unwrap().timestamp());
+  }
+  #[test]
+  fn client_scale_0() {
+    let mut l = Ledger::new().with_client_scale(0, 0).with_client_scale(1, 8);
+    // JPY
+    l.add_transaction(Transaction::new_dep(0, 0, "1500.7".parse().unwrap()));
+    l.add_transaction(Transaction::new_dep(0, 1, "20.2".parse().unwrap()));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    // BTC
+    l.add_transaction(Transaction::new_dep(1, 2, "0.123456789".parse().unwrap()));
+    l.add_transaction(Transaction::new_dep(1, 3, "1.5".parse().unwrap()));
+    // Default
+    l.add_transaction(Transaction::new_dep(2, 4, "1.5".parse().unwrap()));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!("1501", summary.available().to_string());
+    assert_eq!("20", summary.held().to_string());
+    assert_eq!("1521", summary.total().to_string());
+    let summary = l.calculate_client_account_summary(1).unwrap();
+    assert_eq!("1.62345679", summary.available().to_string());
+    assert_eq!("0.00000000", summary.held().to_string());
+    let summary = l.calculate_client_account_summary(2).unwrap();
+    assert_eq!("1.5000", summary.available().to_string());
+    // Written at each client's scale, the default client at `CURRENCY_SCALE`
+    let mut summaries = l.calculate_all_account_summaries();
+    SortKey::Client.sort(&mut summaries);
+    let mut out = Vec::new();
+    write_as_csv(&mut out, summaries, &Column::ALL).unwrap();
+    assert_eq!(
+      "client,available,held,total,locked\n0,1501,20,1521,false\n1,1.62345679,0.00000000,1.62345679,false\n2,1.5000,0.0000,1.5000,false\n",
+      String::from_utf8(out).unwrap()
+    );
+  }
+  #[test]
+  fn integrity_check_0() {
+    let mut l = Ledger::new();
+    l.seed_balances(std::iter::once((0, new_currency(1000000))).collect());
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_wit(1, 2, new_currency(500)));
+    l.add_transaction(Transaction::new_adj(1, 3, new_currency(500)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    assert_eq!(Ok(()), l.integrity_check());
+
+    let mut corrupted = l.clone();
+    corrupted.txns.insert(1, BasicTransaction::new_dep(0, 1, new_currency(1)));
+    assert_eq!(Err(IntegrityError::BalanceMismatch { client_id: 0 }), corrupted.integrity_check());
+    let mut corrupted = l.clone();
+    corrupted.clients.get_mut(&1).unwrap().insert(7);
+    assert_eq!(Err(IntegrityError::MissingTxn { client_id: 1, txn_id: 7 }), corrupted.integrity_check());
+    let mut corrupted = l.clone();
+    corrupted.clients.get_mut(&0).unwrap().remove(&0);
+    assert_eq!(Err(IntegrityError::BalanceMismatch { client_id: 0 }), corrupted.integrity_check());
+    corrupted.clients.get_mut(&1).unwrap().insert(0);
+    corrupted.clients.remove(&0);
+    assert_eq!(Err(IntegrityError::WrongClient { client_id: 1, txn_id: 0 }), corrupted.integrity_check());
+  }
+  #[test]
+  fn canonicalize_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(1, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_wit(2, 2, new_currency(500)));
+    l.add_transaction(Transaction::new_adj(3, 3, new_currency(500)));
+    // Client 1's only transaction is removed from under it
+    l.txns.remove(&1);
+    assert!(l.integrity_check().is_err());
+    l.canonicalize();
+    assert_eq!(Ok(()), l.integrity_check());
+    assert!(!l.clients.contains_key(&1));
+    assert_eq!(None, l.calculate_client_account_summary(1));
+    // Client 2's only withdrawal overdrew the account, so it had nothing left to keep
+    assert!(!l.clients.contains_key(&2));
+    assert_eq!(&new_currency(100000), l.calculate_client_account_summary(0).unwrap().total());
+    assert_eq!(&new_currency(500), l.calculate_client_account_summary(3).unwrap().total());
+    let canonical = l.clone();
+    l.canonicalize();
+    assert_eq!(canonical, l);
+  }
+  #[test]
+  fn admin_hold_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    assert!(l.place_hold(0, &new_currency(40000)));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(60000), summary.available());
+    assert_eq!(&new_currency(92500), summary.held());
+    assert_eq!(&new_currency(152500), summary.total());
+    assert_eq!(Ok(()), l.integrity_check());
+    // Neither more than is available nor any of the dispute hold can be moved
+    assert!(!l.place_hold(0, &new_currency(60001)));
+    assert!(!l.release_hold(0, &new_currency(40001)));
+    assert!(!l.release_hold(0, &-new_currency(1)));
+    assert!(!l.place_hold(1, &new_currency(1)));
+    assert!(l.release_hold(0, &new_currency(30000)));
+    l.add_transaction(Transaction::new_res(0, 1));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(142500), summary.available());
+    assert_eq!(&new_currency(10000), summary.held());
+    assert_eq!(&new_currency(152500), summary.total());
+    assert!(!l.place_hold(0, &new_currency(142501)));
+    assert_eq!(Ok(()), l.integrity_check());
+  }
+  #[test]
+  fn gross_flows_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dep(0, 2, new_currency(2500)));
+    l.add_transaction(Transaction::new_dep(0, 3, new_currency(7500)));
+    l.add_transaction(Transaction::new_wit(0, 4, new_currency(20000)));
+    // Rejected for insufficient funds
+    l.add_transaction(Transaction::new_wit(0, 5, new_currency(1000000)));
+    l.add_transaction(Transaction::new_adj(0, 6, new_currency(500)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_dis(0, 2));
+    l.add_transaction(Transaction::new_cha(0, 2));
+    assert_eq!((new_currency(160000), new_currency(20000)), l.gross_flows(0));
+    assert_eq!((new_currency(0), new_currency(0)), l.gross_flows(1));
+  }
+  #[test]
+  fn velocity_limit_0() {
+    let mut l = Ledger::new().with_velocity_limit(3, new_currency(1000000));
+    for txn_id in 0..4 {
+      l.add_transaction(Transaction::new_dep(0, txn_id, new_currency(100)));
+    }
+    for txn_id in 4..7 {
+      l.add_transaction(Transaction::new_dep(1, txn_id, new_currency(100)));
+    }
+    l.add_transaction(Transaction::new_dep(2, 7, new_currency(1000000)));
+    l.add_transaction(Transaction::new_wit(2, 8, new_currency(1)));
+    // Referential transactions don't count
+    l.add_transaction(Transaction::new_dis(1, 4));
+    l.add_transaction(Transaction::new_res(1, 4));
+    assert_eq!(vec![0, 2], l.flagged_accounts());
+    // Flagged accounts are still processed
+    assert_eq!(&new_currency(400), l.calculate_client_account_summary(0).unwrap().total());
+    assert!(Ledger::new().flagged_accounts().is_empty());
+  }
+  #[test]
+  fn negative_total_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(80000)));
+    l.add_transaction(Transaction::new_dep(1, 2, new_currency(100000)));
+    assert!(l.negative_totals().is_empty());
+    // Charging back a deposit that has mostly been withdrawn takes the total below zero
+    l.add_transaction(Transaction::new_dis(0, 0));
+    l.add_transaction(Transaction::new_cha(0, 0));
+    assert_eq!(&-new_currency(80000), l.calculate_client_account_summary(0).unwrap().total());
+    assert_eq!(vec![0], l.negative_totals());
+    let mut detailed = l.detailed_summaries();
+    detailed.sort_by_key(|detailed| detailed.summary.client());
+    assert_eq!(vec![Anomaly::NegativeAvailable { txn_id: 0 }, Anomaly::NegativeTotal { txn_id: 0 }], detailed[0].anomalies);
+  }
+  #[test]
+  fn effective_available_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(40000)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(60000), summary.available());
+    assert_eq!(&new_currency(40000), summary.held());
+    // The withdrawal would be reversed
+    assert_eq!(&new_currency(100000), summary.effective_available());
+    l.add_transaction(Transaction::new_dep(0, 2, new_currency(20000)));
+    l.add_transaction(Transaction::new_dis(0, 2));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(60000), summary.available());
+    assert_eq!(&new_currency(120000), summary.effective_available());
+    // Settling both disputes in the client's favour makes the projection real
+    let mut settled = l.clone();
+    settled.add_transaction(Transaction::new_cha(0, 1));
+    settled.add_transaction(Transaction::new_res(0, 2));
+    assert_eq!(summary.effective_available(), settled.calculate_client_account_summary(0).unwrap().available());
+    l.add_transaction(Transaction::new_res(0, 1));
+    assert_eq!(&new_currency(80000), l.calculate_client_account_summary(0).unwrap().effective_available());
+  }
+  #[test]
+  fn dispute_shortfall_0() {
+    let ledger = |dispute_shortfall| {
+      let mut l = Ledger::new().with_dispute_shortfall(dispute_shortfall);
+      l.add_transaction(Transaction::new_dep(0, 0, new_currency(1000000)));
+      l.add_transaction(Transaction::new_wit(0, 1, new_currency(600000)));
+      l.add_transaction(Transaction::new_dis(0, 0));
+      l
+    };
+    // The full deposit is held, which is flagged
+    let l = ledger(DisputeShortfall::AllowNegative);
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&-new_currency(600000), summary.available());
+    assert_eq!(&new_currency(1000000), summary.held());
+    assert_eq!(&new_currency(400000), summary.total());
+    assert_eq!(vec![Anomaly::NegativeAvailable { txn_id: 0 }], l.detailed_summaries()[0].anomalies);
+    assert_eq!(Ok(()), l.integrity_check());
+    // Only the remaining 40 is held
+    let l = ledger(DisputeShortfall::HoldAvailable);
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(0), summary.available());
+    assert_eq!(&new_currency(400000), summary.held());
+    assert_eq!(&new_currency(400000), summary.total());
+    assert!(l.detailed_summaries()[0].anomalies.is_empty());
+    assert_eq!(Ok(()), l.integrity_check());
+    let mut resolved = l.clone();
+    resolved.add_transaction(Transaction::new_res(0, 0));
+    assert_eq!(&new_currency(400000), resolved.calculate_client_account_summary(0).unwrap().available());
+    assert_eq!(Ok(()), resolved.integrity_check());
+    let mut charged_back = l.clone();
+    charged_back.add_transaction(Transaction::new_cha(0, 0));
+    let summary = charged_back.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(0), summary.total());
+    assert!(summary.locked());
+    assert_eq!(Ok(()), charged_back.integrity_check());
+  }
+  #[test]
+  fn stated_amount_0() {
+    use std::convert::TryFrom;
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(50000)));
+    // 10.00 is the same amount as the stored 10.0000
+    let matching = Transaction::try_from("dispute,0,0,10.00").unwrap();
+    assert_eq!(ApplyOutcome::Applied, l.simulate(&matching));
+    l.add_transaction(matching);
+    let mismatched = Transaction::try_from("dispute,0,1,6.0").unwrap();
+    assert_eq!(ApplyOutcome::AmountMismatch, l.simulate(&mismatched));
+    l.add_transaction(mismatched);
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(50000), summary.available());
+    assert_eq!(&new_currency(100000), summary.held());
+    assert!(!l.txns[&1].disputed());
+    assert_eq!(vec![Anomaly::AmountMismatch { txn_id: 1, stated: currency("6.0") }], l.detailed_summaries()[0].anomalies);
+  }
+  #[test]
+  fn dispute_skipped_overdraft_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(500000)));
+    assert_eq!(ApplyOutcome::DanglingReference, l.simulate(&Transaction::new_dis(0, 1)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_cha(0, 1));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(100000), summary.available());
+    assert_eq!(&new_currency(0), summary.held());
+    assert_eq!(&new_currency(100000), summary.total());
+    assert!(!summary.locked());
+    assert!(l.open_disputes().is_empty());
+  }
+  #[test]
+  fn partial_chargeback_0() {
+    use std::convert::TryFrom;
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    let partial = Transaction::try_from("chargeback,0,0,4.0").unwrap();
+    assert_eq!(ApplyOutcome::Applied, l.simulate(&partial));
+    l.add_transaction(partial);
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(60000), summary.available());
+    assert_eq!(&new_currency(0), summary.held());
+    assert_eq!(&new_currency(60000), summary.total());
+    assert!(summary.locked());
+    assert!(l.txns[&0].charged_back());
+    assert_eq!(Ok(()), l.integrity_check());
+  }
+  #[test]
+  fn partial_chargeback_1() {
+    use std::convert::TryFrom;
+    use crate::ledger::DisputeShortfall;
+    // Only the 3.0 still available is held by the dispute, so at most that can be charged back
+    let mut l = Ledger::new().with_dispute_shortfall(DisputeShortfall::HoldAvailable);
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(70000)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    let excessive = Transaction::try_from("chargeback,0,0,5.0").unwrap();
+    assert_eq!(ApplyOutcome::AmountMismatch, l.simulate(&excessive));
+    l.add_transaction(excessive);
+    assert!(l.txns[&0].disputed());
+    l.add_transaction(Transaction::try_from("chargeback,0,0,2.0").unwrap());
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(10000), summary.available());
+    assert_eq!(&new_currency(0), summary.held());
+    assert_eq!(&new_currency(10000), summary.total());
+    assert!(summary.locked());
+    assert_eq!(Ok(()), l.integrity_check());
+    // A partially charged back withdrawal only returns the stated amount
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 1, new_currency(40000)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::try_from("chargeback,0,1,1.0").unwrap());
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(70000), summary.available());
+    assert_eq!(&new_currency(70000), summary.total());
+    assert_eq!(Ok(()), l.integrity_check());
+  }
+  #[test]
+  fn display_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(12, 0, new_currency(52500)));
+    l.add_transaction(Transaction::new_dep(3, 1, new_currency(1000000)));
+    l.add_transaction(Transaction::new_dis(12, 0));
+    let table = l.to_string();
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(vec![
+      "client  available    held     total  locked",
+      "     3   100.0000  0.0000  100.0000   false",
+      "    12     0.0000  5.2500    5.2500   false",
+    ], lines);
+    assert_eq!("client  available  held  total  locked\n", Ledger::new().to_string());
+  }
+  #[test]
+  fn to_csv_record_0() {
+    let summary = AccountSummary {
+      client: 7,
+      available: currency("1.23456"),
+      held: currency("2"),
+      effective_available: currency("1.23456"),
+      pending: Currency::zero_with_scale(),
+      total: currency("3.23456"),
+      locked: true,
+      scale: None,
+    };
+    assert_eq!(vec!["7", "1.2346", "2.0000", "3.2346", "true"], summary.to_csv_record(4));
+    assert_eq!(vec!["7", "1", "2", "3", "true"], summary.to_csv_record(0));
+    assert_eq!(summary.to_csv_record(CurrencyFormat::MAX_PRECISION), summary.to_csv_record(usize::MAX));
+    assert_eq!("1.234560000000000000", summary.to_csv_record(usize::MAX)[1]);
+  }
+  #[test]
+  fn apply_transaction_at_0() {
+    let mut l = Ledger::new();
+    // Given out of order, the withdrawal only succeeds if the deposit is applied first
+    assert!(l.apply_transaction_at(2, Transaction::new_dis(0, 0)));
+    assert!(l.apply_transaction_at(1, Transaction::new_wit(0, 1, new_currency(40000))));
+    assert_eq!(2, l.awaiting_arrivals());
+    assert_eq!(None, l.calculate_client_account_summary(0));
+    assert!(l.apply_transaction_at(0, Transaction::new_dep(0, 0, new_currency(100000))));
+    assert!(!l.apply_transaction_at(1, Transaction::new_dep(0, 3, new_currency(10000))));
+    assert_eq!(0, l.awaiting_arrivals());
+    l.add_transaction(Transaction::new_res(0, 0));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(60000), summary.available());
+    assert_eq!(&new_currency(60000), summary.total());
+    let indices: Vec<u64> = l.audit_trail().iter().map(|entry| entry.index).collect();
+    assert_eq!(vec![2, 3], indices);
+  }
+  #[test]
+  fn reset_dispute_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(20000)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    assert_eq!(&new_currency(20000), l.calculate_client_account_summary(0).unwrap().available());
+    assert!(l.reset_dispute(0));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(120000), summary.available());
+    assert_eq!(&Currency::zero_with_scale(), summary.held());
+    assert_eq!(DisputeState::None, l.txns[&0].dispute_state());
+    // Nothing left to reset, and an unknown transaction has nothing to reset
+    assert!(!l.reset_dispute(0));
+    assert!(!l.reset_dispute(9));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_cha(0, 1));
+    assert!(!l.reset_dispute(1));
+    assert_eq!(&new_currency(100000), l.calculate_client_account_summary(0).unwrap().total());
+  }
+  #[test]
+  fn total_model_0() {
+    use crate::ledger::TotalModel;
+    let totals = |total_model| {
+      let mut l = Ledger::new().with_total_model(total_model);
+      l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+      l.add_transaction(Transaction::new_wit(0, 1, new_currency(30000)));
+      l.add_transaction(Transaction::new_dis(0, 1));
+      let disputed = l.calculate_client_account_summary(0).unwrap().total;
+      l.add_transaction(Transaction::new_cha(0, 1));
+      let charged_back = l.calculate_client_account_summary(0).unwrap().total;
+      (disputed, charged_back)
+    };
+    assert_eq!((new_currency(100000), new_currency(100000)), totals(TotalModel::AvailablePlusHeld));
+    assert_eq!((new_currency(70000), new_currency(100000)), totals(TotalModel::DepositsMinusWithdrawals));
+  }
+  #[test]
+  fn disputable_transaction_ids_0() {
+    let mut l = Ledger::new().with_disputable_kinds(DisputableKinds::DepositsOnly);
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(20000)));
+    l.add_transaction(Transaction::new_wit(0, 2, new_currency(10000)));
+    l.add_transaction(Transaction::new_dep(1, 3, new_currency(10000)));
+    assert_eq!(vec![0, 1], l.disputable_transaction_ids(0));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    assert_eq!(vec![0], l.disputable_transaction_ids(0));
+    l.add_transaction(Transaction::new_res(0, 1));
+    assert_eq!(vec![0, 1], l.disputable_transaction_ids(0));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_cha(0, 1));
+    assert!(l.disputable_transaction_ids(0).is_empty());
+    assert_eq!(vec![3], l.disputable_transaction_ids(1));
+    assert!(l.disputable_transaction_ids(9).is_empty());
+  }
+  #[test]
+  fn max_scale_0() {
+    let mut l = Ledger::new().with_max_scale(4);
+    let mut unlimited = Ledger::new();
+    for txn_id in 0..5000 {
+      // Amounts from 0.1 down to 0.000000000001
+      let amount = Currency::new((txn_id % 97 + 1).into(), (txn_id % 12 + 1) as i64);
+      l.add_transaction(Transaction::new_dep(0, txn_id, amount.clone()));
+      unlimited.add_transaction(Transaction::new_dep(0, txn_id, amount));
+    }
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    let (digits, scale) = summary.available().as_bigint_and_exponent();
+    assert!(scale <= 4);
+    assert!(digits.to_string().len() <= 10);
+    assert_eq!(12, unlimited.calculate_client_account_summary(0).unwrap().available().as_bigint_and_exponent().1);
+    assert_eq!(Ok(()), l.integrity_check());
+    // Amounts are compared once rounded, so a stated amount matches the rounded deposit
+    l.add_transaction(Transaction::Referential(ReferentialTransaction::new_dis(0, 0).with_stated_amount(currency("0.100001"))));
+    assert!(l.txns[&0].disputed());
+  }
+  #[test]
+  fn reconcile_0() {
+    use crate::ledger::ReconcileReport;
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(1, 0, new_currency(15000)));
+    l.add_transaction(Transaction::new_dep(2, 1, new_currency(20000)));
+    let mut expected = l.calculate_all_account_summaries();
+    expected.sort_by_key(|summary| summary.client());
+    let mut reversed = expected.clone();
+    reversed.reverse();
+    assert!(l.reconcile(&reversed).is_ok());
+    let mut wrong = expected.clone();
+    wrong[0].held = new_currency(10000);
+    wrong[1].client = 3;
+    let report = l.reconcile(&wrong);
+    assert_eq!(ReconcileReport {
+      mismatches: vec![(wrong[0].clone(), expected[0].clone())],
+      missing: vec![3],
+      extra: vec![2],
+    }, report);
+    assert_eq!(
+      "Client 1: expected 1.5000, 1.0000, 1.5000, false but got 1.5000, 0.0000, 1.5000, false.\n\
+       Client 3: expected but not found.\n\
+       Client 2: found but not expected.\n",
+      report.to_string()
+    );
+  }
+  #[test]
+  fn lock_freezes_open_disputes_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(50000)));
+    l.add_transaction(Transaction::new_dep(0, 2, new_currency(20000)));
+    l.add_transaction(Transaction::new_dis(0, 0));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_cha(0, 0));
+    // The other dispute's funds stay held once the account locks
+    let expected = AccountSummary {
+      client: 0,
+      available: new_currency(20000),
+      held: new_currency(50000),
+      effective_available: new_currency(70000),
+      pending: new_currency(0),
+      total: new_currency(70000),
+      locked: true,
+      scale: None,
+    };
+    assert_eq!(Some(expected), l.calculate_client_account_summary(0));
+    l.add_transaction(Transaction::new_dep(0, 3, new_currency(10000)));
+    l.add_transaction(Transaction::new_res(0, 1));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(70000), summary.available());
+    assert_eq!(&new_currency(0), summary.held());
+    assert_eq!(&new_currency(70000), summary.total());
+    assert!(summary.locked());
+    assert_eq!(Ok(()), l.integrity_check());
+  }
+  #[test]
+  fn transactions_for_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 7, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(1, 8, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 3, new_currency(20000)));
+    l.add_transaction(Transaction::new_dep(0, 5, new_currency(50000)));
+    l.add_transaction(Transaction::new_dis(0, 5));
+    let mut disputed = BasicTransaction::new_dep(0, 5, new_currency(50000));
+    disputed.open_dispute();
+    assert_eq!(vec![
+      BasicTransaction::new_dep(0, 7, new_currency(100000)),
+      BasicTransaction::new_wit(0, 3, new_currency(20000)),
+      disputed,
+    ], l.transactions_for(0));
+    assert!(l.transactions_for(9).is_empty());
+  }
+  #[test]
+  fn statement_0() {
+    use crate::ledger::StatementLine;
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 9, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(1, 2, new_currency(100000)));
+    l.add_transaction(Transaction::new_wit(0, 3, new_currency(25000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(5000)));
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_wit(0, 4, new_currency(90000)));
+    l.add_transaction(Transaction::new_res(0, 1));
+    let line = |txn_id, kind, amount, balance_after| StatementLine {
+      txn_id,
+      kind,
+      amount: new_currency(amount),
+      balance_after: new_currency(balance_after),
+    };
+    // The overdrawing withdrawal was never applied, so isn't on the statement
+    assert_eq!(vec![
+      line(9, "deposit", 100000, 100000),
+      line(3, "withdrawal", 25000, 75000),
+      line(1, "deposit", 5000, 80000),
+      line(1, "dispute", 5000, 75000),
+      line(1, "resolve", 5000, 80000),
+    ], l.statement(0));
+    assert!(l.statement(7).is_empty());
+  }
+  #[test]
+  fn apply_batch_transactional_0() {
+    use crate::{ledger::StrictErrorKind, transactions::ValidationError};
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    let before = l.clone();
+    let result = l.apply_batch_transactional(vec![
+      Transaction::new_dep(0, 1, new_currency(50000)),
+      Transaction::new_dis(0, 0),
+      Transaction::new_dep(1, 2, new_currency(0)),
+      Transaction::new_dep(1, 3, new_currency(20000)),
+    ]);
+    assert_eq!(Err((2, StrictErrorKind::Invalid(ValidationError::NonPositiveAmount(new_currency(0))))), result);
+    assert_eq!(before, l);
+    let result = l.apply_batch_transactional(vec![
+      Transaction::new_wit(0, 1, new_currency(50000)),
+      Transaction::new_wit(0, 2, new_currency(60000)),
+    ]);
+    assert_eq!(Err((1, StrictErrorKind::Overdraft { client: 0, tx: 2 })), result);
+    assert_eq!(before, l);
+    assert_eq!(Ok(()), l.apply_batch_transactional(vec![
+      Transaction::new_dep(1, 1, new_currency(50000)),
+      Transaction::new_dis(0, 0),
+    ]));
+    assert_eq!(&new_currency(100000), l.calculate_client_account_summary(0).unwrap().held());
+    assert_eq!(&new_currency(50000), l.calculate_client_account_summary(1).unwrap().total());
+  }
+  #[test]
+  fn apply_batch_transactional_1() {
+    use crate::ledger::StrictErrorKind;
+    let mut l = Ledger::new().with_velocity_limit(1, new_currency(10000000));
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    // Waits for arrival 1, which the batch's first transaction takes
+    assert!(l.apply_transaction_at(2, Transaction::new_dep(2, 5, new_currency(100))));
+    let before = l.clone();
+    let result = l.apply_batch_transactional(vec![
+      Transaction::new_dep(0, 1, new_currency(50000)),
+      Transaction::new_dis(0, 0),
+      Transaction::new_res(0, 0),
+      Transaction::new_dis(0, 1),
+      Transaction::new_wit(3, 2, new_currency(100)),
+    ]);
+    assert_eq!(Err((4, StrictErrorKind::Overdraft { client: 3, tx: 2 })), result);
+    // The early arrival, flag, audit entries and anomalies are all rolled back with the balances
+    assert_eq!(before, l);
+    assert_eq!(1, l.awaiting_arrivals());
+    assert!(l.flagged_accounts().is_empty());
+  }
+  #[test]
+  fn deterministic_hasher_0() {
+    let build = || {
+      let mut l = Ledger::new().with_deterministic_hasher();
+      for txn_id in 0..200 {
+        l.add_transaction(Transaction::new_dep((txn_id % 37) as ClientId, txn_id, new_currency(100)));
+      }
+      l.add_transaction(Transaction::new_dis(3, 3));
+      l.add_transaction(Transaction::new_cha(3, 3));
+      l
+    };
+    let (a, b) = (build(), build());
+    assert!(a.txns.keys().eq(b.txns.keys()));
+    assert!(a.clients.keys().eq(b.clients.keys()));
+    assert!(a.locked_clients.iter().eq(b.locked_clients.iter()));
+    assert_eq!(a.calculate_all_account_summaries(), b.calculate_all_account_summaries());
+    // Switching hasher keeps the contents
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(1, 1, new_currency(100)));
+    assert_eq!(l.clone(), l.with_deterministic_hasher());
+  }
+  #[test]
+  fn compact_0() {
+    let mut l = Ledger::new().with_dispute_window(4);
+    l.add_transaction(Transaction::new_dep(1, 1, new_currency(100)));
+    l.add_transaction(Transaction::new_wit(1, 2, new_currency(30)));
+    l.add_transaction(Transaction::new_dep(2, 3, new_currency(50)));
+    l.add_transaction(Transaction::new_dis(2, 3));
+    l.add_transaction(Transaction::new_dep(1, 4, new_currency(20)));
+    l.add_transaction(Transaction::new_dep(2, 5, new_currency(10)));
+    l.add_transaction(Transaction::new_dep(1, 6, new_currency(5)));
+    // Too old to dispute, so this is ignored
+    l.add_transaction(Transaction::new_dis(1, 1));
+    let mut before = l.calculate_all_account_summaries();
+    SortKey::Client.sort(&mut before);
+    assert_eq!(l.transaction_count(), 6);
+    // Only 1 and 2 are outside the window; 3 is under dispute
+    assert_eq!(l.compact(), 2);
+    assert_eq!(l.transaction_count(), 4);
+    let mut after = l.calculate_all_account_summaries();
+    SortKey::Client.sort(&mut after);
+    assert_eq!(before, after);
+    assert_eq!(l.integrity_check(), Ok(()));
+    // Compacted ids can't be reused, and their disputes are still ignored
+    l.add_transaction(Transaction::new_dep(1, 1, new_currency(100)));
+    l.add_transaction(Transaction::new_dis(1, 2));
+    l.add_transaction(Transaction::new_cha(2, 3));
+    let mut after = l.calculate_all_account_summaries();
+    SortKey::Client.sort(&mut after);
+    assert_eq!(after[0], before[0]);
+    assert!(after[1].locked);
+  }
+  #[test]
+  fn replay_with_callback_0() {
+    let mut l = Ledger::new();
+    let mut seen = Vec::new();
+    let count = l.replay_from_csv_with_callback("testdata/spec_example.csv".into(), |client_id, summary| {
+      seen.push((client_id, summary.available().clone()));
+    }).unwrap();
+    assert_eq!(count, 5);
+    assert_eq!(seen, vec![
+      (1, new_currency(10000)),
+      (2, new_currency(20000)),
+      (1, new_currency(30000)),
+      (1, new_currency(15000)),
+      // The overdrawing withdrawal leaves the balance unchanged
+      (2, new_currency(20000)),
+    ]);
+  }
+  #[test]
+  fn hypothetical_disputes_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(1, 1, new_currency(100)));
+    l.add_transaction(Transaction::new_dep(1, 2, new_currency(50)));
+    l.add_transaction(Transaction::new_dep(2, 3, new_currency(20)));
+    l.add_transaction(Transaction::new_dis(1, 1));
+    let real = l.calculate_client_account_summary(1).unwrap();
+    assert_eq!((real.available(), real.held()), (&new_currency(50), &new_currency(100)));
+    // 1 is already disputed and 3 is another client's, so only 2 changes anything
+    let hypothetical = l.summary_with_hypothetical_disputes(1, &[1, 2, 3]).unwrap();
+    assert_eq!((hypothetical.available(), hypothetical.held()), (&new_currency(0), &new_currency(150)));
+    assert_eq!(hypothetical.total(), real.total());
+    // The ledger itself is untouched
+    assert_eq!(l.calculate_client_account_summary(1).unwrap(), real);
+    assert_eq!(l.disputable_transaction_ids(1), vec![2]);
+    assert_eq!(l.summary_with_hypothetical_disputes(9, &[1]), None);
+  }
+  #[test]
+  fn hypothetical_disputes_1() {
+    // Matches raising the same disputes for real, whatever the shortfall policy and total model
+    for dispute_shortfall in [DisputeShortfall::AllowNegative, DisputeShortfall::HoldAvailable].iter().copied() {
+      for total_model in [TotalModel::AvailablePlusHeld, TotalModel::DepositsMinusWithdrawals].iter().copied() {
+        let mut l = Ledger::new().with_dispute_shortfall(dispute_shortfall).with_total_model(total_model).with_dispute_window(4);
+        l.add_transaction(Transaction::new_dep(1, 1, new_currency(100)));
+        l.add_transaction(Transaction::new_dep(1, 2, new_currency(50)));
+        l.add_transaction(Transaction::new_wit(1, 3, new_currency(120)));
+        l.add_transaction(Transaction::new_dep(1, 4, new_currency(10)));
+        let hypothetical = l.summary_with_hypothetical_disputes(1, &[3, 1, 2, 4, 4]).unwrap();
+        // Each real dispute moves the window along, which the hypothetical ones don't
+        let mut real = l.clone().with_dispute_window(8);
+        for txn_id in [3, 1, 2, 4].iter().copied() {
+          real.add_transaction(Transaction::new_dis(1, txn_id));
+        }
+        assert_eq!(real.calculate_client_account_summary(1), Some(hypothetical));
+      }
+    }
+  }
+  #[test]
+  fn stale_disputes_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(1, 1, new_currency(100)));
+    l.add_transaction(Transaction::new_dep(2, 2, new_currency(100)));
+    l.add_transaction(Transaction::new_dep(1, 3, new_currency(100)));
+    l.add_transaction(Transaction::new_dis(2, 2));
+    l.add_transaction(Transaction::new_dis(1, 1));
+    l.add_transaction(Transaction::new_dis(1, 3));
+    l.add_transaction(Transaction::new_res(1, 3));
+    // A second dispute doesn't reset the age of one already open
+    l.add_transaction(Transaction::new_dis(2, 2));
+    let stale: Vec<(TxnId, u64)> = l.stale_disputes(4).into_iter().map(|(txn, opened)| (txn.txn_id(), opened)).collect();
+    assert_eq!(stale, vec![(2, 3)]);
+    let stale: Vec<(TxnId, u64)> = l.stale_disputes(8).into_iter().map(|(txn, opened)| (txn.txn_id(), opened)).collect();
+    assert_eq!(stale, vec![(2, 3), (1, 4)]);
+    assert!(l.stale_disputes(3).is_empty());
+    // Closed disputes are no longer stale
+    l.add_transaction(Transaction::new_cha(2, 2));
+    assert!(l.reset_dispute(1));
+    assert!(l.stale_disputes(u64::MAX).is_empty());
+  }
+  #[test]
+  fn fold_summaries_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(1, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dep(2, 2, new_currency(7500)));
+    l.add_transaction(Transaction::new_dis(2, 2));
+    l.add_transaction(Transaction::new_wit(0, 3, new_currency(500)));
+    let available = l.fold_summaries(Currency::zero_with_scale(), |total, summary| total + summary.available());
+    assert_eq!(new_currency(152000), available);
+    assert_eq!(0, Ledger::new().fold_summaries(0, |count, _| count + 1));
+  }
+  #[test]
+  fn dispute_lifecycle_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(0, 1, new_currency(50000)));
+    // Charging back a resolved dispute
+    l.add_transaction(Transaction::new_dis(0, 0));
+    l.add_transaction(Transaction::new_res(0, 0));
+    assert_eq!(ApplyOutcome::InvalidDisputeTransition, l.simulate(&Transaction::new_cha(0, 0)));
+    l.add_transaction(Transaction::new_cha(0, 0));
+    assert!(!l.is_locked(0));
+    assert_eq!(&new_currency(150000), l.calculate_client_account_summary(0).unwrap().available());
+    // Resolving a charged back dispute
+    l.add_transaction(Transaction::new_dis(0, 1));
+    l.add_transaction(Transaction::new_cha(0, 1));
+    assert_eq!(ApplyOutcome::InvalidDisputeTransition, l.simulate(&Transaction::new_res(0, 1)));
+    l.add_transaction(Transaction::new_res(0, 1));
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(&new_currency(100000), summary.available());
+    assert_eq!(&new_currency(0), summary.held());
+    assert_eq!(vec![
+      Anomaly::InvalidDisputeTransition { txn: ReferentialTransaction::new_cha(0, 0), state: DisputeState::None },
+      Anomaly::InvalidDisputeTransition { txn: ReferentialTransaction::new_res(0, 1), state: DisputeState::ChargedBack },
+    ], l.detailed_summaries()[0].anomalies);
+  }
+  #[test]
+  fn txn_id_reused_across_clients_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(1, 0, new_currency(52500)));
+    l.add_transaction(Transaction::new_wit(1, 0, new_currency(500)));
+    assert_eq!(0, l.txns.get(&0).unwrap().client_id());
+    assert_eq!(new_currency(100000), *l.calculate_client_account_summary(0).unwrap().total());
+    assert_eq!(new_currency(0), *l.calculate_client_account_summary(1).unwrap().total());
+    let detailed = l.detailed_summaries();
+    let anomalies = &detailed.iter().find(|d| d.summary.client() == 1).unwrap().anomalies;
+    assert_eq!(&vec![Anomaly::DuplicateTxnId { txn_id: 0 }; 2], anomalies);
+  }
+  #[test]
+  fn dispute_other_clients_txn_0() {
+    let mut l = Ledger::new();
+    l.add_transaction(Transaction::new_dep(0, 0, new_currency(100000)));
+    l.add_transaction(Transaction::new_dep(1, 1, new_currency(52500)));
+    l.add_transaction(Transaction::new_dis(1, 0));
+    l.add_transaction(Transaction::new_cha(1, 0));
+    assert!(!l.txns.get(&0).unwrap().disputed());
+    assert!(l.locked_client_ids().is_empty());
+    let summary = l.calculate_client_account_summary(0).unwrap();
+    assert_eq!(new_currency(100000), *summary.available());
+    assert_eq!(new_currency(0), *summary.held());
+  }
+}
+
+#[cfg(test)]
+mod ledger_properties {
+  use super::*;
+  use proptest::prelude::*;
+  use crate::{
+    transactions::{BasicTransaction, Transaction},
+    ledger::{DisputeShortfall, Ledger}
+  };
+
+  #[derive(Clone, Debug)]
+  enum Op {
+    Deposit(ClientId, u32),
+    Withdrawal(ClientId, u32),
+    Dispute(usize),
+    Resolve(usize),
+    Chargeback(usize),
+  }
+
+  fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+      3 => (0..3 as ClientId, 1..50000u32).prop_map(|(client_id, amount)| Op::Deposit(client_id, amount)),
+      2 => (0..3 as ClientId, 1..50000u32).prop_map(|(client_id, amount)| Op::Withdrawal(client_id, amount)),
+      2 => any::<usize>().prop_map(Op::Dispute),
+      1 => any::<usize>().prop_map(Op::Resolve),
+      1 => any::<usize>().prop_map(Op::Chargeback),
+    ]
+  }
+
+  /// Turns generated operations into a valid stream of transactions, where referential
+  /// transactions target an earlier deposit or withdrawal.
+  fn transactions(ops: &[Op]) -> Vec<Transaction> {
+    let mut basic: Vec<(ClientId, TxnId)> = Vec::new();
+    let mut txns = Vec::new();
+    for (i, op) in ops.iter().enumerate() {
+      let txn_id = i as TxnId;
+      match *op {
+        Op::Deposit(client_id, amount) => {
+          basic.push((client_id, txn_id));
+          txns.push(Transaction::new_dep(client_id, txn_id, new_currency(amount)));
+        },
+        Op::Withdrawal(client_id, amount) => {
+          basic.push((client_id, txn_id));
+          txns.push(Transaction::new_wit(client_id, txn_id, new_currency(amount)));
+        },
+        Op::Dispute(target) | Op::Resolve(target) | Op::Chargeback(target) if !basic.is_empty() => {
+          let (client_id, txn_id) = basic[target % basic.len()];
+          txns.push(match op {
+            Op::Dispute(_) => Transaction::new_dis(client_id, txn_id),
+            Op::Resolve(_) => Transaction::new_res(client_id, txn_id),
+            _ => Transaction::new_cha(client_id, txn_id),
+          });
+        },
+        _ => {},
+      }
+    }
+    txns
+  }
+
+  fn ledger_from(ops: &[Op]) -> Ledger {
+    let mut l = Ledger::new();
+    for txn in transactions(ops) {
+      l.add_transaction(txn);
+    }
+    l
+  }
+
+  proptest! {
+    #[test]
+    fn money_is_conserved(ops in prop::collection::vec(op(), 0..60)) {
+      let l = ledger_from(&ops);
+      // Retained transactions are exactly those applied, charged back ones having been reversed
+      let mut expected = Currency::zero_with_scale();
+      for txn in l.txns.values().filter(|txn| !txn.charged_back()) {
+        match txn {
+          BasicTransaction::Deposit { amount, .. } => expected += amount,
+          // Disputed withdrawals are held on top of what remains in the account
+          BasicTransaction::Withdrawal { .. } if txn.disputed() => {},
+          BasicTransaction::Withdrawal { amount, .. } => expected -= amount,
+        }
+      }
+      let total = l.summaries().fold(Currency::zero_with_scale(), |total, summary| total + summary.total);
+      prop_assert_eq!(expected, total);
+    }
+    #[test]
+    fn integrity_holds(ops in prop::collection::vec(op(), 0..60)) {
+      prop_assert_eq!(Ok(()), ledger_from(&ops).integrity_check());
+    }
+    #[test]
+    fn hold_available_keeps_available_non_negative(ops in prop::collection::vec(op(), 0..60)) {
+      let mut l = Ledger::new().with_dispute_shortfall(DisputeShortfall::HoldAvailable);
+      for txn in transactions(&ops) {
+        l.add_transaction(txn);
+      }
+      prop_assert_eq!(Ok(()), l.integrity_check());
+      for summary in &l {
+        prop_assert!(summary.available >= Currency::zero_with_scale());
+      }
+    }
+    #[test]
+    fn parsing_never_panics(lines in prop::collection::vec(
+      "(deposit|withdrawal|dispute|resolve|chargeback|adjustment|[a-z]{0,8}),[0-9 -]{0,7},[0-9 ]{0,11},[0-9eE.+ -]{0,24}",
+      0..20
+    )) {
+      use crate::csv_handlers::TransactionReader;
+      let input = format!("type,client,tx,amount\n{}", lines.join("\n"));
+      let mut reader = TransactionReader::from_bytes(input.into_bytes()).with_scientific_amounts(true);
+      let mut l = Ledger::new();
+      while !reader.is_done() {
+        if let Ok(transaction) = reader.transaction() {
+          l.add_transaction(transaction);
+        }
+      }
+      prop_assert_eq!(Ok(()), l.integrity_check());
+    }
+    #[test]
+    fn held_is_never_negative(ops in prop::collection::vec(op(), 0..60)) {
+      let l = ledger_from(&ops);
+      for summary in &l {
+        prop_assert!(summary.held >= Currency::zero_with_scale());
+        prop_assert_eq!(&summary.available + &summary.held, summary.total);
+      }
+    }
+    #[test]
+    fn withdrawals_need_available_funds_on_arrival(ops in prop::collection::vec(op(), 0..60)) {
+      let mut l = Ledger::new();
+      for txn in transactions(&ops) {
+        let client_id = txn.client_id();
+        let before = l.calculate_client_account_summary(client_id).map(|summary| summary.available);
+        let withdrawal = if let Transaction::Basic(BasicTransaction::Withdrawal { amount, .. }) = &txn { Some(amount.clone()) } else { None };
+        let txn_id = txn.txn_id();
+        l.add_transaction(txn);
+        if let Some(amount) = withdrawal {
+          let affordable = before.is_some_and(|available| amount <= available);
+          prop_assert_eq!(affordable && !l.is_locked(client_id), l.txns.contains_key(&txn_id));
+        }
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod account_summary_tests {
+  use super::*;
+  use crate::ledger::AccountSummary;
+  #[test]
+  fn new_0() {
+      let actual = AccountSummary::new();
+      let expected = AccountSummary {
+        client: 0,
+        available: Currency::zero_with_scale(),
+        held: Currency::zero_with_scale(),
+        effective_available: Currency::zero_with_scale(),
+        pending: Currency::zero_with_scale(),
+        total: Currency::zero_with_scale(),
+        locked: false,
+        scale: None,
+      };
+      assert_eq!(actual, expected);
+  }
+  #[test]
+  fn getters_0() {
+      let mut summary = AccountSummary::new();
+      summary.client = 3;
+      summary.available = new_currency(10000);
+      summary.held = new_currency(20000);
+      summary.total = new_currency(30000);
+      summary.locked = true;
+      assert_eq!(3, summary.client());
+      assert_eq!(&new_currency(10000), summary.available());
+      assert_eq!(&new_currency(20000), summary.held());
+      assert_eq!(&Currency::zero_with_scale(), summary.pending());
+      assert_eq!(&new_currency(30000), summary.total());
+      assert!(summary.locked());
+  }
+  #[test]
+  fn zero_scale_0() {
+      let summary = AccountSummary::new();
+      let zeros = [
+        Currency::zero_with_scale(),
+        new_currency(0),
+        summary.available,
+        summary.held,
+        summary.total,
+      ];
+      for zero in &zeros {
+        assert_eq!(Currency::from(0), *zero);
+        assert_eq!(CURRENCY_SCALE, zero.as_bigint_and_exponent().1);
+        assert_eq!("0.0000", zero.to_string());
+      }
+  }
+  #[test]
+  fn approx_eq_0() {
+      let mut summary = AccountSummary::new();
+      summary.available = currency("1.5");
+      summary.total = currency("1.5");
+      let mut rescaled = summary.clone();
+      rescaled.available = new_currency(15000);
+      // Equal in value, but not in how they're displayed until normalized
+      assert_eq!(summary, rescaled);
+      assert_ne!(summary.available().to_string(), rescaled.available().to_string());
+      assert!(summary.approx_eq(&rescaled));
+      let (summary, rescaled) = (summary.normalized(CURRENCY_SCALE), rescaled.normalized(CURRENCY_SCALE));
+      assert_eq!(summary.available().to_string(), rescaled.available().to_string());
+      assert_eq!(summary.total().to_string(), rescaled.total().to_string());
+      // Differing beyond the output scale, so different in value but output the same
+      let mut finer = summary.clone();
+      finer.available = currency("1.50001");
+      assert_ne!(summary, finer);
+      assert!(summary.approx_eq(&finer));
+      let mut different = summary.clone();
+      different.available = currency("1.5001");
+      assert_ne!(summary, different);
+      assert!(!summary.approx_eq(&different));
+      different = summary.clone();
+      different.locked = true;
+      assert!(!summary.approx_eq(&different));
+  }
+}
+
+#[cfg(test)]
+mod end2end {
+  use super::*;
+  use std::convert::TryInto;
+  use crate::{csv_handlers::{read_account_states, TransactionReader}, ledger::Ledger, transactions::Transaction};
+  #[test]
+  fn many_clients() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file("testdata/many_clients.csv".into())?;
+    let mut l = Ledger::new();
+    while !reader.is_done() {
+      if let Ok(record) = reader.record() {
+        if let Ok(transaction) = record.try_into() {
+          l.add_transaction(transaction);
+        }
+      }
+    }
+    assert_eq!(999, l.txns.len());
+    assert_eq!(999, l.clients.len());
+    for txn_ids in l.clients.values() {
+      assert_eq!(1, txn_ids.len());
+    }
+    assert!(l.locked_client_ids().is_empty());
+    Ok(())
+  }
+  #[test]
+  fn disputes() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file("testdata/disputes.csv".into())?;
+    let mut l = Ledger::new();
+    while !reader.is_done() {
+      if let Ok(record) = reader.record() {
+        if let Ok(transaction) = record.try_into() {
+          l.add_transaction(transaction);
+        }
+      }
+    }
+    assert_eq!(27, l.txns.len());
+    assert_eq!(9, l.clients.len());
+    for txn_ids in l.clients.values() {
+      assert_eq!(3, txn_ids.len());
+    }
+    assert!(l.locked_client_ids().is_empty());
+    for summary in l.calculate_all_account_summaries() {
+      // I'd love to assert the client_ids are correct, but can't guarantee ordering
+      assert_eq!(&currency("5.5555"), summary.available());
+      // Balances are tracked at scale 4, so they also print exactly as expected
+      assert_eq!("5.5555", summary.available().to_string());
+      assert_eq!(&currency("10.0"), summary.held());
+      assert_eq!(&currency("15.5555"), summary.total());
+      assert!(!summary.locked());
+    }
+    Ok(())
+  }
+  #[test]
+  fn chargeback() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file("testdata/chargeback.csv".into())?;
+    let mut l = Ledger::new();
+    while !reader.is_done() {
+      if let Ok(record) = reader.record() {
+        if let Ok(transaction) = record.try_into() {
+          l.add_transaction(transaction);
+        }
+      }
+    }
+    // The charged back transaction is kept, marked as charged back
+    assert_eq!(4, l.txns.len());
+    assert!(l.txns.get(&1).unwrap().charged_back());
+    assert_eq!(1, l.clients.len());
+    for txn_ids in l.clients.values() {
+      assert_eq!(4, txn_ids.len());
+    }
+    assert_eq!(vec![1], l.locked_client_ids());
+    assert!(!l.is_locked(2));
+    for summary in l.calculate_all_account_summaries() {
+      // If the chargeback didn't lock the account and prevent the final
+      // deposit, available would have been 7
+      assert_eq!(&currency("6.0000"), summary.available());
+      assert_eq!(&currency("0.0"), summary.held());
+      assert_eq!(&currency("6.0000"), summary.total());
+      assert_eq!("6.0000", summary.total().to_string());
+      assert!(summary.locked());
+    }
+    Ok(())
+  }
+  #[test]
+  fn malformed_rows() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file("testdata/malformed_rows.csv".into())?;
+    let mut l = Ledger::new();
+    while !reader.is_done() {
+      if let Ok(record) = reader.record() {
+        if let Ok(transaction) = record.try_into() {
+          l.add_transaction(transaction);
+        }
+      }
+    }
+    assert_eq!(3, l.txns.len());
+    let summary = l.calculate_client_account_summary(1).unwrap();
+    assert_eq!(&currency("2.5"), summary.available());
+    assert_eq!(&currency("2.5"), summary.total());
+    Ok(())
+  }
+  #[test]
+  fn replay_from_dir() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("transaction_processor_replay_{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    // If 02 were replayed first the deposit would be accepted and the chargeback would be a no-op
+    std::fs::write(dir.join("01.csv"), "type,client,tx,amount\ndeposit,1,1,10\ndispute,1,1,\n")?;
+    std::fs::write(dir.join("02.csv"), "type,client,tx,amount\nchargeback,1,1,\ndeposit,1,2,5\n")?;
+    std::fs::write(dir.join("00.txt"), "type,client,tx,amount\ndeposit,2,3,10\n")?;
+    let mut l = Ledger::new();
+    let result = l.replay_from_dir(dir.clone());
+    std::fs::remove_dir_all(&dir)?;
+    result?;
+    assert_eq!(1, l.clients.len());
+    assert!(l.is_locked(1));
+    let summary = l.calculate_client_account_summary(1).unwrap();
+    assert_eq!(&Currency::from(0), summary.available());
+    assert_eq!(&Currency::from(0), summary.total());
+    Ok(())
+  }
+  #[test]
+  fn comments() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    assert_eq!(3, l.replay_from_csv("testdata/comments.csv".into())?);
+    let summary = l.calculate_client_account_summary(1).unwrap();
+    assert_eq!(&currency("12.5"), summary.available());
+    assert_eq!(&currency("12.5"), summary.total());
+    assert!(!l.txns.contains_key(&3));
+    Ok(())
+  }
+  #[test]
+  fn seed_from_account_states() -> Result<(), Box<dyn std::error::Error>> {
+    let states = read_account_states("testdata/account_states.csv".into())?;
+    let mut l = Ledger::from_account_states(states);
+    l.add_transaction(Transaction::new_dep(1, 1, Currency::from(2)));
+    l.add_transaction(Transaction::new_dep(2, 2, Currency::from(2)));
+    assert_eq!(3, l.clients.len());
+    let summary = l.calculate_client_account_summary(1).unwrap();
+    assert_eq!(&currency("12.5"), summary.available());
+    assert!(!summary.locked());
+    let summary = l.calculate_client_account_summary(2).unwrap();
+    assert_eq!(&Currency::from(3), summary.available());
+    assert_eq!(&Currency::from(1), summary.held());
+    assert_eq!(&Currency::from(4), summary.total());
+    assert!(summary.locked());
+    Ok(())
+  }
+  #[test]
+  fn summary_filters() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    l.replay_from_csv("testdata/summary_filters.csv".into())?;
+    let filtered_clients = |args: &[&str]| -> Result<Vec<ClientId>, Box<dyn std::error::Error>> {
+      let args = crate::cli::CliArgs::parse(args.iter().map(|arg| arg.to_string()))?;
+      let mut summaries = l.calculate_all_account_summaries();
+      summaries.retain(|summary| args.keeps(summary));
+      let mut clients: Vec<ClientId> = summaries.iter().map(|summary| summary.client()).collect();
+      clients.sort_unstable();
+      Ok(clients)
+    };
+    assert_eq!(vec![1, 2, 3, 4], filtered_clients(&["in.csv"])?);
+    assert_eq!(vec![1, 4], filtered_clients(&["in.csv", "--nonzero-only"])?);
+    assert_eq!(vec![3, 4], filtered_clients(&["in.csv", "--locked-only"])?);
+    assert_eq!(vec![4], filtered_clients(&["in.csv", "--nonzero-only", "--locked-only"])?);
+    Ok(())
+  }
+  #[test]
+  fn scientific_amounts() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    assert_eq!(1, l.replay_from_csv("testdata/scientific.csv".into())?);
+    assert_eq!(&currency("2.5"), l.calculate_client_account_summary(1).unwrap().total());
+    assert_eq!(None, l.calculate_client_account_summary(2));
+    let mut l = Ledger::new().with_scientific_amounts(true);
+    assert_eq!(3, l.replay_from_csv("testdata/scientific.csv".into())?);
+    assert_eq!(&currency("1502.5"), l.calculate_client_account_summary(1).unwrap().total());
+    assert_eq!(&Currency::from(10), l.calculate_client_account_summary(2).unwrap().total());
+    Ok(())
+  }
+  #[test]
+  fn enabled_features() {
+    // Every feature in the manifest is listed, so none can be left out of `enabled_features`
+    let manifest = include_str!("../Cargo.toml");
+    let mut declared: Vec<&str> = manifest.split("[features]").nth(1).unwrap_or_default()
+      .lines()
+      .take_while(|line| !line.starts_with('['))
+      .filter_map(|line| line.split_once(" = ").map(|(name, _)| name))
+      .collect();
+    let mut listed: Vec<&str> = crate::FEATURES.iter().map(|(name, _)| *name).collect();
+    declared.sort_unstable();
+    listed.sort_unstable();
+    assert_eq!(declared, listed);
+  }
+  #[cfg(not(any(
+    feature = "bincode", feature = "serde_json", feature = "memmap2", feature = "ureq",
+    feature = "rayon", feature = "flate2", feature = "wide_ids", feature = "sled"
+  )))]
+  #[test]
+  fn default_features() {
+    assert!(crate::enabled_features().is_empty());
+  }
+  #[cfg(feature = "sled")]
+  #[test]
+  fn disk_index_matches_memory() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::ledger::SortKey;
+    let dir = std::env::temp_dir().join(format!("transaction_processor_disk_index_{}", std::process::id()));
+    let mut in_memory = Ledger::new();
+    in_memory.replay_from_csv("testdata/disputes.csv".into())?;
+    let mut on_disk = Ledger::new().with_disk_index(dir.clone())?;
+    let replayed = on_disk.replay_from_csv("testdata/disputes.csv".into());
+    let (mut expected, mut actual) = (in_memory.calculate_all_account_summaries(), on_disk.calculate_all_account_summaries());
+    let (open_disputes, integrity) = (on_disk.open_disputes().len(), on_disk.integrity_check());
+    let held_in_memory = on_disk.txns.len();
+    drop(on_disk);
+    std::fs::remove_dir_all(&dir)?;
+    replayed?;
+    SortKey::Client.sort(&mut expected);
+    SortKey::Client.sort(&mut actual);
+    assert_eq!(expected, actual);
+    assert_eq!(in_memory.open_disputes().len(), open_disputes);
+    assert_eq!(Ok(()), integrity);
+    assert!(held_in_memory < in_memory.txns.len());
+    Ok(())
+  }
+  #[cfg(feature = "sled")]
+  #[test]
+  fn disk_index_batch_rollback() -> Result<(), Box<dyn std::error::Error>> {
+    let dir = std::env::temp_dir().join(format!("transaction_processor_disk_batch_{}", std::process::id()));
+    let mut l = Ledger::new().with_disk_index(dir.clone())?;
+    let replayed = l.replay_from_csv("testdata/disputes.csv".into());
+    let disputable = l.disputable_transaction_ids(1);
+    let before = (l.calculate_all_account_summaries(), l.txns.clone(), l.audit_trail().to_vec());
+    let result = l.apply_batch_transactional(vec![
+      Transaction::new_dep(1, 1000, Currency::from(5)),
+      Transaction::new_dis(1, disputable[0]),
+      Transaction::new_dis(1, 1001),
+    ]);
+    let after = (l.calculate_all_account_summaries(), l.txns.clone(), l.audit_trail().to_vec());
+    let still_disputable = l.disputable_transaction_ids(1);
+    drop(l);
+    std::fs::remove_dir_all(&dir)?;
+    replayed?;
+    assert!(result.is_err());
+    assert_eq!(before, after);
+    // The disputed transaction went back to the index rather than staying in memory
+    assert_eq!(disputable, still_disputable);
+    assert!(!before.1.contains_key(&disputable[0]));
+    Ok(())
+  }
+  #[cfg(feature = "bincode")]
+  #[test]
+  fn bincode_round_trip() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new().with_disputable_kinds(crate::ledger::DisputableKinds::DepositsOnly);
+    l.replay_from_csv("testdata/disputes.csv".into())?;
+    l.add_transaction(Transaction::new_cha(1, 1));
+    l.add_transaction(Transaction::new_wit(2, 100, Currency::from(1000)));
+    let mut bytes = Vec::new();
+    l.save_bincode(&mut bytes)?;
+    let loaded = Ledger::load_bincode(bytes.as_slice())?;
+    assert_eq!(l, loaded);
+    assert!(loaded.diff(&l).is_empty());
+    Ok(())
+  }
+  #[cfg(feature = "serde_json")]
+  #[test]
+  fn json_matches_csv() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::csv_handlers::InputFormat;
+    let mut from_csv = Ledger::new();
+    from_csv.replay_from_csv("testdata/spec_example.csv".into())?;
+    let mut from_json = Ledger::new();
+    assert_eq!(from_json.replay_from_csv("testdata/spec_example.json".into())?, 5);
+    assert_eq!(from_csv, from_json);
+    // Recognised as JSON from the contents rather than the extension
+    let mut from_objects = Ledger::new();
+    assert_eq!(from_objects.replay_from_csv("testdata/spec_example_objects.txt".into())?, 5);
+    assert_eq!(from_csv, from_objects);
+    // Forcing csv reads every object as a malformed row
+    let mut forced = Ledger::new().with_input_format(InputFormat::Csv);
+    assert_eq!(forced.replay_from_csv("testdata/spec_example_objects.txt".into())?, 0);
+    Ok(())
+  }
+  #[test]
+  fn replay_strict() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::ledger::{ApplyOutcome, StrictError, StrictErrorKind};
+    let mut l = Ledger::new();
+    let error = l.replay_from_csv_strict("testdata/strict_bad_row.csv".into()).unwrap_err();
+    assert_eq!(5, error.line);
+    assert!(matches!(error.kind, StrictErrorKind::Malformed(_)));
+    // Everything before the bad row was applied
+    assert_eq!(&currency("7.5"), l.calculate_client_account_summary(1).unwrap().total());
+    let error = Ledger::new().replay_from_csv_strict("testdata/strict_dangling.csv".into()).unwrap_err();
+    assert_eq!(StrictError { line: 4, kind: StrictErrorKind::Rejected(ApplyOutcome::DanglingReference) }, error);
+    let error = Ledger::new().replay_from_csv_strict("testdata/malformed_rows.csv".into()).unwrap_err();
+    assert_eq!(3, error.line);
+    assert_eq!(Ok(36), Ledger::new().replay_from_csv_strict("testdata/disputes.csv".into()));
+    let error = Ledger::new().replay_from_csv_strict("testdata/chargeback.csv".into()).unwrap_err();
+    assert_eq!(StrictError { line: 10, kind: StrictErrorKind::Rejected(ApplyOutcome::AccountLocked) }, error);
+    let error = Ledger::new().replay_from_csv_strict("testdata/strict_overdraft.csv".into()).unwrap_err();
+    assert_eq!(StrictError { line: 4, kind: StrictErrorKind::Overdraft { client: 2, tx: 3 } }, error);
+    assert_eq!("Line 4: withdrawal 3 overdraws client 2.", error.to_string());
+    let error = Ledger::new().replay_from_csv_strict("testdata/negative_total.csv".into()).unwrap_err();
+    assert_eq!(StrictError { line: 5, kind: StrictErrorKind::NegativeTotal(1) }, error);
+    Ok(())
+  }
+  #[cfg(feature = "memmap2")]
+  #[test]
+  fn mmap_matches_buffered() -> Result<(), Box<dyn std::error::Error>> {
+    let mut summaries = Vec::new();
+    for mut reader in [
+      TransactionReader::from_file("testdata/interleaved.csv".into())?,
+      TransactionReader::from_mmap_file("testdata/interleaved.csv".into())?,
+    ] {
+      let mut l = Ledger::new();
+      while !reader.is_done() {
+        if let Ok(transaction) = reader.transaction() {
+          l.add_transaction(transaction);
+        }
+      }
+      let mut ledger_summaries = l.calculate_all_account_summaries();
+      ledger_summaries.sort_by_key(|summary| summary.client());
+      summaries.push(ledger_summaries);
+    }
+    assert_eq!(5, summaries[0].len());
+    assert_eq!(summaries[0], summaries[1]);
+    Ok(())
+  }
+  #[cfg(feature = "ureq")]
+  #[test]
+  fn url_matches_file() -> Result<(), Box<dyn std::error::Error>> {
+    use std::{io::{BufRead, BufReader, Write}, net::TcpListener};
+    // Serves the testdata directory over plain http, one request per connection
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    std::thread::spawn(move || {
+      for mut stream in listener.incoming().flatten() {
+        let mut request = String::new();
+        let mut reader = BufReader::new(&stream);
+        let _ = reader.read_line(&mut request);
+        let mut line = String::new();
+        while reader.read_line(&mut line).is_ok_and(|read| read > 2) {
+          line.clear();
+        }
+        let path = request.split_whitespace().nth(1).unwrap_or_default().trim_start_matches('/');
+        let response = match std::fs::read(format!("testdata/{}", path)) {
+          Ok(body) => [format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n", body.len()).into_bytes(), body].concat(),
+          Err(_) => b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_vec(),
+        };
+        let _ = stream.write_all(&response);
+      }
+    });
+    let mut from_file = Ledger::new();
+    let mut from_url = Ledger::new();
+    assert_eq!(
+      from_file.replay_from_csv("testdata/interleaved.csv".into())?,
+      from_url.replay_from_url(&format!("http://{}/interleaved.csv", addr))?
+    );
+    let mut expected = from_file.calculate_all_account_summaries();
+    let mut actual = from_url.calculate_all_account_summaries();
+    expected.sort_by_key(|summary| summary.client());
+    actual.sort_by_key(|summary| summary.client());
+    assert_eq!(5, actual.len());
+    assert_eq!(expected, actual);
+    assert!(TransactionReader::from_url(&format!("http://{}/missing.csv", addr)).is_err());
+    Ok(())
+  }
+  #[test]
+  fn sharded_matches_single() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::sharded::ShardedLedger;
+    let mut reader = TransactionReader::from_file("testdata/interleaved.csv".into())?;
+    let mut transactions = Vec::new();
+    while !reader.is_done() {
+      if let Ok(transaction) = reader.transaction() {
+        transactions.push(transaction);
+      }
+    }
+    let mut l = Ledger::new();
+    for transaction in transactions.clone() {
+      l.add_transaction(transaction);
+    }
+    let mut expected = l.calculate_all_account_summaries();
+    expected.sort_by_key(|summary| summary.client());
+    // More threads than shards, so threads contend for them. Each thread takes whole clients, so
+    // every client's transactions are still added in order.
+    let (threads, sharded) = (3, ShardedLedger::new(2));
+    std::thread::scope(|scope| {
+      for thread in 0..threads {
+        let (sharded, transactions) = (&sharded, &transactions);
+        scope.spawn(move || {
+          for transaction in transactions.iter().filter(|txn| txn.client_id() as usize % threads == thread) {
+            sharded.add_transaction(transaction.clone());
+          }
+        });
+      }
+    });
+    assert_eq!(expected, sharded.calculate_all_account_summaries());
+    // Ids are unique across shards too. Added after the threads finish, so it's known which
+    // client used the id first.
+    let (first, second) = (Transaction::new_dep(90, 900, new_currency(10000)), Transaction::new_dep(91, 900, new_currency(20000)));
+    for transaction in [first, second] {
+      l.add_transaction(transaction.clone());
+      sharded.add_transaction(transaction);
+    }
+    let mut expected = l.calculate_all_account_summaries();
+    expected.sort_by_key(|summary| summary.client());
+    assert_eq!(expected, sharded.calculate_all_account_summaries());
+    assert_eq!(&currency("0"), l.calculate_client_account_summary(91).unwrap().total());
+    Ok(())
+  }
+  #[test]
+  fn interleaved_disputes_and_withdrawals() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    l.replay_from_csv("testdata/interleaved.csv".into())?;
+    // (client, available, held, locked), worked through by hand in arrival order
+    let expected = [
+      // A withdrawal made while a deposit is disputed only draws on the undisputed funds
+      (1, "11", "0", false),
+      // A withdrawal rejected while a deposit is disputed stays rejected after its chargeback
+      (2, "15", "0", true),
+      // A disputed withdrawal's funds are held until resolved, then stay withdrawn
+      (3, "7", "0", false),
+      // A dispute arriving before its deposit is ignored, and a charged back withdrawal is returned
+      (4, "2.5", "0", true),
+      // A withdrawal rejected while a deposit is disputed can be made once the dispute is resolved
+      (5, "7", "0", false),
+    ];
+    for (client, available, held, locked) in expected {
+      let summary = l.calculate_client_account_summary(client).unwrap();
+      assert_eq!(&currency(available), summary.available(), "client {}", client);
+      assert_eq!(&currency(held), summary.held(), "client {}", client);
+      assert_eq!(&(currency(available) + currency(held)), summary.total(), "client {}", client);
+      assert_eq!(locked, summary.locked(), "client {}", client);
+    }
+    // Rebuilding the balances from the stored transactions gives the same result
+    assert_eq!(Ok(()), l.integrity_check());
+    Ok(())
+  }
+  #[test]
+  fn repeated_header() -> Result<(), Box<dyn std::error::Error>> {
+    let mut reader = TransactionReader::from_file("testdata/repeated_header.csv".into())?;
+    let mut transactions = 0;
+    while !reader.is_done() {
+      match reader.transaction() {
+        Ok(_) => transactions += 1,
+        Err(e) => assert!(reader.is_done(), "{}", e),
+      }
+    }
+    assert_eq!(4, transactions);
+    assert_eq!(0, reader.skipped_rows());
+    let records = csv::Reader::from_path("testdata/repeated_header.csv")?.into_records().collect::<Result<Vec<_>, _>>()?;
+    let stats = Ledger::new().process_stream(records);
+    assert_eq!(4, stats.records);
+    assert_eq!(0, stats.parse_errors);
+    Ok(())
+  }
+  #[test]
+  fn output_rounded() -> Result<(), Box<dyn std::error::Error>> {
+    use crate::{csv_handlers::{write_as_csv, Column}, ledger::SortKey};
+    let mut l = Ledger::new();
+    l.replay_from_csv("testdata/precise_amounts.csv".into())?;
+    let mut summaries = l.calculate_all_account_summaries();
+    SortKey::Client.sort(&mut summaries);
+    let mut out = Vec::new();
+    write_as_csv(&mut out, summaries, &Column::ALL)?;
+    assert_eq!("client,available,held,total,locked\n1,1.2346,0.0000,1.2346,false\n2,1.5001,0.0000,1.5001,false\n", String::from_utf8(out)?);
+    Ok(())
+  }
+  #[test]
+  fn process_stream() {
+    use csv::StringRecord;
+    use crate::ledger::ProcessingStats;
+    let records = vec![
+      StringRecord::from(vec!["deposit", "1", "1", "10.0"]),
+      StringRecord::from(vec!["deposit", "2", "2", "5.0"]),
+      StringRecord::from(vec!["withdrawal", "1", "3", "2.5"]),
+      StringRecord::from(vec!["withdrawal", "2", "4", "50.0"]),
+      StringRecord::from(vec!["refund", "1", "5", "1.0"]),
+      StringRecord::from(vec!["dispute", "2", "2", ""]),
+      StringRecord::from(vec!["chargeback", "1", "9", ""]),
+      StringRecord::from(vec!["deposit", "x", "6", "1.0"]),
+    ];
+    let mut l = Ledger::new();
+    let stats = l.process_stream(records);
+    assert_eq!(ProcessingStats { records: 8, applied: 4, rejected: 2, parse_errors: 2, invalid: 0, locked: 0 }, stats);
+    assert_eq!(&currency("7.5"), l.calculate_client_account_summary(1).unwrap().total());
+    let summary = l.calculate_client_account_summary(2).unwrap();
+    assert_eq!(&currency("0"), summary.available());
+    assert_eq!(&currency("5"), summary.held());
+  }
+  #[test]
+  fn process_stream_locked() {
+    use csv::StringRecord;
+    let records = vec![
+      StringRecord::from(vec!["deposit", "1", "1", "10.0"]),
+      StringRecord::from(vec!["dispute", "1", "1", ""]),
+      StringRecord::from(vec!["chargeback", "1", "1", ""]),
+      StringRecord::from(vec!["deposit", "1", "2", "5.0"]),
+      StringRecord::from(vec!["deposit", "1", "x", "5.0"]),
+      StringRecord::from(vec!["withdrawal", "1", "3", "50.0"]),
+    ];
+    let mut l = Ledger::new();
+    let stats = l.process_stream(records);
+    assert_eq!(2, stats.locked);
+    assert_eq!(0, stats.rejected);
+    assert_eq!(1, stats.parse_errors);
+    assert_eq!(2, l.dropped_by_lock());
+    assert_eq!(&currency("0"), l.calculate_client_account_summary(1).unwrap().total());
+  }
+  #[test]
+  fn process_stream_scientific() {
+    use csv::StringRecord;
+    let records = || vec![
+      StringRecord::from(vec!["deposit", "1", "1", "1.5e3"]),
+      StringRecord::from(vec!["deposit", "1", "2", "2.0"]),
+    ];
+    // Rejected like the reader rejects it, unless the ledger accepts scientific amounts
+    let mut l = Ledger::new();
+    let stats = l.process_stream(records());
+    assert_eq!((1, 1), (stats.applied, stats.parse_errors));
+    assert_eq!(&"2".parse::<Currency>().unwrap(), l.calculate_client_account_summary(1).unwrap().total());
+    let mut l = Ledger::new().with_scientific_amounts(true);
+    let stats = l.process_stream(records());
+    assert_eq!((2, 0), (stats.applied, stats.parse_errors));
+    assert_eq!(&"1502".parse::<Currency>().unwrap(), l.calculate_client_account_summary(1).unwrap().total());
+  }
+}
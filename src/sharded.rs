@@ -0,0 +1,57 @@
+use std::{collections::HashSet, sync::{Mutex, PoisonError}};
+use crate::{
+  ClientId,
+  TxnId,
+  ledger::{AccountSummary, ApplyOutcome, Ledger, SortKey},
+  transactions::Transaction
+};
+
+/// A ledger split across several inner ledgers by client, each behind its own lock, so
+/// transactions for different clients can be added from multiple threads at once.
+///
+/// Transactions for the same client must still be added in order. Transaction ids are unique
+/// across every shard, as in a single `Ledger`, though which of two clients reusing an id at once
+/// from different threads gets it depends on which is added first.
+pub struct ShardedLedger {
+  shards: Vec<Mutex<Ledger>>,
+  /// Ids of the deposits and withdrawals stored by any shard.
+  txn_ids: Mutex<HashSet<TxnId>>,
+}
+impl ShardedLedger {
+  /// Creates a ledger with `shards` inner ledgers, or one if `shards` is zero.
+  pub fn new(shards: usize) -> Self {
+    ShardedLedger {
+      shards: (0..shards.max(1)).map(|_| Mutex::new(Ledger::new())).collect(),
+      txn_ids: Mutex::new(HashSet::new()),
+    }
+  }
+  fn shard(&self, client_id: ClientId) -> &Mutex<Ledger> {
+    // Truncating a wide id on a narrow platform still spreads clients across the shards
+    &self.shards[client_id as usize % self.shards.len()]
+  }
+  /// Adds a transaction to the shard owning its client. Referential transactions act on the
+  /// client's own transactions, so they're found in the same shard.
+  pub fn add_transaction(&self, txn: Transaction) {
+    // A panic in another thread holding the lock shouldn't stop every other client being processed
+    let mut shard = self.shard(txn.client_id()).lock().unwrap_or_else(PoisonError::into_inner);
+    // Only the id of a transaction the shard would store is taken, as in a single ledger. The
+    // shard stays locked, so nothing can change the outcome before it's added.
+    if let Transaction::Basic(basic_txn) = &txn {
+      if shard.simulate(&txn) == ApplyOutcome::Applied
+        && !self.txn_ids.lock().unwrap_or_else(PoisonError::into_inner).insert(basic_txn.txn_id()) {
+        shard.reject_duplicate(basic_txn);
+        return
+      }
+    }
+    shard.add_transaction(txn);
+  }
+  /// Calculates the summary of every client account across the shards, ordered by client id.
+  #[must_use]
+  pub fn calculate_all_account_summaries(&self) -> Vec<AccountSummary> {
+    let mut summaries: Vec<AccountSummary> = self.shards.iter()
+      .flat_map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).calculate_all_account_summaries())
+      .collect();
+    SortKey::Client.sort(&mut summaries);
+    summaries
+  }
+}
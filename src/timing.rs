@@ -0,0 +1,54 @@
+
+use std::{
+  fmt,
+  time::{Duration, Instant}
+};
+
+/// Wall-clock durations of each phase of a run.
+#[derive(Clone, Debug)]
+pub struct Timings {
+  /// Number of valid transactions read during the read phase.
+  pub records: usize,
+  /// Time spent reading, parsing and applying transactions.
+  pub read: Duration,
+  /// Time spent summarising accounts and writing them out.
+  pub write: Duration,
+}
+impl Timings {
+  pub fn records_per_sec(&self) -> f64 {
+    let secs = self.read.as_secs_f64();
+    if secs > 0.0 { self.records as f64 / secs } else { 0.0 }
+  }
+}
+impl fmt::Display for Timings {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    writeln!(f, "read:  {:?} ({} records, {:.0} records/sec)", self.read, self.records, self.records_per_sec())?;
+    write!(f, "write: {:?}", self.write)
+  }
+}
+
+/// Runs `f`, returning its result alongside how long it took.
+pub fn timed<T>(f: impl FnOnce() -> T) -> (T, Duration) {
+  let start = Instant::now();
+  let result = f();
+  (result, start.elapsed())
+}
+
+#[cfg(test)]
+mod timing_tests {
+  use super::*;
+  use crate::ledger::Ledger;
+
+  #[test]
+  fn timed_replay() -> Result<(), Box<dyn std::error::Error>> {
+    let mut l = Ledger::new();
+    let (records, read) = timed(|| l.replay_from_csv("testdata/spec_example.csv".into()));
+    let (summaries, write) = timed(|| l.calculate_all_account_summaries());
+    let timings = Timings { records: records?, read, write };
+    assert_eq!(5, timings.records);
+    assert_eq!(2, summaries.len());
+    assert!(timings.records_per_sec() >= 0.0);
+    assert!(timings.to_string().contains("5 records"));
+    Ok(())
+  }
+}
@@ -1,166 +1,783 @@
-
-use std::str::FromStr;
-use csv::StringRecord;
-
-use crate::{ClientId, TxnId, Currency};
-
-#[derive(Clone, Debug, PartialEq)]
-pub enum BasicTransaction {
-    Deposit    { client_id: ClientId, txn_id: TxnId, amount: Currency, disputed: bool },
-    Withdrawal { client_id: ClientId, txn_id: TxnId, amount: Currency, disputed: bool },
-}
-impl BasicTransaction {
-    pub fn new_dep(client_id: ClientId, txn_id: TxnId, amount: Currency) -> Self {
-        Self::Deposit { client_id, txn_id, amount, disputed: false }
-    }
-    pub fn new_wit(client_id: ClientId, txn_id: TxnId, amount: Currency) -> Self {
-        Self::Withdrawal { client_id, txn_id, amount, disputed: false }
-    }
-    pub fn client_id(&self) -> ClientId {
-        match self {
-            &Self::Deposit    { client_id, .. } => client_id,
-            &Self::Withdrawal { client_id, .. } => client_id,
-        }
-    }
-    pub fn txn_id(&self) -> TxnId {
-        match self {
-            &Self::Deposit    { client_id: _, txn_id, .. } => txn_id,
-            &Self::Withdrawal { client_id: _, txn_id, .. } => txn_id,
-        }
-    }
-    pub fn amount(&self) -> Currency {
-        match &self {
-            &Self::Deposit    { client_id: _, txn_id: _, amount, .. } => amount.clone(),
-            &Self::Withdrawal { client_id: _, txn_id: _, amount, .. } => amount.clone(),
-        }
-    }
-    pub fn disputed(&self) -> bool {
-        match self {
-            &Self::Deposit    { client_id: _, txn_id: _, amount: _, disputed } => disputed,
-            &Self::Withdrawal { client_id: _, txn_id: _, amount: _, disputed } => disputed,
-        }
-    }
-    pub fn set_disputed(&mut self, new_state: bool) {
-      match self {
-        Self::Deposit    { client_id: _, txn_id: _, amount: _, disputed } => *disputed = new_state,
-        Self::Withdrawal { client_id: _, txn_id: _, amount: _, disputed } => *disputed = new_state,
-      }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub enum ReferentialTransaction {
-  Dispute    { client_id: ClientId, txn_id: TxnId },
-  Resolve    { client_id: ClientId, txn_id: TxnId },
-  Chargeback { client_id: ClientId, txn_id: TxnId }
-}
-impl ReferentialTransaction {
-  pub fn new_dis(client_id: ClientId, txn_id: TxnId) -> Self {
-    ReferentialTransaction::Dispute { client_id, txn_id }
-  }
-  pub fn new_res(client_id: ClientId, txn_id: TxnId) -> Self {
-    ReferentialTransaction::Resolve { client_id, txn_id }
-  }
-  pub fn new_cha(client_id: ClientId, txn_id: TxnId) -> Self {
-    ReferentialTransaction::Chargeback { client_id, txn_id }
-  }
-  pub fn client_id(&self) -> ClientId {
-    match self {
-      Self::Dispute    { client_id, .. } => *client_id,
-      Self::Resolve    { client_id, .. } => *client_id,
-      Self::Chargeback { client_id, .. } => *client_id,
-    }
-  }
-  pub fn txn_id(&self) -> TxnId {
-      match self {
-        Self::Dispute    { client_id: _, txn_id, .. } => *txn_id,
-        Self::Resolve    { client_id: _, txn_id, .. } => *txn_id,
-        Self::Chargeback { client_id: _, txn_id, .. } => *txn_id,
-      }
-  }
-}
-
-#[derive(Clone, Debug)]
-pub enum Transaction {
-  Basic(BasicTransaction),
-  Referential(ReferentialTransaction),
-}
-impl Transaction {
-  pub fn new_dep(client_id: ClientId, txn_id: TxnId, amount: Currency) -> Self {
-    Self::Basic(BasicTransaction::new_dep(client_id, txn_id, amount))
-  }
-  pub fn new_wit(client_id: ClientId, txn_id: TxnId, amount: Currency) -> Self {
-    Self::Basic(BasicTransaction::new_wit(client_id, txn_id, amount))
-  }
-  pub fn new_dis(client_id: ClientId, txn_id: TxnId) -> Self {
-    Self::Referential(ReferentialTransaction::new_dis(client_id, txn_id))
-  }
-  pub fn new_res(client_id: ClientId, txn_id: TxnId) -> Self {
-    Self::Referential(ReferentialTransaction::new_res(client_id, txn_id))
-  }
-  pub fn new_cha(client_id: ClientId, txn_id: TxnId) -> Self {
-    Self::Referential(ReferentialTransaction::new_cha(client_id, txn_id))
-  }
-  pub fn client_id(&self) -> ClientId {
-    match &self {
-      Self::Basic(txn)    => txn.client_id(),
-      Self::Referential(txn) => txn.client_id()
-    }
-  }
-  pub fn txn_id(&self) -> TxnId {
-    match &self {
-      Self::Basic(txn)    => txn.txn_id(),
-      Self::Referential(txn) => txn.txn_id()
-    }
-  }
-  pub fn amount(&self) -> Option<Currency> {
-    if let Self::Basic(txn) = self { Some(txn.amount()) } else { None }
-  }
-  pub fn disputed(&self) -> Option<bool> {
-    if let Self::Basic(txn) = self { Some(txn.disputed()) } else { None }
-  }
-  pub fn is_basic(&self) -> bool {
-    if let Self::Basic(_) = self { true } else { false }
-  }
-  pub fn into_inner_basic(self) -> Option<BasicTransaction> {
-    if let Self::Basic(txn) = self { Some(txn) } else { None }
-  }
-}
-impl std::convert::TryFrom<StringRecord> for Transaction {
-  type Error = ();
-  fn try_from(string_record: StringRecord) -> Result<Self, Self::Error> {
-    if string_record.len() < 3 {
-      return Err(())
-    }
-    let client_id = if let Some(client_id) = string_record.get(1) {
-        if let Ok(client_id) = client_id.trim().parse::<ClientId>() {
-          client_id
-        }
-        else {
-          return Err(())
-        }
-      } else {
-        return Err(())
-    };
-    let txn_id = if let Some(txn_id) = string_record.get(2) {
-        if let Ok(txn_id) = txn_id.trim().parse::<TxnId>() {
-          txn_id
-        }
-        else {
-          return Err(())
-        }
-      } else {
-        return Err(())
-    };
-    let amount = if let Some(amount) = string_record.get(3) { Currency::from_str(amount) } else { Err(bigdecimal::ParseBigDecimalError::Empty) };
-    // Unwrap safety: already checked that string_record has a length > 2
-    match (string_record.get(0).unwrap().trim(), amount) {
-      ("deposit",    Ok(amount)) => Ok(Transaction::new_dep(client_id, txn_id, amount)),
-      ("withdrawal", Ok(amount)) => Ok(Transaction::new_wit(client_id, txn_id, amount)),
-      ("dispute",    Err(_)) => Ok(Transaction::new_dis(client_id, txn_id)),
-      ("resolve",    Err(_)) => Ok(Transaction::new_res(client_id, txn_id)),
-      ("chargeback", Err(_)) => Ok(Transaction::new_cha(client_id, txn_id)),
-      _ => Err(())
-    }
-  }
-}
+
+use std::{collections::HashMap, fmt, io::Read, str::FromStr};
+use csv::{Reader, StringRecord};
+use serde::{Deserialize, Serialize};
+
+use crate::{ClientId, TxnId, Currency, CurrencyExt, Timestamp};
+
+/// Where a transaction is in the dispute process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisputeState {
+    None,
+    /// Under dispute, holding the number of disputes yet to be resolved.
+    Disputed(u32),
+    /// A dispute ended in a chargeback, reversing the transaction. It can't be disputed again.
+    ChargedBack,
+}
+impl DisputeState {
+    /// Whether a dispute, resolve or chargeback is a valid next step in the dispute lifecycle: only
+    /// a disputed transaction can be resolved or charged back, and a charged back transaction can't
+    /// be disputed again.
+    pub fn accepts(&self, ref_txn: &ReferentialTransaction) -> bool {
+        match (self, ref_txn) {
+            (Self::ChargedBack, _) => false,
+            (Self::Disputed(_), _) | (Self::None, ReferentialTransaction::Dispute { .. }) => true,
+            (Self::None, _) => false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum BasicTransaction {
+    /// Deposits are `settled` once their funds can be withdrawn, until then they are pending.
+    Deposit    { client_id: ClientId, txn_id: TxnId, amount: Currency, dispute_state: DisputeState, settled: bool, timestamp: Option<Timestamp> },
+    Withdrawal { client_id: ClientId, txn_id: TxnId, amount: Currency, dispute_state: DisputeState, timestamp: Option<Timestamp> },
+}
+impl BasicTransaction {
+    pub fn new_dep(client_id: ClientId, txn_id: TxnId, amount: Currency) -> Self {
+        Self::Deposit { client_id, txn_id, amount, dispute_state: DisputeState::None, settled: true, timestamp: None }
+    }
+    pub fn new_wit(client_id: ClientId, txn_id: TxnId, amount: Currency) -> Self {
+        Self::Withdrawal { client_id, txn_id, amount, dispute_state: DisputeState::None, timestamp: None }
+    }
+    pub fn client_id(&self) -> ClientId {
+        match self {
+            Self::Deposit    { client_id, .. } => *client_id,
+            Self::Withdrawal { client_id, .. } => *client_id,
+        }
+    }
+    pub fn txn_id(&self) -> TxnId {
+        match self {
+            Self::Deposit    { client_id: _, txn_id, .. } => *txn_id,
+            Self::Withdrawal { client_id: _, txn_id, .. } => *txn_id,
+        }
+    }
+    pub fn kind(&self) -> TransactionKind {
+        match self {
+            Self::Deposit    { .. } => TransactionKind::Deposit,
+            Self::Withdrawal { .. } => TransactionKind::Withdrawal,
+        }
+    }
+    /// The type of the transaction, spelt exactly as it is in csv input.
+    pub fn kind_str(&self) -> &'static str {
+        self.kind().as_str()
+    }
+    pub fn amount(&self) -> Currency {
+        match self {
+            Self::Deposit    { client_id: _, txn_id: _, amount, .. } => amount.clone(),
+            Self::Withdrawal { client_id: _, txn_id: _, amount, .. } => amount.clone(),
+        }
+    }
+    /// Borrows the amount rather than cloning it, which avoids a heap allocation per call.
+    pub fn amount_ref(&self) -> &Currency {
+        match self {
+            Self::Deposit    { client_id: _, txn_id: _, amount, .. } => amount,
+            Self::Withdrawal { client_id: _, txn_id: _, amount, .. } => amount,
+        }
+    }
+    pub fn dispute_state(&self) -> DisputeState {
+        match self {
+            Self::Deposit    { client_id: _, txn_id: _, amount: _, dispute_state, .. } => *dispute_state,
+            Self::Withdrawal { client_id: _, txn_id: _, amount: _, dispute_state, .. } => *dispute_state,
+        }
+    }
+    pub fn disputed(&self) -> bool {
+        matches!(self.dispute_state(), DisputeState::Disputed(_))
+    }
+    pub fn charged_back(&self) -> bool {
+        self.dispute_state() == DisputeState::ChargedBack
+    }
+    /// When the transaction was ingested, if known.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        match self {
+            Self::Deposit    { timestamp, .. } => *timestamp,
+            Self::Withdrawal { timestamp, .. } => *timestamp,
+        }
+    }
+    /// Withdrawals are always settled.
+    pub fn settled(&self) -> bool {
+        match self {
+            Self::Deposit    { settled, .. } => *settled,
+            Self::Withdrawal { .. } => true,
+        }
+    }
+    /// Sets whether a deposit is settled, this has no effect on withdrawals.
+    pub fn set_settled(&mut self, new_state: bool) {
+      if let Self::Deposit { settled, .. } = self {
+        *settled = new_state;
+      }
+    }
+    pub fn set_timestamp(&mut self, new_timestamp: Timestamp) {
+      match self {
+        Self::Deposit    { timestamp, .. } => *timestamp = Some(new_timestamp),
+        Self::Withdrawal { timestamp, .. } => *timestamp = Some(new_timestamp),
+      }
+    }
+    /// Rounds the amount to `scale` decimal places if it has more.
+    pub fn limit_scale(&mut self, scale: i64) {
+      match self {
+        Self::Deposit    { amount, .. } => *amount = amount.limit_scale(scale),
+        Self::Withdrawal { amount, .. } => *amount = amount.limit_scale(scale),
+      }
+    }
+    fn dispute_state_mut(&mut self) -> &mut DisputeState {
+      match self {
+        Self::Deposit    { client_id: _, txn_id: _, amount: _, dispute_state, .. } => dispute_state,
+        Self::Withdrawal { client_id: _, txn_id: _, amount: _, dispute_state, .. } => dispute_state,
+      }
+    }
+    /// Opens another dispute against the transaction, unless it has been charged back.
+    pub fn open_dispute(&mut self) {
+      let state = self.dispute_state_mut();
+      *state = match *state {
+        DisputeState::None => DisputeState::Disputed(1),
+        DisputeState::Disputed(count) => DisputeState::Disputed(count.saturating_add(1)),
+        DisputeState::ChargedBack => DisputeState::ChargedBack,
+      };
+    }
+    /// Resolves one open dispute, the transaction is only undisputed once every dispute is resolved.
+    pub fn resolve_dispute(&mut self) {
+      let state = self.dispute_state_mut();
+      *state = match *state {
+        DisputeState::Disputed(count) if count > 1 => DisputeState::Disputed(count - 1),
+        DisputeState::ChargedBack => DisputeState::ChargedBack,
+        _ => DisputeState::None,
+      };
+    }
+    /// Drops every open dispute at once, as if they had never been raised. A charged back
+    /// transaction stays charged back.
+    pub fn clear_disputes(&mut self) {
+      let state = self.dispute_state_mut();
+      if let DisputeState::Disputed(_) = *state {
+        *state = DisputeState::None;
+      }
+    }
+    /// Closes every open dispute with a chargeback, after which the transaction is final.
+    pub fn charge_back(&mut self) {
+      *self.dispute_state_mut() = DisputeState::ChargedBack;
+    }
+}
+
+/// Each carries the `amount` of the transaction it references if the input stated one, which is
+/// checked against the stored amount before it's applied.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ReferentialTransaction {
+  Dispute    { client_id: ClientId, txn_id: TxnId, amount: Option<Currency> },
+  Resolve    { client_id: ClientId, txn_id: TxnId, amount: Option<Currency> },
+  Chargeback { client_id: ClientId, txn_id: TxnId, amount: Option<Currency> }
+}
+impl ReferentialTransaction {
+  pub fn new_dis(client_id: ClientId, txn_id: TxnId) -> Self {
+    ReferentialTransaction::Dispute { client_id, txn_id, amount: None }
+  }
+  pub fn new_res(client_id: ClientId, txn_id: TxnId) -> Self {
+    ReferentialTransaction::Resolve { client_id, txn_id, amount: None }
+  }
+  pub fn new_cha(client_id: ClientId, txn_id: TxnId) -> Self {
+    ReferentialTransaction::Chargeback { client_id, txn_id, amount: None }
+  }
+  /// Sets the amount stated for the referenced transaction.
+  pub fn with_stated_amount(mut self, stated: Currency) -> Self {
+    match &mut self {
+      Self::Dispute { amount, .. } | Self::Resolve { amount, .. } | Self::Chargeback { amount, .. } => *amount = Some(stated),
+    }
+    self
+  }
+  /// The amount stated for the referenced transaction, if any.
+  pub fn stated_amount(&self) -> Option<&Currency> {
+    match self {
+      Self::Dispute { amount, .. } | Self::Resolve { amount, .. } | Self::Chargeback { amount, .. } => amount.as_ref(),
+    }
+  }
+  /// Rounds any stated amount to `scale` decimal places if it has more.
+  pub fn limit_scale(&mut self, scale: i64) {
+    match self {
+      Self::Dispute { amount, .. } | Self::Resolve { amount, .. } | Self::Chargeback { amount, .. } =>
+        if let Some(amount) = amount {
+          *amount = amount.limit_scale(scale);
+        },
+    }
+  }
+  pub fn client_id(&self) -> ClientId {
+    match self {
+      Self::Dispute    { client_id, .. } => *client_id,
+      Self::Resolve    { client_id, .. } => *client_id,
+      Self::Chargeback { client_id, .. } => *client_id,
+    }
+  }
+  pub fn kind(&self) -> TransactionKind {
+    match self {
+      Self::Dispute    { .. } => TransactionKind::Dispute,
+      Self::Resolve    { .. } => TransactionKind::Resolve,
+      Self::Chargeback { .. } => TransactionKind::Chargeback,
+    }
+  }
+  /// The type of the transaction, spelt exactly as it is in csv input.
+  pub fn kind_str(&self) -> &'static str {
+    self.kind().as_str()
+  }
+  pub fn txn_id(&self) -> TxnId {
+      match self {
+        Self::Dispute    { client_id: _, txn_id, .. } => *txn_id,
+        Self::Resolve    { client_id: _, txn_id, .. } => *txn_id,
+        Self::Chargeback { client_id: _, txn_id, .. } => *txn_id,
+      }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Transaction {
+  Basic(BasicTransaction),
+  Referential(ReferentialTransaction),
+  /// A manual credit (positive amount) or debit (negative amount) applied directly to available
+  /// funds. Adjustments are not retained, so they cannot be disputed.
+  Adjustment { client_id: ClientId, txn_id: TxnId, amount: Currency },
+}
+impl Transaction {
+  pub fn new_dep(client_id: ClientId, txn_id: TxnId, amount: Currency) -> Self {
+    Self::Basic(BasicTransaction::new_dep(client_id, txn_id, amount))
+  }
+  pub fn new_wit(client_id: ClientId, txn_id: TxnId, amount: Currency) -> Self {
+    Self::Basic(BasicTransaction::new_wit(client_id, txn_id, amount))
+  }
+  pub fn new_dis(client_id: ClientId, txn_id: TxnId) -> Self {
+    Self::Referential(ReferentialTransaction::new_dis(client_id, txn_id))
+  }
+  pub fn new_res(client_id: ClientId, txn_id: TxnId) -> Self {
+    Self::Referential(ReferentialTransaction::new_res(client_id, txn_id))
+  }
+  pub fn new_cha(client_id: ClientId, txn_id: TxnId) -> Self {
+    Self::Referential(ReferentialTransaction::new_cha(client_id, txn_id))
+  }
+  pub fn new_adj(client_id: ClientId, txn_id: TxnId, amount: Currency) -> Self {
+    Self::Adjustment { client_id, txn_id, amount }
+  }
+  pub fn client_id(&self) -> ClientId {
+    match &self {
+      Self::Basic(txn)    => txn.client_id(),
+      Self::Referential(txn) => txn.client_id(),
+      Self::Adjustment { client_id, .. } => *client_id,
+    }
+  }
+  pub fn txn_id(&self) -> TxnId {
+    match &self {
+      Self::Basic(txn)    => txn.txn_id(),
+      Self::Referential(txn) => txn.txn_id(),
+      Self::Adjustment { client_id: _, txn_id, .. } => *txn_id,
+    }
+  }
+  pub fn kind(&self) -> TransactionKind {
+    match self {
+      Self::Basic(txn) => txn.kind(),
+      Self::Referential(ref_txn) => ref_txn.kind(),
+      Self::Adjustment { .. } => TransactionKind::Adjustment,
+    }
+  }
+  /// The type of the transaction, spelt exactly as it is in csv input.
+  pub fn kind_str(&self) -> &'static str {
+    self.kind().as_str()
+  }
+  /// A hashable key identifying the transaction, for use in sets and maps: its type, client, id
+  /// and amount. The amount is rendered without trailing zeros, so `1.50` and `1.5` are the same
+  /// key. Dispute state, settlement and timestamp are not part of the key.
+  pub fn dedup_key(&self) -> (String, ClientId, TxnId, Option<String>) {
+    let amount = self.amount_ref().map(|amount| amount.normalized().to_string());
+    (self.kind_str().to_string(), self.client_id(), self.txn_id(), amount)
+  }
+  pub fn amount(&self) -> Option<Currency> {
+    self.amount_ref().cloned()
+  }
+  pub fn amount_ref(&self) -> Option<&Currency> {
+    match self {
+      Self::Basic(txn) => Some(txn.amount_ref()),
+      Self::Referential(_) => None,
+      Self::Adjustment { client_id: _, txn_id: _, amount } => Some(amount),
+    }
+  }
+  /// Rounds every amount the transaction carries to `scale` decimal places if it has more.
+  pub fn limit_scale(&mut self, scale: i64) {
+    match self {
+      Self::Basic(txn) => txn.limit_scale(scale),
+      Self::Referential(ref_txn) => ref_txn.limit_scale(scale),
+      Self::Adjustment { amount, .. } => *amount = amount.limit_scale(scale),
+    }
+  }
+  /// Checks the rules a parsed transaction has to follow to make sense: deposits and withdrawals
+  /// are for a positive amount, adjustments aren't for zero, and any amount stated by a dispute,
+  /// resolve or chargeback is positive. Parsing only checks each field can be read.
+  pub fn validate(&self) -> Result<(), ValidationError> {
+    let zero = Currency::from(0);
+    match self {
+      Self::Basic(txn) if *txn.amount_ref() <= zero => Err(ValidationError::NonPositiveAmount(txn.amount())),
+      Self::Adjustment { amount, .. } if *amount == zero => Err(ValidationError::ZeroAdjustment),
+      Self::Referential(ref_txn) => match ref_txn.stated_amount() {
+        Some(stated) if *stated <= zero => Err(ValidationError::NonPositiveStatedAmount(stated.clone())),
+        _ => Ok(()),
+      },
+      _ => Ok(()),
+    }
+  }
+  pub fn disputed(&self) -> Option<bool> {
+    if let Self::Basic(txn) = self { Some(txn.disputed()) } else { None }
+  }
+  pub fn is_basic(&self) -> bool {
+    matches!(self, Self::Basic(_))
+  }
+  pub fn into_inner_basic(self) -> Option<BasicTransaction> {
+    if let Self::Basic(txn) = self { Some(txn) } else { None }
+  }
+}
+impl Transaction {
+  /// Parses a transaction from the fields of a single record, using `columns` to locate each field.
+  pub fn from_fields(fields: &[&str], columns: &ColumnMap) -> Result<Self, TransactionParseError> {
+    let field = |column: usize, name: &'static str| fields.get(column).ok_or(TransactionParseError::MissingField(name));
+    let client_id = field(columns.client, "client")?;
+    let client_id = if let Some(client_id) = parse_id::<ClientId>(client_id) {
+        client_id
+      } else {
+        return Err(TransactionParseError::InvalidClientId(client_id.to_string()))
+    };
+    let txn_id = field(columns.tx, "tx")?;
+    let txn_id = if let Some(txn_id) = parse_id::<TxnId>(txn_id) {
+        txn_id
+      } else {
+        return Err(TransactionParseError::InvalidTxnId(txn_id.to_string()))
+    };
+    let kind: TransactionKind = field(columns.kind, "type")?.trim().parse()?;
+    // A blank amount is missing, which referential transactions usually are
+    let amount = fields.get(columns.amount).filter(|amount| !amount.trim().is_empty()).map(|amount| parse_amount(amount).ok_or(()));
+    // An empty timestamp is treated as missing, but one that is present must be valid
+    let timestamp = match columns.timestamp.and_then(|column| fields.get(column)).map(|timestamp| timestamp.trim()) {
+      Some("") | None => None,
+      Some(timestamp) => if let Ok(timestamp) = timestamp.parse::<Timestamp>() {
+          Some(timestamp)
+        } else {
+          return Err(TransactionParseError::InvalidTimestamp(timestamp.to_string()))
+      },
+    };
+    let with_timestamp = |mut txn: BasicTransaction| {
+      if let Some(timestamp) = timestamp {
+        txn.set_timestamp(timestamp);
+      }
+      Transaction::Basic(txn)
+    };
+    let referential = |ref_txn: ReferentialTransaction, amount: Option<Currency>| {
+      Transaction::Referential(if let Some(amount) = amount { ref_txn.with_stated_amount(amount) } else { ref_txn })
+    };
+    match (kind, amount.transpose()) {
+      (TransactionKind::Deposit,    Ok(Some(amount))) => Ok(with_timestamp(BasicTransaction::new_dep(client_id, txn_id, amount))),
+      (TransactionKind::Withdrawal, Ok(Some(amount))) => Ok(with_timestamp(BasicTransaction::new_wit(client_id, txn_id, amount))),
+      (TransactionKind::Adjustment, Ok(Some(amount))) => Ok(Transaction::new_adj(client_id, txn_id, amount)),
+      (TransactionKind::Dispute,    Ok(amount)) => Ok(referential(ReferentialTransaction::new_dis(client_id, txn_id), amount)),
+      (TransactionKind::Resolve,    Ok(amount)) => Ok(referential(ReferentialTransaction::new_res(client_id, txn_id), amount)),
+      (TransactionKind::Chargeback, Ok(amount)) => Ok(referential(ReferentialTransaction::new_cha(client_id, txn_id), amount)),
+      _ => Err(TransactionParseError::InvalidAmount),
+    }
+  }
+  /// Parses every record of a csv reader as a transaction, in the standard `type,client,tx,amount`
+  /// layout. Rows that can't be read or parsed are yielded as errors rather than ending the iteration.
+  pub fn parse_csv_reader<R: Read>(reader: Reader<R>) -> impl Iterator<Item = Result<Transaction, TransactionParseError>> {
+    reader.into_records().map(|record| {
+      let record = record.map_err(TransactionParseError::Csv)?;
+      let fields: Vec<&str> = record.iter().collect();
+      Transaction::from_fields(&fields, &ColumnMap::default())
+    })
+  }
+}
+impl Transaction {
+  /// Parses a record in the standard `type,client,tx,amount` layout, reading its type through
+  /// `synonyms`.
+  pub fn from_record(record: &StringRecord, synonyms: &TypeSynonyms) -> Result<Self, TransactionParseError> {
+    let mut fields: Vec<&str> = record.iter().collect();
+    synonyms.canonicalize(&mut fields, ColumnMap::default().kind);
+    Transaction::from_fields(&fields, &ColumnMap::default())
+  }
+}
+/// Parses a record in the standard `type,client,tx,amount` layout, recognising only the canonical
+/// type names.
+impl std::convert::TryFrom<StringRecord> for Transaction {
+  type Error = TransactionParseError;
+  fn try_from(string_record: StringRecord) -> Result<Self, Self::Error> {
+    Transaction::from_record(&string_record, &TypeSynonyms::default())
+  }
+}
+
+/// Parses a client or transaction id. Ids are plain decimal digits, optionally surrounded by
+/// whitespace, and leading zeros are ignored, so `007` is the same id as `7`. Anything else is
+/// rejected, including signs (`+5`), decimals (`5.0`), whitespace between the digits (`1 2`) and
+/// ids too large for the id type.
+pub fn parse_id<T: FromStr>(id: &str) -> Option<T> {
+  let id = id.trim();
+  if id.is_empty() || !id.bytes().all(|b| b.is_ascii_digit()) {
+    return None
+  }
+  id.parse().ok()
+}
+
+/// Largest power of ten, either way, an amount in scientific notation can be scaled by. Larger
+/// exponents overflow while parsing, or make every later calculation with the amount enormous.
+const MAX_AMOUNT_EXPONENT: i64 = 64;
+
+fn parse_amount(amount: &str) -> Option<Currency> {
+  if let Some((_, exponent)) = amount.split_once(['e', 'E']) {
+    if !exponent.parse::<i64>().is_ok_and(|exponent| (-MAX_AMOUNT_EXPONENT..=MAX_AMOUNT_EXPONENT).contains(&exponent)) {
+      return None
+    }
+  }
+  Currency::from_str(amount).ok()
+}
+
+/// Parses a single line in the standard `type,client,tx,amount` layout, e.g. `deposit,1,1,10.0`.
+/// The line is split on every comma, as csv input is read without quoting.
+impl std::convert::TryFrom<&str> for Transaction {
+  type Error = TransactionParseError;
+  fn try_from(line: &str) -> Result<Self, Self::Error> {
+    let fields: Vec<&str> = line.trim_end_matches(['\r', '\n']).split(',').collect();
+    Transaction::from_fields(&fields, &ColumnMap::default())
+  }
+}
+
+/// Why a record could not be parsed as a transaction.
+#[derive(Debug)]
+pub enum TransactionParseError {
+  /// The record could not be read as csv.
+  Csv(csv::Error),
+  /// The record has no column for the named field.
+  MissingField(&'static str),
+  InvalidClientId(String),
+  InvalidTxnId(String),
+  InvalidTimestamp(String),
+  /// The `type` is not one of the known transaction types.
+  UnknownKind(String),
+  /// A deposit, withdrawal or adjustment has a missing or invalid amount, or a dispute, resolve or
+  /// chargeback has an invalid one.
+  InvalidAmount,
+}
+impl fmt::Display for TransactionParseError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Csv(e) => write!(f, "Unreadable record: {}", e),
+      Self::MissingField(name) => write!(f, "Missing {} field.", name),
+      Self::InvalidClientId(client_id) => write!(f, "Invalid client id: {}", client_id),
+      Self::InvalidTxnId(txn_id) => write!(f, "Invalid tx id: {}", txn_id),
+      Self::InvalidTimestamp(timestamp) => write!(f, "Invalid timestamp: {}", timestamp),
+      Self::UnknownKind(kind) => write!(f, "Unknown transaction type: {}", kind),
+      Self::InvalidAmount => f.write_str("Missing or invalid amount."),
+    }
+  }
+}
+impl std::error::Error for TransactionParseError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    if let Self::Csv(e) = self { Some(e) } else { None }
+  }
+}
+
+/// Why a parsed transaction breaks the rules checked by `Transaction::validate`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationError {
+  /// A deposit or withdrawal is for zero or a negative amount.
+  NonPositiveAmount(Currency),
+  /// An adjustment for zero, which would have no effect.
+  ZeroAdjustment,
+  /// A dispute, resolve or chargeback states a zero or negative amount, which no transaction has.
+  NonPositiveStatedAmount(Currency),
+}
+impl fmt::Display for ValidationError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::NonPositiveAmount(amount) => write!(f, "Amount must be positive: {}", amount),
+      Self::ZeroAdjustment => f.write_str("Adjustment of zero."),
+      Self::NonPositiveStatedAmount(amount) => write!(f, "Stated amount must be positive: {}", amount),
+    }
+  }
+}
+impl std::error::Error for ValidationError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ColumnMap {
+  pub kind: usize,
+  pub client: usize,
+  pub tx: usize,
+  pub amount: usize,
+  /// Deposits and withdrawals can optionally carry the time they happened.
+  pub timestamp: Option<usize>,
+}
+impl Default for ColumnMap {
+  /// The standard `type,client,tx,amount` layout.
+  fn default() -> Self {
+    ColumnMap { kind: 0, client: 1, tx: 2, amount: 3, timestamp: None }
+  }
+}
+
+/// The type of a transaction, as named in the `type` column.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionKind {
+  Deposit,
+  Withdrawal,
+  Dispute,
+  Resolve,
+  Chargeback,
+  Adjustment,
+}
+impl TransactionKind {
+  pub const ALL: [TransactionKind; 6] = [
+    Self::Deposit, Self::Withdrawal, Self::Dispute, Self::Resolve, Self::Chargeback, Self::Adjustment
+  ];
+
+  /// The canonical spelling, as read from and written to csv.
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Deposit    => "deposit",
+      Self::Withdrawal => "withdrawal",
+      Self::Dispute    => "dispute",
+      Self::Resolve    => "resolve",
+      Self::Chargeback => "chargeback",
+      Self::Adjustment => "adjustment",
+    }
+  }
+}
+impl fmt::Display for TransactionKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.as_str())
+  }
+}
+/// Only the canonical spellings are accepted, synonyms are mapped to them by `TypeSynonyms`
+/// before parsing.
+impl FromStr for TransactionKind {
+  type Err = TransactionParseError;
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    Self::ALL.iter().copied().find(|kind| kind.as_str() == s).ok_or_else(|| TransactionParseError::UnknownKind(s.to_string()))
+  }
+}
+
+/// Other names upstreams use for transaction types, e.g. `credit` for a deposit, each mapped to
+/// the kind it names. The default has none, so only the canonical names are recognised.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TypeSynonyms(HashMap<String, TransactionKind>);
+impl TypeSynonyms {
+  /// Adds `synonym` as another name for `kind`, e.g. `.with("credit", TransactionKind::Deposit)`.
+  pub fn with(mut self, synonym: &str, kind: TransactionKind) -> Self {
+    self.0.insert(synonym.to_string(), kind);
+    self
+  }
+  /// The canonical name for a type, which is the type itself unless it's a synonym.
+  pub fn canonical<'a>(&self, kind: &'a str) -> &'a str {
+    self.0.get(kind.trim()).map_or(kind, |synonym| synonym.as_str())
+  }
+  /// Replaces the type in the record's fields, at index `kind`, with its canonical name.
+  pub fn canonicalize(&self, fields: &mut [&str], kind: usize) {
+    if let Some(field) = fields.get_mut(kind) {
+      *field = self.canonical(field);
+    }
+  }
+}
+
+#[cfg(test)]
+mod transaction_tests {
+  use super::*;
+
+  #[test]
+  fn id_formats() {
+    for (id, expected) in [("5", 5), (" 5 ", 5), ("\t5", 5), ("007", 7), ("0", 0), ("000", 0), ("65535", 65535)] {
+      assert_eq!(Some(expected), parse_id::<u16>(id), "{:?}", id);
+    }
+    for id in ["", " ", "+5", "-5", "-0", "5.0", "5.", "1 2", "0x5", "5e2", "٥", "65536"] {
+      assert_eq!(None, parse_id::<u16>(id), "{:?}", id);
+    }
+    // Both id fields follow the same rules
+    use std::convert::TryFrom;
+    let txn = Transaction::try_from("deposit,007,010,1.0").unwrap();
+    assert_eq!((7, 10), (txn.client_id(), txn.txn_id()));
+    assert!(matches!(Transaction::try_from("deposit,+5,1,1.0"), Err(TransactionParseError::InvalidClientId(_))));
+    assert!(matches!(Transaction::try_from("deposit,5,+1,1.0"), Err(TransactionParseError::InvalidTxnId(_))));
+  }
+
+  #[test]
+  fn amount_ref_matches_amount() {
+    let dep = BasicTransaction::new_dep(0, 0, Currency::from_str("10.5").unwrap());
+    let wit = BasicTransaction::new_wit(0, 1, Currency::from_str("0.0001").unwrap());
+    assert_eq!(&dep.amount(), dep.amount_ref());
+    assert_eq!(&wit.amount(), wit.amount_ref());
+    assert_eq!(Transaction::Basic(dep.clone()).amount_ref(), Some(dep.amount_ref()));
+    assert_eq!(Transaction::new_dis(0, 0).amount_ref(), None);
+  }
+  #[test]
+  fn dispute_counting() {
+    let mut dep = BasicTransaction::new_dep(0, 0, Currency::from_str("10.5").unwrap());
+    dep.resolve_dispute();
+    assert_eq!(DisputeState::None, dep.dispute_state());
+    dep.open_dispute();
+    dep.open_dispute();
+    assert_eq!(DisputeState::Disputed(2), dep.dispute_state());
+    dep.resolve_dispute();
+    assert!(dep.disputed());
+    dep.resolve_dispute();
+    assert!(!dep.disputed());
+  }
+  #[test]
+  fn from_fields_column_map() {
+    let columns = ColumnMap { kind: 3, client: 2, tx: 1, amount: 0, timestamp: None };
+    let dep = Transaction::from_fields(&["1.5", "7", "3", "deposit"], &columns).unwrap();
+    assert_eq!(3, dep.client_id());
+    assert_eq!(7, dep.txn_id());
+    assert_eq!(Some(Currency::from_str("1.5").unwrap()), dep.amount());
+    let dis = Transaction::from_fields(&["", "7", "3", "dispute"], &columns).unwrap();
+    assert!(!dis.is_basic());
+    assert!(Transaction::from_fields(&["1.5", "7", "3", "deposit"], &ColumnMap::default()).is_err());
+    assert!(Transaction::from_fields(&["1.5", "7"], &columns).is_err());
+  }
+  #[test]
+  fn dedup_key() {
+    let amount = |s| Currency::from_str(s).unwrap();
+    let dep = Transaction::new_dep(1, 2, amount("1.50"));
+    assert_eq!(("deposit".to_string(), 1, 2, Some("1.5".to_string())), dep.dedup_key());
+    assert_eq!(dep.dedup_key(), Transaction::new_dep(1, 2, amount("1.5000")).dedup_key());
+    assert_eq!(Some("100".to_string()), Transaction::new_dep(1, 2, amount("100.0")).dedup_key().3);
+    assert_eq!(("dispute".to_string(), 1, 2, None), Transaction::new_dis(1, 2).dedup_key());
+    let keys: std::collections::HashSet<_> = [
+      dep.clone(),
+      Transaction::new_wit(1, 2, amount("1.5")),
+      Transaction::new_dep(3, 2, amount("1.5")),
+      Transaction::new_dep(1, 4, amount("1.5")),
+      Transaction::new_dep(1, 2, amount("1.6")),
+      Transaction::new_dis(1, 2),
+      Transaction::new_res(1, 2),
+    ].iter().map(Transaction::dedup_key).collect();
+    assert_eq!(7, keys.len());
+    assert!(keys.contains(&dep.dedup_key()));
+  }
+  #[test]
+  fn kind_str_round_trip() {
+    let amount = Currency::from_str("1.5").unwrap();
+    let txns = [
+      (Transaction::new_dep(1, 1, amount.clone()), "deposit"),
+      (Transaction::new_wit(1, 1, amount.clone()), "withdrawal"),
+      (Transaction::new_dis(1, 1), "dispute"),
+      (Transaction::new_res(1, 1), "resolve"),
+      (Transaction::new_cha(1, 1), "chargeback"),
+      (Transaction::new_adj(1, 1, amount), "adjustment"),
+    ];
+    for (txn, kind) in &txns {
+      assert_eq!(*kind, txn.kind_str());
+      let amount = txn.amount().map(|amount| amount.to_string()).unwrap_or_default();
+      let parsed = Transaction::from_fields(&[txn.kind_str(), "1", "1", &amount], &ColumnMap::default()).unwrap();
+      assert_eq!(*kind, parsed.kind_str());
+    }
+  }
+  #[test]
+  fn transaction_kind_round_trip() {
+    for kind in TransactionKind::ALL {
+      assert_eq!(kind, kind.to_string().parse().unwrap());
+    }
+    assert_eq!("chargeback", TransactionKind::Chargeback.to_string());
+    assert_eq!(TransactionKind::Dispute, Transaction::new_dis(1, 1).kind());
+    for kind in ["Deposit", " deposit", "transfer", ""] {
+      assert!(matches!(kind.parse::<TransactionKind>(), Err(TransactionParseError::UnknownKind(k)) if k == kind));
+    }
+  }
+  #[test]
+  fn parse_adjustment() {
+    let credit = Transaction::from_fields(&["adjustment", "1", "2", "3.5"], &ColumnMap::default()).unwrap();
+    assert_eq!(Some(Currency::from_str("3.5").unwrap()), credit.amount());
+    assert_eq!(None, credit.disputed());
+    let debit = Transaction::from_fields(&["adjustment", "1", "3", "-0.25"], &ColumnMap::default()).unwrap();
+    assert_eq!(Some(Currency::from_str("-0.25").unwrap()), debit.amount());
+    assert!(Transaction::from_fields(&["adjustment", "1", "3", ""], &ColumnMap::default()).is_err());
+  }
+  #[test]
+  fn parse_timestamp() {
+      let columns = ColumnMap { timestamp: Some(4), ..ColumnMap::default() };
+      let txn = Transaction::from_fields(&["deposit", "1", "2", "1.5", " 1700000000 "], &columns).unwrap();
+      assert_eq!(Some(1700000000), txn.into_inner_basic().unwrap().timestamp());
+      let txn = Transaction::from_fields(&["withdrawal", "1", "2", "1.5", ""], &columns).unwrap();
+      assert_eq!(None, txn.into_inner_basic().unwrap().timestamp());
+      let txn = Transaction::from_fields(&["dispute", "1", "2", ""], &columns).unwrap();
+      assert!(!txn.is_basic());
+      assert!(Transaction::from_fields(&["deposit", "1", "2", "1.5", "yesterday"], &columns).is_err());
+      let txn = Transaction::from_fields(&["deposit", "1", "2", "1.5", "1700000000"], &ColumnMap::default()).unwrap();
+      assert_eq!(None, txn.into_inner_basic().unwrap().timestamp());
+  }
+  #[test]
+  fn parse_csv_reader_bad_rows() {
+    let reader = csv::Reader::from_path("testdata/bad_rows.csv").unwrap();
+    let results: Vec<_> = Transaction::parse_csv_reader(reader).collect();
+    assert_eq!(8, results.len());
+    assert_eq!(4, results.iter().filter(|result| result.is_ok()).count());
+    assert_eq!("deposit", results[0].as_ref().unwrap().kind_str());
+    assert!(matches!(&results[1], Err(TransactionParseError::InvalidClientId(client_id)) if client_id == "x"));
+    assert!(matches!(&results[2], Err(TransactionParseError::UnknownKind(kind)) if kind == "refund"));
+    assert!(matches!(results[3], Err(TransactionParseError::InvalidAmount)));
+    // Referential transactions can state the amount of the transaction they reference
+    assert!(matches!(&results[4], Ok(Transaction::Referential(ref_txn)) if ref_txn.stated_amount() == Some(&Currency::from(5))));
+    assert_eq!("withdrawal", results[5].as_ref().unwrap().kind_str());
+    assert!(matches!(results[6], Err(TransactionParseError::Csv(_))));
+    assert_eq!("dispute", results[7].as_ref().unwrap().kind_str());
+  }
+  #[test]
+  fn try_from_blank_type() {
+    use std::convert::TryFrom;
+    for kind in &["", "   ", "\t"] {
+      let record = StringRecord::from(vec![*kind, "1", "2"]);
+      assert!(matches!(Transaction::try_from(record), Err(TransactionParseError::UnknownKind(k)) if k.is_empty()));
+    }
+    let record = StringRecord::from(vec!["", "", ""]);
+    assert!(matches!(Transaction::try_from(record), Err(TransactionParseError::InvalidClientId(_))));
+    assert!(matches!(Transaction::try_from(StringRecord::new()), Err(TransactionParseError::MissingField("client"))));
+  }
+  #[test]
+  fn type_synonyms() {
+    use std::convert::TryFrom;
+    let synonyms = TypeSynonyms::default()
+      .with("credit", TransactionKind::Deposit)
+      .with("debit", TransactionKind::Withdrawal)
+      .with("reversal", TransactionKind::Chargeback);
+    let record = |line: &str| StringRecord::from(line.split(',').collect::<Vec<_>>());
+    let credit = Transaction::from_record(&record("credit,1,1,10.0"), &synonyms).unwrap();
+    assert_eq!(Transaction::new_dep(1, 1, Currency::from_str("10.0").unwrap()).dedup_key(), credit.dedup_key());
+    let debit = Transaction::from_record(&record(" debit ,1,2,5.0"), &synonyms).unwrap();
+    assert_eq!(Transaction::new_wit(1, 2, Currency::from_str("5.0").unwrap()).dedup_key(), debit.dedup_key());
+    let reversal = Transaction::from_record(&record("reversal,1,1,"), &synonyms).unwrap();
+    assert_eq!(Transaction::new_cha(1, 1).dedup_key(), reversal.dedup_key());
+    // Canonical names still parse with synonyms installed
+    assert!(Transaction::from_record(&record("deposit,1,3,1.0"), &synonyms).is_ok());
+    // Without a synonym map only the canonical names are known
+    for line in ["credit,1,1,10.0", "debit,1,2,5.0"] {
+      assert!(matches!(Transaction::try_from(record(line)), Err(TransactionParseError::UnknownKind(_))));
+      assert!(matches!(Transaction::from_record(&record(line), &TypeSynonyms::default()), Err(TransactionParseError::UnknownKind(_))));
+    }
+  }
+  #[test]
+  fn try_from_line() {
+    use std::convert::TryFrom;
+    let amount = Currency::from_str("10.0").unwrap();
+    assert_eq!(Transaction::new_dep(1, 2, amount.clone()).dedup_key(), Transaction::try_from("deposit,1,2,10.0").unwrap().dedup_key());
+    assert_eq!(Transaction::new_wit(1, 3, amount).dedup_key(), Transaction::try_from("withdrawal, 1, 3,10.0\n").unwrap().dedup_key());
+    assert_eq!(Transaction::new_dis(1, 2).dedup_key(), Transaction::try_from("dispute,1,2,").unwrap().dedup_key());
+    assert_eq!(Transaction::new_dis(1, 2).dedup_key(), Transaction::try_from("dispute,1,2").unwrap().dedup_key());
+    assert!(matches!(Transaction::try_from("deposit,1,2,\"10.0\""), Err(TransactionParseError::InvalidAmount)));
+    assert!(matches!(Transaction::try_from(""), Err(TransactionParseError::MissingField("client"))));
+  }
+  #[test]
+  fn extreme_exponents() {
+    use std::convert::TryFrom;
+    for line in ["deposit,1,2,1e-9223372036854775808", "deposit,1,2,1e9223372036854775807", "deposit,1,2,1e65", "deposit,1,2,1e1e1"] {
+      assert!(matches!(Transaction::try_from(line), Err(TransactionParseError::InvalidAmount)));
+    }
+    let amount = Transaction::try_from("deposit,1,2,1.5E-64").unwrap().amount().unwrap();
+    assert_eq!(Currency::new(15.into(), 65), amount);
+  }
+  #[test]
+  fn validate() {
+    use std::convert::TryFrom;
+    // Parsing accepts a negative deposit, only validation rejects it
+    let deposit = Transaction::try_from("deposit,1,2,-10.0").unwrap();
+    assert_eq!(Err(ValidationError::NonPositiveAmount(Currency::from_str("-10.0").unwrap())), deposit.validate());
+    assert!(Transaction::try_from("withdrawal,1,3,0").unwrap().validate().is_err());
+    assert_eq!(Err(ValidationError::ZeroAdjustment), Transaction::try_from("adjustment,1,4,0.0").unwrap().validate());
+    assert!(Transaction::try_from("dispute,1,2,-10.0").unwrap().validate().is_err());
+    for line in ["deposit,1,2,10.0", "adjustment,1,4,-1.5", "dispute,1,2,", "dispute,1,2,10.0", "chargeback,1,2"] {
+      assert_eq!(Ok(()), Transaction::try_from(line).unwrap().validate());
+    }
+  }
+}